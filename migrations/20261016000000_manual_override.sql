@@ -0,0 +1,6 @@
+-- Tracks a manual zone run so the schedule won't interrupt it.
+CREATE TABLE IF NOT EXISTS ManualOverride (
+    id         TINYINT PRIMARY KEY,
+    zone_id    TINYINT NOT NULL,
+    expires_at BIGINT  NOT NULL
+);
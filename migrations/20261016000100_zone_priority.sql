@@ -0,0 +1,2 @@
+-- Higher priority zones may preempt lower priority zones that are already running.
+ALTER TABLE Zones ADD COLUMN Priority TINYINT NOT NULL DEFAULT 0;
@@ -0,0 +1,8 @@
+-- Records every time a zone was run, for charting watering over a season.
+CREATE TABLE IF NOT EXISTS ZoneHistory (
+    id            BIGINT  PRIMARY KEY AUTO_INCREMENT,
+    zone_id       TINYINT NOT NULL,
+    started_at    BIGINT  NOT NULL,
+    duration_secs BIGINT  NOT NULL,
+    INDEX zone_started (zone_id, started_at)
+);
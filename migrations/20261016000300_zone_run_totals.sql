@@ -0,0 +1,4 @@
+-- Lifetime run statistics, for planning valve maintenance.
+ALTER TABLE Zones
+    ADD COLUMN total_runs         BIGINT NOT NULL DEFAULT 0,
+    ADD COLUMN total_runtime_secs BIGINT NOT NULL DEFAULT 0;
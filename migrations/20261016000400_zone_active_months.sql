@@ -0,0 +1,2 @@
+-- A bitmask of the months a zone waters in, bit 0 being January. Defaults to every month.
+ALTER TABLE Zones ADD COLUMN ActiveMonths SMALLINT NOT NULL DEFAULT 4095;
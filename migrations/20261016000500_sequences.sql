@@ -0,0 +1,5 @@
+-- Named groups of zones that can be run on their own, in the order the ids are listed.
+CREATE TABLE IF NOT EXISTS Sequences (
+    name     VARCHAR(64)  PRIMARY KEY,
+    zone_ids VARCHAR(255) NOT NULL
+);
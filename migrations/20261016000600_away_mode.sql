@@ -0,0 +1,5 @@
+-- Refuses all watering while enabled, without touching the system schedule toggle.
+CREATE TABLE IF NOT EXISTS AwayMode (
+    id      TINYINT PRIMARY KEY,
+    enabled BOOLEAN NOT NULL
+);
@@ -0,0 +1,4 @@
+-- Widens the zone ids kept by ZoneHistory and ManualOverride to SMALLINT, so ids above 127 aren't truncated.
+-- Zones.id isn't created by a migration, and must already be at least SMALLINT.
+ALTER TABLE ZoneHistory MODIFY zone_id SMALLINT NOT NULL;
+ALTER TABLE ManualOverride MODIFY zone_id SMALLINT NOT NULL;
@@ -0,0 +1,6 @@
+-- Tracks timed manual runs so the daemon can finish their countdowns after a restart.
+CREATE TABLE IF NOT EXISTS ManualRuns (
+    zone_id       SMALLINT PRIMARY KEY,
+    started_at    BIGINT   NOT NULL,
+    duration_secs BIGINT   NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Whether a zone may be turned on by hand. The schedule runs it either way.
+ALTER TABLE Zones ADD COLUMN AllowManual BOOLEAN NOT NULL DEFAULT TRUE;
@@ -0,0 +1,3 @@
+-- How long a zone's valve takes to open and to close, so runs can be lengthened or shortened to match.
+ALTER TABLE Zones ADD COLUMN OpenLagMs INT NOT NULL DEFAULT 0;
+ALTER TABLE Zones ADD COLUMN CloseLagMs INT NOT NULL DEFAULT 0;
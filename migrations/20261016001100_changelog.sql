@@ -0,0 +1,9 @@
+-- Records every change to the zones and the system schedule, and where it was made from.
+CREATE TABLE IF NOT EXISTS ChangeLog (
+    id          BIGINT      PRIMARY KEY AUTO_INCREMENT,
+    changed_at  BIGINT      NOT NULL,
+    source      VARCHAR(16) NOT NULL,
+    operation   VARCHAR(32) NOT NULL,
+    before_json TEXT        NULL,
+    after_json  TEXT        NULL
+);
@@ -0,0 +1,8 @@
+-- A stable id for each zone that doesn't change when the zone is renamed. Existing zones get one made
+-- from their name, the same way as zone::slugify, numbered with the zone's id where names collide.
+ALTER TABLE Zones ADD COLUMN Slug VARCHAR(255) NOT NULL DEFAULT '';
+UPDATE Zones SET Slug = TRIM(BOTH '-' FROM LOWER(REGEXP_REPLACE(Name, '[^A-Za-z0-9]+', '-')));
+UPDATE Zones SET Slug = 'zone' WHERE Slug = '';
+UPDATE Zones z
+    JOIN (SELECT Slug FROM Zones GROUP BY Slug HAVING COUNT(*) > 1) dupes ON z.Slug = dupes.Slug
+    SET z.Slug = CONCAT(z.Slug, '-', z.id);
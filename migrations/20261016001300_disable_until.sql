@@ -0,0 +1,6 @@
+-- When a time-bounded disable of the system schedule ends, such as for a vacation. Scheduled runs are
+-- skipped until then without touching the system schedule toggle.
+CREATE TABLE IF NOT EXISTS DisableUntil (
+    id        TINYINT PRIMARY KEY,
+    resume_at BIGINT  NOT NULL
+);
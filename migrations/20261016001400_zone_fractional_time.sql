@@ -0,0 +1,4 @@
+-- Lets a zone's time be fractional, such as a 2.5 minute drip pulse. It stays in the configured time_unit.
+-- MODIFY redefines the whole column, so it keeps a zone's time required, and a zone without one runs for 0.
+UPDATE Zones SET Time = 0 WHERE Time IS NULL;
+ALTER TABLE Zones MODIFY COLUMN Time DOUBLE NOT NULL DEFAULT 0;
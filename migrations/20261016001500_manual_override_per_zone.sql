@@ -0,0 +1,3 @@
+-- Keeps one manual override per zone, so with max_concurrent_zones above 1 a second zone turned on by
+-- hand doesn't replace the first zone's override. An existing override is kept for its zone.
+ALTER TABLE ManualOverride DROP PRIMARY KEY, DROP COLUMN id, ADD PRIMARY KEY (zone_id);
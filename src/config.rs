@@ -1,21 +1,89 @@
+use crate::sqlsprinkler::notify::EventType;
+use chrono_tz::Tz;
 use lazy_static::lazy_static;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
 use std::sync::RwLock;
+use std::{env, fmt, fs, io};
 
 const SETTINGS_FILE_PATH: &str = "/etc/sqlsprinkler/sqlsprinkler.conf";
 
+/// A commented config file with every setting, used to bootstrap a new install.
+const SAMPLE_CONFIG: &str = include_str!("../conf/sqlsprinkler.conf");
+
+/// The current version of the config file layout. Config files without a version are version 0.
+/// Bump it whenever a setting is added, renamed or converted, so existing files are upgraded.
+pub const CONFIG_VERSION: u32 = 2;
+
+/// The environment variable the database password is read from when it is not in the config file.
+pub const DB_PASS_ENV_VAR: &str = "SQLSPRINKLER_PASS";
+
+/// The default maximum number of connections to hold open to the database.
+const DEFAULT_DB_MAX_CONNECTIONS: u32 = 5;
+
+/// The default number of seconds to wait for a database connection before giving up.
+const DEFAULT_DB_ACQUIRE_TIMEOUT_SECS: u64 = 10;
+
+/// The default number of seconds a zone is turned on for when testing it.
+const DEFAULT_TEST_DURATION_SECS: u64 = 12;
+
+/// The default time scale, which runs everything in real time.
+const DEFAULT_TIME_SCALE: u64 = 1;
+
+/// The default number of seconds a run may go without making progress before the watchdog steps in.
+const DEFAULT_WATCHDOG_TIMEOUT_SECS: u64 = 120;
+
+/// The default number of minutes a manual override holds off the schedule for.
+const DEFAULT_MANUAL_OVERRIDE_TIMEOUT_MINS: u64 = 60;
+
+/// The default number of seconds outbound HTTP calls wait to connect.
+const DEFAULT_HTTP_CONNECT_TIMEOUT_SECS: u64 = 3;
+
+/// The default number of seconds an outbound HTTP call may take in total.
+const DEFAULT_HTTP_TIMEOUT_SECS: u64 = 5;
+
+/// The default number of zones that may be on at once, which runs zones one at a time.
+const DEFAULT_MAX_CONCURRENT_ZONES: usize = 1;
+
+/// The default most zones the system will read or add, far more than any controller has outputs for.
+const DEFAULT_MAX_ZONES: usize = 64;
+
+/// The default temperature, in Fahrenheit, at or below which the freeze guard skips watering.
+const DEFAULT_FREEZE_GUARD_TEMP_F: i32 = 34;
+
+/// The default soil temperature, in Fahrenheit, below which scheduled runs are skipped.
+const DEFAULT_MIN_SOIL_TEMP_F: i32 = 50;
+
+/// The default number of seconds zone runs last in commissioning mode.
+const DEFAULT_COMMISSIONING_RUN_SECS: u64 = 20;
+
+/// The default number of times in a row a failed daemon task is restarted.
+const DEFAULT_TASK_MAX_RESTARTS: u32 = 5;
+
 lazy_static! {
     static ref SETTINGS: RwLock<MyConfig> = RwLock::new(MyConfig::default());
 }
 
 /// Configuration for the application
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Default)]
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
 pub struct MyConfig {
+    /// The version of the config file layout, used to upgrade old config files
+    #[serde(default)]
+    pub config_version: u32,
+
     /// The user to connect to the database as
     pub sqlsprinkler_user: String,
 
-    /// The password to connect to the database with
-    pub sqlsprinkler_pass: String,
+    /// The password to connect to the database with. May be left out of the config file, see `db_pass`.
+    #[serde(default)]
+    pub sqlsprinkler_pass: Secret,
+
+    /// A file to read the database password from, such as a docker or systemd secret
+    #[serde(default)]
+    pub sqlsprinkler_pass_file: String,
 
     /// The database host to connect to
     pub sqlsprinkler_host: String,
@@ -23,8 +91,378 @@ pub struct MyConfig {
     /// The name of the database to connect to
     pub sqlsprinkler_db: String,
 
+    /// A read replica of the database to send status queries to. Empty sends everything to
+    /// `sqlsprinkler_host`.
+    #[serde(default)]
+    pub sqlsprinkler_read_host: String,
+
     /// Whether or not the application should be running in verbose mode.
     pub verbose: bool,
+
+    /// The unit that zone run times are stored in.
+    #[serde(default)]
+    pub time_unit: TimeUnit,
+
+    /// The maximum number of connections to hold open to the database
+    #[serde(default = "default_db_max_connections")]
+    pub db_max_connections: u32,
+
+    /// How many seconds to wait for a database connection before erroring
+    #[serde(default = "default_db_acquire_timeout_secs")]
+    pub db_acquire_timeout_secs: u64,
+
+    /// How many minutes a manually started zone without auto off holds off the schedule for
+    #[serde(default = "default_manual_override_timeout_mins")]
+    pub manual_override_timeout_mins: u64,
+
+    /// How many times faster than real time to sleep during runs, for testing schedules
+    #[serde(default = "default_time_scale")]
+    pub time_scale: u64,
+
+    /// How many seconds a zone is turned on for when testing it
+    #[serde(default = "default_test_duration_secs")]
+    pub test_duration_secs: u64,
+
+    /// How many minutes quick runs, such as `zone state <id> on --for`, last when no duration is
+    /// given. 0 uses the zone's own time.
+    #[serde(default)]
+    pub default_run_minutes: u64,
+
+    /// Whether every zone run is cut down to `commissioning_run_secs`, for checking each zone quickly
+    /// while installing the system. The valves still open.
+    #[serde(default)]
+    pub commissioning_mode: bool,
+
+    /// How many seconds zone runs last in commissioning mode.
+    #[serde(default = "default_commissioning_run_secs")]
+    pub commissioning_run_secs: u64,
+
+    /// The IANA name of the timezone to show times in, such as `America/Denver`. Empty uses the
+    /// system's local timezone.
+    #[serde(default)]
+    pub timezone: String,
+
+    /// A Unix domain socket path to serve the daemon on instead of TCP port 3030. Empty uses TCP.
+    #[serde(default)]
+    pub daemon_unix_socket: String,
+
+    /// Whether the daemon turns every zone off when it starts. When false, a zone left running by a
+    /// manual run before a restart is left on.
+    #[serde(default = "default_startup_turn_off_all")]
+    pub startup_turn_off_all: bool,
+
+    /// Whether the daemon runs the system once when it starts, if the system schedule is enabled.
+    /// Otherwise the daemon never waters on its own.
+    #[serde(default)]
+    pub run_on_start: bool,
+
+    /// The most zones that may be on at once. With 1, turning a zone on turns the others off first.
+    #[serde(default = "default_max_concurrent_zones")]
+    pub max_concurrent_zones: usize,
+
+    /// The most zones the system will read or add. Zones past it are left out with an error, so a
+    /// corrupt database or a mass insert can't run the daemon out of memory.
+    #[serde(default = "default_max_zones")]
+    pub max_zones: usize,
+
+    /// How many milliseconds to wait after turning a zone on, so a pump's inrush current settles before
+    /// anything else happens. It is added on top of the zone's time. 0 disables it.
+    #[serde(default)]
+    pub zone_startup_delay_ms: u64,
+
+    /// How many seconds a run may go without making progress before every zone is turned off.
+    /// 0 disables the watchdog.
+    #[serde(default = "default_watchdog_timeout_secs")]
+    pub watchdog_timeout_secs: u64,
+
+    /// What the watchdog does after turning every zone off.
+    #[serde(default)]
+    pub watchdog_action: WatchdogAction,
+
+    /// A URL to POST a JSON event to when a run starts, finishes or is skipped. Empty disables it.
+    #[serde(default)]
+    pub notify_webhook_url: String,
+
+    /// Which events are sent to `notify_webhook_url`.
+    #[serde(default)]
+    pub notify_events: Vec<EventType>,
+
+    /// How many seconds outbound HTTP calls, such as the webhook, wait to connect.
+    #[serde(default = "default_http_connect_timeout_secs")]
+    pub http_connect_timeout_secs: u64,
+
+    /// How many seconds outbound HTTP calls may take in total before they are given up on.
+    #[serde(default = "default_http_timeout_secs")]
+    pub http_timeout_secs: u64,
+
+    /// The id of a 1-wire DS18B20 air temperature sensor, such as `28-0316a2792fff`, for the freeze
+    /// guard. Empty disables the freeze guard.
+    #[serde(default)]
+    pub freeze_guard_sensor_id: String,
+
+    /// The temperature, in Fahrenheit, at or below which scheduled runs are skipped.
+    #[serde(default = "default_freeze_guard_temp_f")]
+    pub freeze_guard_temp_f: i32,
+
+    /// The id of a 1-wire DS18B20 soil temperature probe, such as `28-0316a2792fff`. Empty disables the
+    /// soil temperature check.
+    #[serde(default)]
+    pub soil_temp_sensor_id: String,
+
+    /// The soil temperature, in Fahrenheit, below which scheduled runs are skipped.
+    #[serde(default = "default_min_soil_temp_f")]
+    pub min_soil_temp_f: i32,
+
+    /// Whether the daemon and commands that switch valves exit when the GPIO interface is
+    /// unavailable. When false, zones are switched in memory instead, for demos and testing off of a
+    /// Pi.
+    #[serde(default = "default_require_real_gpio")]
+    pub require_real_gpio: bool,
+
+    /// GPIO pins used by other hardware, such as a HAT, which zones may never use.
+    #[serde(default)]
+    pub reserved_gpios: Vec<u8>,
+
+    /// A TOML file of zones that the daemon makes the database match when it starts. Empty disables it.
+    #[serde(default)]
+    pub zones_file: String,
+
+    /// Whether zones in the database but not in `zones_file` are removed when it is reconciled.
+    #[serde(default)]
+    pub zones_file_remove_extras: bool,
+
+    /// How many days of zone history the daemon keeps. 0 keeps it forever.
+    #[serde(default)]
+    pub history_retention_days: u64,
+
+    /// How many times in a row a failed background task of the daemon is restarted before it is given
+    /// up on. 0 never restarts it.
+    #[serde(default = "default_task_max_restarts")]
+    pub task_max_restarts: u32,
+}
+
+impl Default for MyConfig {
+    fn default() -> Self {
+        MyConfig {
+            config_version: CONFIG_VERSION,
+            sqlsprinkler_user: String::new(),
+            sqlsprinkler_pass: Secret::default(),
+            sqlsprinkler_pass_file: String::new(),
+            sqlsprinkler_host: String::new(),
+            sqlsprinkler_db: String::new(),
+            sqlsprinkler_read_host: String::new(),
+            verbose: false,
+            time_unit: TimeUnit::default(),
+            db_max_connections: DEFAULT_DB_MAX_CONNECTIONS,
+            db_acquire_timeout_secs: DEFAULT_DB_ACQUIRE_TIMEOUT_SECS,
+            manual_override_timeout_mins: DEFAULT_MANUAL_OVERRIDE_TIMEOUT_MINS,
+            time_scale: DEFAULT_TIME_SCALE,
+            test_duration_secs: DEFAULT_TEST_DURATION_SECS,
+            default_run_minutes: 0,
+            commissioning_mode: false,
+            commissioning_run_secs: DEFAULT_COMMISSIONING_RUN_SECS,
+            timezone: String::new(),
+            daemon_unix_socket: String::new(),
+            startup_turn_off_all: true,
+            run_on_start: false,
+            max_concurrent_zones: DEFAULT_MAX_CONCURRENT_ZONES,
+            max_zones: DEFAULT_MAX_ZONES,
+            zone_startup_delay_ms: 0,
+            watchdog_timeout_secs: DEFAULT_WATCHDOG_TIMEOUT_SECS,
+            watchdog_action: WatchdogAction::default(),
+            notify_webhook_url: String::new(),
+            notify_events: Vec::new(),
+            http_connect_timeout_secs: DEFAULT_HTTP_CONNECT_TIMEOUT_SECS,
+            http_timeout_secs: DEFAULT_HTTP_TIMEOUT_SECS,
+            freeze_guard_sensor_id: String::new(),
+            freeze_guard_temp_f: DEFAULT_FREEZE_GUARD_TEMP_F,
+            soil_temp_sensor_id: String::new(),
+            min_soil_temp_f: DEFAULT_MIN_SOIL_TEMP_F,
+            require_real_gpio: true,
+            reserved_gpios: Vec::new(),
+            zones_file: String::new(),
+            zones_file_remove_extras: false,
+            history_retention_days: 0,
+            task_max_restarts: DEFAULT_TASK_MAX_RESTARTS,
+        }
+    }
+}
+
+impl MyConfig {
+    /// Gets the database password. The first one that is set wins, in this order:
+    /// 1. `sqlsprinkler_pass` in the config file
+    /// 2. The `SQLSPRINKLER_PASS` environment variable
+    /// 3. The contents of the file at `sqlsprinkler_pass_file`, without trailing newlines
+    /// # Return
+    /// The password, which is empty if none of the above are set.
+    pub fn db_pass(&self) -> io::Result<Secret> {
+        self.db_pass_from(|name| env::var(name).ok())
+    }
+
+    /// Gets the database password like `db_pass`, looking environment variables up with `env_var`.
+    /// # Params
+    /// * `env_var` Gets the value of the environment variable with the given name, if it is set.
+    fn db_pass_from<F>(&self, env_var: F) -> io::Result<Secret>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if !self.sqlsprinkler_pass.is_empty() {
+            return Ok(self.sqlsprinkler_pass.clone());
+        }
+        if let Some(pass) = env_var(DB_PASS_ENV_VAR) {
+            if !pass.is_empty() {
+                return Ok(Secret(pass));
+            }
+        }
+        if !self.sqlsprinkler_pass_file.is_empty() {
+            let pass = fs::read_to_string(&self.sqlsprinkler_pass_file)?;
+            return Ok(Secret(pass.trim_end_matches(&['\r', '\n'][..]).to_string()));
+        }
+        Ok(Secret::default())
+    }
+
+    /// Gets the configured timezone.
+    /// # Return
+    /// The timezone, or `None` to use the system's local timezone if `timezone` is unset or
+    /// is not a known IANA timezone name.
+    pub fn timezone(&self) -> Option<Tz> {
+        if self.timezone.is_empty() {
+            return None;
+        }
+        match self.timezone.parse() {
+            Ok(tz) => Some(tz),
+            Err(e) => {
+                warn!("Unknown timezone {}, using local time: {}", self.timezone, e);
+                None
+            }
+        }
+    }
+}
+
+/// A config value, such as a password, that is redacted when printed with `Debug`.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Default)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    /// Gets the real value of this secret.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Gets whether this secret is empty.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"<redacted>\"")
+    }
+}
+
+fn default_db_max_connections() -> u32 {
+    DEFAULT_DB_MAX_CONNECTIONS
+}
+
+fn default_db_acquire_timeout_secs() -> u64 {
+    DEFAULT_DB_ACQUIRE_TIMEOUT_SECS
+}
+
+fn default_test_duration_secs() -> u64 {
+    DEFAULT_TEST_DURATION_SECS
+}
+
+fn default_time_scale() -> u64 {
+    DEFAULT_TIME_SCALE
+}
+
+fn default_manual_override_timeout_mins() -> u64 {
+    DEFAULT_MANUAL_OVERRIDE_TIMEOUT_MINS
+}
+
+fn default_startup_turn_off_all() -> bool {
+    true
+}
+
+fn default_require_real_gpio() -> bool {
+    true
+}
+
+fn default_http_connect_timeout_secs() -> u64 {
+    DEFAULT_HTTP_CONNECT_TIMEOUT_SECS
+}
+
+fn default_http_timeout_secs() -> u64 {
+    DEFAULT_HTTP_TIMEOUT_SECS
+}
+
+fn default_freeze_guard_temp_f() -> i32 {
+    DEFAULT_FREEZE_GUARD_TEMP_F
+}
+
+fn default_min_soil_temp_f() -> i32 {
+    DEFAULT_MIN_SOIL_TEMP_F
+}
+
+fn default_commissioning_run_secs() -> u64 {
+    DEFAULT_COMMISSIONING_RUN_SECS
+}
+
+fn default_task_max_restarts() -> u32 {
+    DEFAULT_TASK_MAX_RESTARTS
+}
+
+fn default_max_concurrent_zones() -> usize {
+    DEFAULT_MAX_CONCURRENT_ZONES
+}
+
+fn default_max_zones() -> usize {
+    DEFAULT_MAX_ZONES
+}
+
+fn default_watchdog_timeout_secs() -> u64 {
+    DEFAULT_WATCHDOG_TIMEOUT_SECS
+}
+
+/// What the watchdog does once it has turned off every zone of a stalled run.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchdogAction {
+    /// Keep going, in case the run recovers.
+    #[default]
+    TurnOff,
+    /// Exit with an error, so that systemd restarts SQLSprinkler.
+    Exit,
+}
+
+/// The unit used to interpret a zone's `Time` column.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeUnit {
+    #[default]
+    Minutes,
+    Seconds,
+}
+
+impl TimeUnit {
+    /// Converts an amount of this unit into seconds.
+    pub fn to_secs(self, amount: u64) -> u64 {
+        match self {
+            TimeUnit::Minutes => amount * 60,
+            TimeUnit::Seconds => amount,
+        }
+    }
+}
+
+impl fmt::Display for TimeUnit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimeUnit::Minutes => write!(f, "minutes"),
+            TimeUnit::Seconds => write!(f, "seconds"),
+        }
+    }
 }
 
 /// Get the current configuration
@@ -32,9 +470,204 @@ pub fn get_settings() -> MyConfig {
     SETTINGS.read().unwrap().clone()
 }
 
+/// Replaces the settings in memory, such as when SQLSprinkler is used as a library.
+/// # Params
+/// * `settings` The settings to use.
+pub fn set_settings(settings: MyConfig) {
+    *SETTINGS.write().unwrap() = settings;
+}
+
 /// Read the settings file from `/etc/sqlsprinlker/sqlsprinkler.conf` and load into memory.
 pub fn read_settings() -> Result<(), confy::ConfyError> {
     let mut new_settings = SETTINGS.write().unwrap();
     *new_settings = confy::load_path(SETTINGS_FILE_PATH)?;
     Ok(())
 }
+
+/// Writes a commented sample config file, with placeholder values for every setting, to `path`.
+/// Refuses to overwrite a file that already exists.
+/// # Params
+/// * `path` Where to write the sample config.
+pub fn write_sample_config(path: &Path) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).open(path)?;
+    file.write_all(SAMPLE_CONFIG.as_bytes())
+}
+
+/// Upgrades a settings file written by an older version to the current `CONFIG_VERSION`, logging
+/// each change. Settings missing from the file are written out with the defaults they were loaded
+/// with. Keys that this version doesn't know about are kept.
+pub fn upgrade_settings() -> Result<(), Box<dyn Error>> {
+    let settings = get_settings();
+    if settings.config_version >= CONFIG_VERSION {
+        return Ok(());
+    }
+    upgrade_file(SETTINGS_FILE_PATH, &settings)?;
+    SETTINGS.write().unwrap().config_version = CONFIG_VERSION;
+    info!("Config upgraded to version {}", CONFIG_VERSION);
+    Ok(())
+}
+
+/// Rewrites the config file at `path`, which `settings` were loaded from, at `CONFIG_VERSION`. The
+/// file is edited as text so its comments are kept: the `config_version` line is replaced, and the
+/// settings missing from the file are added after it.
+fn upgrade_file(path: &str, settings: &MyConfig) -> Result<(), Box<dyn Error>> {
+    let contents = fs::read_to_string(path)?;
+    let table: toml::value::Table = toml::from_str(&contents)?;
+    for version in settings.config_version..CONFIG_VERSION {
+        info!("Upgrading config from version {} to {}", version, version + 1);
+        // Versions 0 to 2 only add missing settings, which is done below. Upgrades that rename or
+        // convert settings go here.
+    }
+    let mut upgraded = vec![format!("config_version = {}", CONFIG_VERSION)];
+    if let toml::Value::Table(loaded) = toml::Value::try_from(settings)? {
+        for (key, value) in loaded {
+            if key != "config_version" && !table.contains_key(&key) {
+                info!("Adding {} = {} to the config", key, value);
+                upgraded.push(format!("{} = {}", key, value));
+            }
+        }
+    }
+    warn!("Rewriting {} at config version {}, keeping its comments", path, CONFIG_VERSION);
+    fs::write(path, with_top_level_lines(&contents, "config_version", &upgraded))?;
+    Ok(())
+}
+
+/// Replaces the top level `key = ...` line of a TOML file with `lines`, or puts `lines` at the top
+/// of the file if it has no such line. Every other line, comments included, is kept as it is.
+fn with_top_level_lines(contents: &str, key: &str, lines: &[String]) -> String {
+    let mut out: Vec<&str> = Vec::new();
+    let mut replaced = false;
+    let mut top_level = true;
+    for line in contents.lines() {
+        let trimmed = line.trim_start();
+        top_level &= !trimmed.starts_with('[');
+        let is_key = trimmed
+            .strip_prefix(key)
+            .is_some_and(|rest| rest.trim_start().starts_with('='));
+        if top_level && is_key && !replaced {
+            out.extend(lines.iter().map(String::as_str));
+            replaced = true;
+        } else {
+            out.push(line);
+        }
+    }
+    if !replaced {
+        let mut top: Vec<&str> = lines.iter().map(String::as_str).collect();
+        top.append(&mut out);
+        out = top;
+    }
+    out.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        upgrade_file, with_top_level_lines, MyConfig, Secret, TimeUnit, CONFIG_VERSION, DB_PASS_ENV_VAR,
+        SAMPLE_CONFIG,
+    };
+    use std::{env, fs};
+
+    #[test]
+    fn inline_password_wins() {
+        let config = MyConfig {
+            sqlsprinkler_pass: Secret("inline".to_string()),
+            sqlsprinkler_pass_file: "/nonexistent".to_string(),
+            ..MyConfig::default()
+        };
+        assert_eq!(config.db_pass().unwrap().expose(), "inline");
+    }
+
+    #[test]
+    fn password_file_is_read_without_trailing_newline() {
+        let path = env::temp_dir().join("sqlsprinkler_pass_file_test");
+        fs::write(&path, "from-file\n").unwrap();
+        let config = MyConfig {
+            sqlsprinkler_pass_file: path.to_string_lossy().to_string(),
+            ..MyConfig::default()
+        };
+        assert_eq!(config.db_pass_from(|_| None).unwrap().expose(), "from-file");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn password_may_be_left_out_for_the_environment() {
+        let config: MyConfig = toml::from_str(
+            "sqlsprinkler_user = 'sprinkler'\nsqlsprinkler_host = 'localhost'\nsqlsprinkler_db = 'SQLSprinkler'\nverbose = false\n",
+        )
+        .unwrap();
+        assert!(config.sqlsprinkler_pass.is_empty());
+        let env_var = |name: &str| (name == DB_PASS_ENV_VAR).then(|| "from-env".to_string());
+        assert_eq!(config.db_pass_from(env_var).unwrap().expose(), "from-env");
+    }
+
+    #[test]
+    fn secrets_are_redacted_when_debug_printed() {
+        let config = MyConfig {
+            sqlsprinkler_pass: Secret("hunter2".to_string()),
+            ..MyConfig::default()
+        };
+        assert!(!format!("{:?}", config).contains("hunter2"));
+    }
+
+    #[test]
+    fn invalid_timezone_falls_back_to_local() {
+        let mut config = MyConfig {
+            timezone: "America/Denver".to_string(),
+            ..MyConfig::default()
+        };
+        assert_eq!(config.timezone(), Some(chrono_tz::America::Denver));
+        config.timezone = "Mars/Olympus_Mons".to_string();
+        assert_eq!(config.timezone(), None);
+        config.timezone = String::new();
+        assert_eq!(config.timezone(), None);
+    }
+
+    #[test]
+    fn sample_config_has_every_setting() {
+        let sample: toml::value::Table = toml::from_str(SAMPLE_CONFIG).unwrap();
+        if let toml::Value::Table(settings) = toml::Value::try_from(MyConfig::default()).unwrap() {
+            for key in settings.keys() {
+                assert!(sample.contains_key(key), "the sample config is missing {}", key);
+            }
+        }
+        let parsed: MyConfig = toml::from_str(SAMPLE_CONFIG).unwrap();
+        assert_eq!(parsed.config_version, CONFIG_VERSION);
+    }
+
+    #[test]
+    fn upgrade_adds_missing_settings_and_keeps_unknown_keys() {
+        let path = env::temp_dir().join("sqlsprinkler_upgrade_test.conf");
+        fs::write(
+            &path,
+            "# The database\nsqlsprinkler_user = 'user'\nsqlsprinkler_pass = 'pass'\nsqlsprinkler_host = 'host'\n\
+             sqlsprinkler_db = 'db'\nverbose = false\nmqtt_host = 'broker'\n",
+        )
+        .unwrap();
+        let path = path.to_string_lossy().to_string();
+        let old: MyConfig = confy::load_path(&path).unwrap();
+        assert_eq!(old.config_version, 0);
+
+        upgrade_file(&path, &old).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let table: toml::value::Table = toml::from_str(&contents).unwrap();
+        assert_eq!(table["mqtt_host"].as_str(), Some("broker"));
+        assert!(contents.contains("# The database\n"));
+        let upgraded: MyConfig = confy::load_path(&path).unwrap();
+        assert_eq!(upgraded.config_version, CONFIG_VERSION);
+        assert_eq!(upgraded.time_unit, TimeUnit::Minutes);
+        assert_eq!(upgraded.sqlsprinkler_user, "user");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn only_the_top_level_version_line_is_replaced() {
+        let lines = vec!["config_version = 2".to_string(), "verbose = false".to_string()];
+        let contents = "# Version\nconfig_version = 1\nconfig_versions = 'x'\n[table]\nconfig_version = 1\n";
+        assert_eq!(
+            with_top_level_lines(contents, "config_version", &lines),
+            "# Version\nconfig_version = 2\nverbose = false\nconfig_versions = 'x'\n[table]\nconfig_version = 1\n"
+        );
+        assert_eq!(with_top_level_lines("# Old\n", "config_version", &lines), "config_version = 2\nverbose = false\n# Old\n");
+    }
+}
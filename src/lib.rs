@@ -0,0 +1,27 @@
+// Copyright 2021 Gavin Pease
+
+//! The zone and system logic of SQLSprinkler, for embedding it in other programs. The
+//! `sqlsprinkler-cli` binary is a thin wrapper around this library.
+//!
+//! Start with [`Sprinkler`], which connects to the database and offers the common operations.
+//! The modules under [`sqlsprinkler`] hold everything else.
+//!
+//! ```no_run
+//! use sqlsprinkler_cli::{config, Sprinkler};
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     config::read_settings()?;
+//!     let sprinkler = Sprinkler::connect(config::get_settings()).await?;
+//!     for zone in sprinkler.zones().await? {
+//!         println!("{} is {}", zone.Name, if zone.is_on() { "on" } else { "off" });
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+pub mod config;
+mod sprinkler;
+pub mod sqlsprinkler;
+
+pub use sprinkler::Sprinkler;
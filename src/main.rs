@@ -2,25 +2,51 @@
 
 extern crate core;
 
-mod config;
-mod sqlsprinkler;
-
-use crate::config::{get_settings, read_settings};
-use crate::sqlsprinkler::system::{
-    get_system_status, get_zones, set_system_status, turn_off_all_zones, winterize,
+use sqlsprinkler_cli::config::{get_settings, read_settings, upgrade_settings, write_sample_config, TimeUnit};
+use sqlsprinkler_cli::sqlsprinkler::system::{
+    clear_manual_override, DiagnosticCheck, get_away_mode, get_disabled_until, set_disabled_until, get_system_status, get_zones, local_timezone_name, ntp_synchronized, reconcile_running_zones, Refusal, set_away_mode, set_system_status,
+    turn_off_all_zones, turn_off_zones_except, winterize,
+};
+use sqlsprinkler_cli::sqlsprinkler::zone::{
+    get_zone_from_id, Direction, Zone, ZoneAdd, ZoneList, ZoneRef, ZoneSwap, ZoneTime,
 };
-use crate::sqlsprinkler::zone::ZoneAdd;
-use chrono::Local;
+use chrono::{Local, NaiveDate, Utc};
+use chrono_tz::Tz;
 use env_logger::fmt::{Color, Formatter};
 use env_logger::{Builder, Env};
-use log::{error, info, warn, Level, Record};
-use sqlsprinkler::daemon;
+use log::{error, info, warn, Level, LevelFilter, Record};
+use sqlsprinkler_cli::sqlsprinkler::{self, daemon, system};
 use std::fmt::Debug;
 use std::io::Write;
+use std::path::PathBuf;
 use std::process::exit;
 use std::str::FromStr;
+use std::time::Duration;
+use structopt::clap::{AppSettings, ErrorKind, Shell};
 use structopt::StructOpt;
-use crate::sqlsprinkler::{create_pool, get_pool, zone};
+use sqlsprinkler_cli::sqlsprinkler::changelog::{self, Source};
+use sqlsprinkler_cli::sqlsprinkler::sequence::{self, Sequence, SequenceEntry};
+use sqlsprinkler_cli::sqlsprinkler::zones_file;
+use sqlsprinkler_cli::sqlsprinkler::{chart, create_pool, get_pool, history, manual_run, unix_now, zone};
+
+// The exit codes of the CLI, which scripts may rely on:
+// - `0`: The command succeeded.
+// - `1`: The command failed for any other reason, such as a zone failing its test.
+// - `2`: The command line was invalid, such as an unknown zone state.
+// - `3`: A zone the command refers to was not found.
+// - `4`: The database could not be reached or a query against it failed.
+// - `5`: Turning a zone on was refused for now, such as by a higher priority zone or away mode.
+
+/// The exit code for failures that aren't covered by a more specific code.
+const EXIT_FAILURE: i32 = 1;
+/// The exit code for an invalid command line.
+const EXIT_USAGE: i32 = 2;
+/// The exit code for a zone that could not be found.
+const EXIT_NOT_FOUND: i32 = 3;
+/// The exit code for a database error.
+const EXIT_DB: i32 = 4;
+/// The exit code for turning a zone on being refused until something else changes, see `refusal_exit_code`.
+const EXIT_REFUSED: i32 = 5;
 
 /// Holds the program's possible CLI options.
 #[derive(Debug, StructOpt)]
@@ -34,6 +60,23 @@ pub struct Opts {
     #[structopt(short = "v", long = "verbose", about = "Verbose mode")]
     verbose_mode: bool,
 
+    /// Whether or not to only print errors
+    #[structopt(
+    short = "q",
+    long = "quiet",
+    about = "Only prints errors, overriding the verbose config option.",
+    conflicts_with = "verbose-mode"
+    )]
+    quiet_mode: bool,
+
+    /// An explicit log level, overriding -v, -q, the verbose config option and RUST_LOG
+    #[structopt(
+    long = "log-level",
+    possible_values = &["trace", "debug", "info", "warn", "error"],
+    case_insensitive = true
+    )]
+    log_level: Option<LevelFilter>,
+
     /// Whether or not to run in daemon mode
     #[structopt(
     short = "w",
@@ -42,6 +85,20 @@ pub struct Opts {
     )]
     daemon_mode: bool,
 
+    /// Writes a sample config file to the given path and exits
+    #[structopt(
+    long = "generate-config",
+    about = "Writes a commented sample config file to the given path."
+    )]
+    generate_config: Option<PathBuf>,
+
+    /// Checks that the config and database are ready, then exits
+    #[structopt(
+    long = "check",
+    about = "Checks that the config file is valid and the database is reachable, then exits. Exits non-zero if a check fails."
+    )]
+    check_mode: bool,
+
     /// A list of sub commands to run
     #[structopt(subcommand)]
     commands: Option<Cli>,
@@ -52,6 +109,13 @@ pub struct Opts {
 enum Cli {
     Zone(ZoneOpts),
     Sys(SysOpts),
+    /// Prints a shell completion script to stdout
+    #[structopt(setting = AppSettings::Hidden)]
+    Completions {
+        /// The shell to generate completions for
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+    },
 }
 
 /// Zone options
@@ -61,33 +125,138 @@ enum ZoneOpts {
     Add(ZoneAdd),
     Delete(ZoneDelete),
     Modify(ZoneModify),
+    /// Swaps the system order of two zones
+    Swap(ZoneSwapArgs),
+    /// Moves a zone one step up or down the system order
+    Reorder(ZoneReorderArgs),
+    /// Tests a single zone, checking that it turns on and off
+    Test(ZoneTest),
+    /// Resets the lifetime run count and runtime of a zone
+    ResetStats(ZoneResetStats),
+    /// Forces a zone's GPIO pin off, for when it is out of sync with the software
+    ResetGpio(ZoneResetGpio),
+    /// Draws a chart of how long a zone watered each day as an SVG
+    Graph(ZoneGraph),
+    /// Manages the history of zone runs
+    History(HistoryOpts),
     List,
 }
 
+/// The history options. Possible subcommands are:
+/// - `clear`: Deletes the runs of every zone, or those before a date.
+#[derive(StructOpt, Debug)]
+enum HistoryOpts {
+    /// Deletes past runs of every zone. The lifetime run totals are kept
+    Clear {
+        /// Only delete runs before this date, such as `2026-01-01`, in the configured timezone.
+        #[structopt(long)]
+        before: Option<NaiveDate>,
+    },
+}
+
+/// Zones may be given by their id or by their name.
 #[derive(StructOpt, Debug)]
 struct ZoneModify {
-    id: u8,
+    zone: ZoneRef,
     name: String,
-    gpio: u8,
-    time: u64,
+    /// The zone's GPIO pin, the same type as it is stored as, so the pin checked is the pin stored.
+    gpio: i8,
+    /// How long the zone runs, in the configured `time_unit`, such as `10` or `2.5`.
+    time: ZoneTime,
     #[structopt(parse(try_from_str))]
     enabled: bool,
     #[structopt(parse(try_from_str))]
     auto_off: bool,
-    order: u8,
+    /// The zone's place in the system order.
+    order: i8,
+    /// The new priority of the zone, left unchanged if not given.
+    #[structopt(long)]
+    priority: Option<i8>,
+    /// The months the zone waters in, such as `4-10`, left unchanged if not given.
+    #[structopt(long, parse(try_from_str = zone::parse_months))]
+    active_months: Option<i16>,
+    /// Whether the zone may be turned on by hand, left unchanged if not given.
+    #[structopt(long)]
+    allow_manual: Option<bool>,
+    /// How many milliseconds the zone's valve takes to open, left unchanged if not given.
+    #[structopt(long)]
+    open_lag_ms: Option<i32>,
+    /// How many milliseconds the zone's valve takes to close, left unchanged if not given.
+    #[structopt(long)]
+    close_lag_ms: Option<i32>,
+}
+
+#[derive(StructOpt, Debug)]
+struct ZoneTest {
+    /// The ID or name of the zone to test.
+    zone: ZoneRef,
+    /// How many seconds to run the zone for, defaults to `test_duration_secs` in the config.
+    #[structopt(long)]
+    secs: Option<u64>,
+}
+
+#[derive(StructOpt, Debug)]
+struct ZoneResetStats {
+    /// The ID or name of the zone to reset.
+    zone: ZoneRef,
+}
+
+#[derive(StructOpt, Debug)]
+struct ZoneResetGpio {
+    /// The ID or name of the zone to reset.
+    zone: ZoneRef,
+}
+
+#[derive(StructOpt, Debug)]
+struct ZoneGraph {
+    /// The ID or name of the zone to chart.
+    zone: ZoneRef,
+    /// How many days to chart, ending today.
+    #[structopt(long, default_value = "7")]
+    days: u32,
+    /// The SVG file to write, defaults to `zone-<id>.svg`.
+    #[structopt(long)]
+    out: Option<PathBuf>,
 }
 
 #[derive(StructOpt, Debug)]
 struct ZoneDelete {
-    id: u8,
+    /// The ID or name of the zone to delete.
+    zone: ZoneRef,
+    /// Stop the zone and delete it even if it is running.
+    #[structopt(long)]
+    force: bool,
+}
+
+#[derive(StructOpt, Debug)]
+struct ZoneSwapArgs {
+    /// The ID or name of the first zone.
+    first: ZoneRef,
+    /// The ID or name of the second zone.
+    second: ZoneRef,
+}
+
+#[derive(StructOpt, Debug)]
+struct ZoneReorderArgs {
+    /// The ID or name of the zone to move.
+    zone: ZoneRef,
+    /// Which way to move the zone.
+    #[structopt(possible_values = &["up", "down"])]
+    direction: Direction,
 }
 
 #[derive(StructOpt, Debug)]
 struct ZoneState {
-    /// The ID of the zone to modify.
-    id: u8,
+    /// The ID or name of the zone to modify.
+    zone: ZoneRef,
     /// The state of the zone.
-    state: String,
+    #[structopt(possible_values = &["on", "off", "status"])]
+    state: ZoneOptsArgs,
+    /// Turn the zone off again after this many minutes, waiting until then. Only used with `on`.
+    /// Without minutes, `default_run_minutes` or else the zone's time is used. Zones with auto off
+    /// always wait and turn off after their time, even without this.
+    #[structopt(long = "for")]
+    for_minutes: Option<Option<u64>>,
 }
 
 /// The options for a zone.
@@ -103,16 +272,13 @@ enum ZoneOptsArgs {
 }
 
 impl FromStr for ZoneOptsArgs {
-    type Err = ();
+    type Err = String;
     fn from_str(input: &str) -> Result<ZoneOptsArgs, Self::Err> {
         match input {
             "on" => Ok(ZoneOptsArgs::On),
             "off" => Ok(ZoneOptsArgs::Off),
             "status" => Ok(ZoneOptsArgs::Status),
-            _ => {
-                println!("Unrecognized subcommand.");
-                exit(1);
-            }
+            _ => Err(format!("Unrecognized zone state {}, expected on, off or status", input)),
         }
     }
 }
@@ -124,6 +290,8 @@ impl FromStr for ZoneOptsArgs {
 /// - `winterize`: Runs a winterization feature.
 /// - `test`: Tests the system, so the user can check functionality.
 /// - `run`: Runs the system as it is configured.
+/// - `run-all`: Runs every enabled zone now, even if the system schedule is disabled.
+/// - `clear-override`: Lets the schedule run again after a zone was turned on by hand.
 #[derive(StructOpt, Debug)]
 enum SysOpts {
     /// Enables the system schedule
@@ -132,26 +300,155 @@ enum SysOpts {
     Off,
     /// Runs the system
     Run,
+    /// Runs every enabled zone now, ignoring whether the system schedule is enabled
+    RunAll,
+    /// Prints the zones a system run would water, in order, without turning anything on
+    DryrunSchedule,
     /// Runs the winterizing schedule
     Winterize,
     /// Prints the status of the system.
     Status,
-    /// Tests the system.
-    Test,
+    /// Tests every zone, one at a time, checking that each turns on and off
+    Test {
+        /// How many seconds to run each zone for, defaults to `test_duration_secs` in the config.
+        #[structopt(long)]
+        secs: Option<u64>,
+    },
+    /// Clears a manual override so the schedule can run again
+    ClearOverride,
+    /// Checks the database and every zone's GPIO pin without turning anything on
+    Diagnose,
+    /// Prints the current time, the timezone schedules run in and whether the clock is synced
+    Now,
+    /// Turns away mode on or off, which refuses all watering until it is turned off
+    Away {
+        /// Whether away mode should be on.
+        #[structopt(possible_values = &["on", "off"])]
+        state: String,
+    },
+    /// Skips scheduled runs until a day, such as the end of a vacation, then resumes them on its own
+    DisableUntil {
+        /// The day scheduled runs resume on, such as `2026-10-20`, in the configured timezone.
+        #[structopt(required_unless = "cancel")]
+        date: Option<NaiveDate>,
+        /// Resumes scheduled runs now instead.
+        #[structopt(long, conflicts_with = "date")]
+        cancel: bool,
+    },
+    /// Manages named sequences of zones that run on their own
+    Sequence(SequenceOpts),
+}
+
+/// A zone in a sequence given on the command line, as `<zone>` or `<zone>:<time>`.
+#[derive(Debug)]
+struct SequenceZone {
+    zone: ZoneRef,
+    time: Option<ZoneTime>,
+}
+
+impl FromStr for SequenceZone {
+    type Err = String;
+    fn from_str(input: &str) -> Result<SequenceZone, Self::Err> {
+        // Only a number after the last `:` is a time, so names with a `:` in them still work.
+        if let Some((zone, time)) = input.rsplit_once(':') {
+            if let Ok(time) = time.trim().parse::<ZoneTime>() {
+                return Ok(SequenceZone { zone: zone.parse()?, time: Some(time) });
+            }
+        }
+        Ok(SequenceZone { zone: input.parse()?, time: None })
+    }
+}
+
+/// The sequence options. Possible subcommands are:
+/// - `add`: Saves a sequence of zones under a name.
+/// - `list`: Prints every sequence.
+/// - `run`: Runs the zones of a sequence in order.
+#[derive(StructOpt, Debug)]
+enum SequenceOpts {
+    /// Saves a sequence, replacing any sequence with the same name
+    Add {
+        /// The name of the sequence.
+        name: String,
+        /// The IDs or names of the zones to run, in order. Each may be followed by `:` and how long
+        /// it runs for in this sequence, such as `1:5` or `1:2.5`, instead of the zone's own time.
+        #[structopt(required = true)]
+        zones: Vec<SequenceZone>,
+    },
+    /// Prints every sequence
+    List,
+    /// Runs the zones of a sequence, one after another
+    Run {
+        /// The name of the sequence.
+        name: String,
+    },
 }
 
 #[tokio::main]
-async fn main() -> Result<(), sqlx::Error> {
-    let cli = Opts::from_args();
+async fn main() {
+    if let Err(e) = run_cli(parse_opts()).await {
+        error!("A database error occurred: {}", e);
+        exit(EXIT_DB);
+    }
+}
+
+/// Parses the command line, exiting with `EXIT_USAGE` if it is invalid.
+fn parse_opts() -> Opts {
+    match Opts::clap().get_matches_safe() {
+        Ok(matches) => Opts::from_clap(&matches),
+        Err(e) => match e.kind {
+            ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => e.exit(),
+            _ => {
+                eprintln!("{}", e.message);
+                exit(EXIT_USAGE);
+            }
+        },
+    }
+}
+
+/// Runs the command given on the command line.
+/// # Params
+///     `cli` The parsed command line.
+/// # Return
+///     An error if the database could not be reached or a query against it failed.
+async fn run_cli(cli: Opts) -> Result<(), sqlx::Error> {
+    if let Some(path) = cli.generate_config {
+        match write_sample_config(&path) {
+            Ok(..) => {
+                println!("Wrote a sample config to {}", path.display());
+                exit(0);
+            }
+            Err(e) => {
+                eprintln!("Unable to write a sample config to {}: {}", path.display(), e);
+                exit(EXIT_FAILURE);
+            }
+        }
+    }
+    // Completions don't need the config or the database, so print them before loading either.
+    if let Some(Cli::Completions { shell }) = cli.commands {
+        Opts::clap().gen_completions_to("sqlsprinkler", shell, &mut std::io::stdout());
+        return Ok(());
+    }
+    // The preflight check reads the config itself, so a bad config is reported instead of exiting.
+    if cli.check_mode {
+        let checks = sqlsprinkler::system::preflight().await;
+        let failed = print_checks(&checks);
+        if failed > 0 {
+            println!("{} of {} checks failed.", failed, checks.len());
+            exit(EXIT_FAILURE);
+        }
+        println!("All {} checks passed.", checks.len());
+        exit(0);
+    }
     let daemon_mode = cli.daemon_mode;
     let version_mode = cli.version_mode;
     let verbose_mode = cli.verbose_mode;
+    let quiet_mode = cli.quiet_mode;
 
     match read_settings() {
         Ok(..) => (),
         Err(e) => {
             error!("An error occurred while reading the config file: {}", e);
-            exit(1)
+            exit(EXIT_FAILURE)
         }
     };
     // let mut log_level = "info";
@@ -159,42 +456,113 @@ async fn main() -> Result<(), sqlx::Error> {
     // if !verbose_mode && !get_settings().verbose {
     //     log_level = "warn";
     // }
-    create_pool().await?;
     let mut level = "error";
-    if get_settings().verbose || verbose_mode {
+    if !quiet_mode && (get_settings().verbose || verbose_mode) {
         level = "sqlsprinkler";
     }
 
-    Builder::from_env(Env::default().default_filter_or(level))
-        .format(log_formatter)
+    // Read before the logger is built, so an unknown timezone is warned about once instead of on
+    // every line.
+    let timezone = get_settings().timezone();
+    let mut builder = match cli.log_level {
+        Some(log_level) => {
+            let mut builder = Builder::new();
+            builder.filter_level(log_level);
+            builder
+        }
+        None => Builder::from_env(Env::default().default_filter_or(level)),
+    };
+    builder
+        .format(move |buf, record| log_formatter(buf, record, timezone))
         .init();
+    if let Err(e) = upgrade_settings() {
+        warn!("Unable to upgrade the config file: {}", e);
+    }
+    // Checking the clock shouldn't need the database, which may be what's misbehaving.
+    if let Some(Cli::Sys(SysOpts::Now)) = cli.commands {
+        print_now();
+        return Ok(());
+    }
+    if let Err(e) = create_pool().await {
+        match e {
+            sqlx::Error::Configuration(e) => {
+                error!("{}", e);
+                exit(EXIT_FAILURE);
+            }
+            e => return Err(e),
+        }
+    }
+
+    let time_unit = get_settings().time_unit;
+    if time_unit == TimeUnit::default() {
+        info!("Zone times are in {}.", time_unit);
+    } else {
+        warn!("Zone times are in {}, not the default of {}.", time_unit, TimeUnit::default());
+    }
+    if get_settings().commissioning_mode {
+        warn!(
+            "COMMISSIONING MODE IS ON: every zone run is cut to {} seconds. Set commissioning_mode = false when done!",
+            get_settings().commissioning_run_secs
+        );
+    }
+    let time_scale = get_settings().time_scale;
+    if time_scale > 1 {
+        warn!("Time scale is {}, runs will sleep {} times faster than real time!", time_scale, time_scale);
+    }
 
     if version_mode {
         info!("SQLSprinkler v{}", env!("CARGO_PKG_VERSION"));
         exit(0);
     }
 
+    // Only commands that switch valves need the GPIO, so the rest, such as listing zones, still work
+    // off of a Pi. Diagnose reports on the GPIO itself, so it shouldn't be stopped by it either.
+    if let Err(e) = zone::init_gpio() {
+        if daemon_mode || switches_valves(&cli.commands) {
+            error!("GPIO is unavailable: {}. Set require_real_gpio = false to run without it.", e);
+            exit(EXIT_FAILURE);
+        }
+        warn!("GPIO is unavailable: {}. Zones will read as off.", e);
+    }
+
     if daemon_mode {
         info!("Starting SQLSprinkler daemon...");
-        match turn_off_all_zones().await {
-            Ok(..) => (),
+        let settings = get_settings();
+        if !settings.zones_file.is_empty() {
+            match zones_file::reconcile(&settings.zones_file, settings.zones_file_remove_extras).await {
+                Ok(changes) if changes.is_empty() => info!("Zones already match {}", settings.zones_file),
+                Ok(changes) => info!("Made {} changes to match {}", changes.len(), settings.zones_file),
+                Err(e) => error!("Unable to reconcile zones with {}: {}", settings.zones_file, e),
+            }
+        }
+        let resumed = match manual_run::resume().await {
+            Ok(resumed) => resumed,
             Err(e) => {
-                error!("An error occurred while turning off all zones: {}", e);
-                // exit(1);
+                error!("An error occurred while resuming manual runs: {}", e);
+                Vec::new()
+            }
+        };
+        if get_settings().startup_turn_off_all {
+            match turn_off_zones_except(&resumed).await {
+                Ok(..) => (),
+                Err(e) => {
+                    error!("An error occurred while turning off all zones: {}", e);
+                    // exit(EXIT_FAILURE);
+                }
             }
+        } else if let Err(e) = reconcile_running_zones(&resumed).await {
+            error!("An error occurred while checking which zones are running: {}", e);
+        }
+        if get_settings().run_on_start {
+            run_on_start().await;
+        } else {
+            info!("run_on_start is off, the daemon will not run the system until it is asked to");
         }
 
         // tokio::task::spawn_blocking(|| {
         //     daemon::run();
         // }).await.expect("TODO: panic message");
-        std::thread::spawn(|| {
-            let runtime = tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .expect("Unable to create a runtime");
-            let future = daemon::run();
-            runtime.block_on(future);
-        }).join().expect("TODO: panic message");
+        daemon::run().await;
     }
 
     if let Some(subcommand) = cli.commands {
@@ -204,30 +572,33 @@ async fn main() -> Result<(), sqlx::Error> {
             Cli::Zone(zone_state) => {
                 match zone_state {
                     ZoneOpts::State(x) => {
-                        let id = usize::from(x.id);
-                        let _zone_list = zone_list;
-                        // find the zone with the matching id.
-                        let my_zone = match _zone_list.zones.into_iter().find(|z| z.id == (id as i8)) {
-                            None => {
-                                error!("Unable to find zone with id {}", id);
-                                exit(1);
-                            }
-                            Some(z) => z
-                        };
-                        match x.state.parse().unwrap() {
+                        let my_zone = find_zone(&zone_list, &x.zone);
+                        match x.state {
                             ZoneOptsArgs::On => {
-                                match turn_off_all_zones().await {
-                                    Ok(_) => (),
+                                match system::check_manual_start(&my_zone).await {
+                                    Ok(None) => (),
+                                    Ok(Some(refusal)) => exit_refused(&my_zone, &refusal),
                                     Err(e) => {
-                                        error!("An error occurred while turning off all zones: {}", e);
-                                        exit(1);
+                                        error!("An error occurred while checking whether the zone may turn on: {}", e);
+                                        exit(EXIT_DB);
                                     }
                                 }
-                                my_zone.turn_on();
-                            }
-                            ZoneOptsArgs::Off => {
-                                my_zone.turn_off();
+                                let run_time = my_zone.manual_run_time(
+                                    x.for_minutes.map(|minutes| minutes.map(|minutes| Duration::from_secs(minutes * 60))),
+                                );
+                                match system::start_manual(&my_zone, run_time, || my_zone.turn_on()).await {
+                                    Ok(Ok(())) => (),
+                                    Ok(Err(refusal)) => exit_refused(&my_zone, &refusal),
+                                    Err(e) => {
+                                        error!("An error occurred while checking running zones: {}", e);
+                                        exit(EXIT_DB);
+                                    }
+                                }
+                                if let Some(run_time) = run_time {
+                                    system::finish_manual_run(&my_zone, run_time).await;
+                                }
                             }
+                            ZoneOptsArgs::Off => system::stop_zone(&my_zone).await,
                             ZoneOptsArgs::Status => {
                                 let state = if my_zone.get_with_state().state {
                                     "on"
@@ -239,24 +610,168 @@ async fn main() -> Result<(), sqlx::Error> {
                         }
                     }
                     ZoneOpts::Add(x) => {
-                        zone::add(x).await?;
+                        if let Err(e) = zone::check_gpio_not_reserved(x.gpio) {
+                            error!("{}.", e);
+                            exit(EXIT_FAILURE);
+                        }
+                        let after = changelog::snapshot(&x);
+                        if !zone::add(x).await? {
+                            error!("There are already max_zones ({}) zones.", get_settings().max_zones);
+                            exit(EXIT_FAILURE);
+                        }
+                        changelog::record(Source::Cli, "zone.add", None, after).await;
                     }
                     ZoneOpts::Delete(x) => {
-                        let query = sqlx::query!("DELETE FROM Zones WHERE id = ?", x.id)
-                            .execute(&get_pool())
+                        let my_zone = find_zone(&zone_list, &x.zone);
+                        if my_zone.is_running() {
+                            if !x.force {
+                                error!("Zone {} ({}) is running, stop it or use --force to delete it.", my_zone.id, my_zone.Name);
+                                exit(EXIT_FAILURE);
+                            }
+                            info!("Stopping zone {} before deleting it.", my_zone.id);
+                            my_zone.stop();
+                        }
+                        let id = my_zone.id;
+                        let query = sqlx::query!("DELETE FROM Zones WHERE id = ?", id)
+                            .execute(&get_pool()?)
                             .await;
                         match query {
-                            Ok(_) => info!("Zone deleted successfully."),
-                            Err(e) => error!("An error occurred while deleting the zone: {}", e),
+                            Ok(_) => {
+                                info!("Zone deleted successfully.");
+                                changelog::record(Source::Cli, "zone.delete", changelog::snapshot(&my_zone), None).await;
+                            }
+                            Err(e) => {
+                                error!("An error occurred while deleting the zone: {}", e);
+                                exit(EXIT_DB);
+                            }
                         }
                     }
                     ZoneOpts::Modify(x) => {
-                        let query = sqlx::query!("UPDATE Zones SET name=?, gpio=?, time=?, enabled=?, autooff=?, systemorder=? WHERE id = ?", x.name, x.gpio, x.time, x.enabled, x.auto_off, x.order, x.id)
-                            .execute(&get_pool())
+                        let before = find_zone(&zone_list, &x.zone);
+                        let id = before.id;
+                        if x.gpio != before.GPIO {
+                            if let Err(e) = zone::check_gpio_not_reserved(x.gpio) {
+                                error!("{}.", e);
+                                exit(EXIT_FAILURE);
+                            }
+                        }
+                        let query = sqlx::query!("UPDATE Zones SET name=?, gpio=?, time=?, enabled=?, autooff=?, systemorder=?, priority=COALESCE(?, priority), activemonths=COALESCE(?, activemonths), allowmanual=COALESCE(?, allowmanual), openlagms=COALESCE(?, openlagms), closelagms=COALESCE(?, closelagms) WHERE id = ?", x.name, x.gpio, x.time.units(), x.enabled, x.auto_off, x.order, x.priority, x.active_months, x.allow_manual, x.open_lag_ms, x.close_lag_ms, id)
+                            .execute(&get_pool()?)
                             .await;
                         match query {
-                            Ok(_) => info!("Zone modified successfully."),
-                            Err(e) => error!("An error occurred while modifying the zone: {}", e),
+                            Ok(_) => {
+                                info!("Zone modified successfully.");
+                                let after = get_zone_from_id(id).await.ok();
+                                let after = after.as_ref().and_then(changelog::snapshot);
+                                changelog::record(Source::Cli, "zone.update", changelog::snapshot(&before), after).await;
+                            }
+                            Err(e) => {
+                                error!("An error occurred while modifying the zone: {}", e);
+                                exit(EXIT_DB);
+                            }
+                        }
+                    }
+                    ZoneOpts::Swap(x) => {
+                        let swap = ZoneSwap {
+                            id1: find_zone(&zone_list, &x.first).id,
+                            id2: find_zone(&zone_list, &x.second).id,
+                        };
+                        let after = changelog::snapshot(&swap);
+                        match zone::swap(swap).await {
+                            Ok(true) => {
+                                info!("Zones swapped successfully.");
+                                changelog::record(Source::Cli, "zone.swap", changelog::orders(&zone_list), after).await;
+                            }
+                            Ok(false) => {
+                                error!("Both zones must exist to be swapped.");
+                                exit(EXIT_NOT_FOUND);
+                            }
+                            Err(e) => {
+                                error!("An error occurred while swapping the zones: {}", e);
+                                exit(EXIT_DB);
+                            }
+                        }
+                    }
+                    ZoneOpts::Reorder(x) => {
+                        let my_zone = find_zone(&zone_list, &x.zone);
+                        match zone::move_zone(my_zone.id, x.direction).await {
+                            Ok(Some(order)) => {
+                                let ids: Vec<String> = order.iter().map(|id| id.to_string()).collect();
+                                info!("Zone {} moved, the order is now {}.", my_zone.id, ids.join(", "));
+                                let after = changelog::snapshot(&order);
+                                changelog::record(Source::Cli, "zone.reorder", changelog::orders(&zone_list), after).await;
+                            }
+                            Ok(None) => {
+                                error!("Unable to find zone with id {}", my_zone.id);
+                                exit(EXIT_NOT_FOUND);
+                            }
+                            Err(e) => {
+                                error!("An error occurred while moving the zone: {}", e);
+                                exit(EXIT_DB);
+                            }
+                        }
+                    }
+                    ZoneOpts::Test(x) => {
+                        let my_zone = find_zone(&zone_list, &x.zone);
+                        exit_when_away(&format!("test zone {} ({})", my_zone.id, my_zone.Name)).await;
+                        match turn_off_all_zones().await {
+                            Ok(_) => (),
+                            Err(e) => {
+                                error!("An error occurred while turning off all zones: {}", e);
+                                exit(EXIT_FAILURE);
+                            }
+                        }
+                        let secs = x.secs.unwrap_or(get_settings().test_duration_secs);
+                        if my_zone.test_for(Duration::from_secs(secs)) {
+                            info!("Zone {} ({}) passed its test.", my_zone.id, my_zone.Name);
+                        } else {
+                            error!("Zone {} ({}) failed its test.", my_zone.id, my_zone.Name);
+                            exit(EXIT_FAILURE);
+                        }
+                    }
+                    ZoneOpts::ResetStats(x) => {
+                        let id = find_zone(&zone_list, &x.zone).id;
+                        match zone::reset_stats(id).await {
+                            Ok(true) => info!("Zone stats reset successfully."),
+                            Ok(false) => {
+                                error!("Unable to find zone with id {}", id);
+                                exit(EXIT_NOT_FOUND);
+                            }
+                            Err(e) => {
+                                error!("An error occurred while resetting the zone stats: {}", e);
+                                exit(EXIT_DB);
+                            }
+                        }
+                    }
+                    ZoneOpts::ResetGpio(x) => {
+                        let my_zone = find_zone(&zone_list, &x.zone);
+                        if let Err(e) = my_zone.reset_gpio() {
+                            error!("An error occurred while resetting the zone's GPIO: {}", e);
+                            exit(EXIT_FAILURE);
+                        }
+                    }
+                    ZoneOpts::Graph(x) => {
+                        let my_zone = find_zone(&zone_list, &x.zone);
+                        let out = x.out.unwrap_or_else(|| PathBuf::from(format!("zone-{}.svg", my_zone.id)));
+                        // A day more than asked for, so today's runs are covered in any timezone.
+                        let from = unix_now() - (x.days as i64 + 1) * 24 * 60 * 60;
+                        let runs = history::for_zone(my_zone.id, Some(from), None, history::MAX_HISTORY_ROWS).await?;
+                        let title = format!("{} watering, last {} days", my_zone.Name, x.days);
+                        match chart::render_svg(&title, &runs, x.days, &out) {
+                            Ok(..) => info!("Wrote the chart to {}", out.display()),
+                            Err(e) => {
+                                error!("An error occurred while drawing the chart: {}", e);
+                                exit(EXIT_FAILURE);
+                            }
+                        }
+                    }
+                    ZoneOpts::History(HistoryOpts::Clear { before }) => {
+                        match history::prune(before.map(sqlsprinkler::system::start_of_day)).await {
+                            Ok(pruned) => info!("Deleted {} runs from the history.", pruned),
+                            Err(e) => {
+                                error!("An error occurred while clearing the history: {}", e);
+                                exit(EXIT_DB);
+                            }
                         }
                     }
                     ZoneOpts::List => {
@@ -271,25 +786,29 @@ async fn main() -> Result<(), sqlx::Error> {
             // `sqlsprinkler sys ...`
             Cli::Sys(sys_opts) => match sys_opts {
                 SysOpts::On => {
+                    let before = get_system_status().await.ok();
                     match set_system_status(true).await {
                         Ok(..) => {
                             info!("System schedule enabled successfully.");
+                            record_system_enable(before, true).await;
                         }
                         Err(e) => {
                             error!("An error occurred while enabling the system schedule: {}", e);
-                            exit(1);
+                            exit(EXIT_DB);
                         }
                     };
                 }
                 SysOpts::Off => {
                     info!("Disabling system schedule.");
+                    let before = get_system_status().await.ok();
                     match set_system_status(false).await {
                         Ok(..) => {
                             info!("System schedule disabled successfully.");
+                            record_system_enable(before, false).await;
                         }
                         Err(e) => {
                             error!("An error occurred while disabling the system schedule: {}", e);
-                            exit(1);
+                            exit(EXIT_DB);
                         }
                     }
                 }
@@ -297,18 +816,53 @@ async fn main() -> Result<(), sqlx::Error> {
                     if get_system_status().await? {
                         info!("Running the system schedule.");
                         match sqlsprinkler::system::run().await {
-                            Ok(..) => {
+                            Ok(true) => {
                                 info!("System schedule ran successfully.");
                             }
+                            Ok(false) => {
+                                warn!("The system schedule was skipped.");
+                            }
                             Err(e) => {
                                 error!("An error occurred while running the system schedule: {}", e);
-                                exit(1);
+                                exit(EXIT_FAILURE);
                             }
                         };
                     } else {
                         warn!("System is not enabled, refusing.");
                     }
                 }
+                SysOpts::RunAll => {
+                    match sqlsprinkler::system::run_all().await {
+                        Ok(ran) => {
+                            info!("Ran {} enabled zones successfully.", ran);
+                        }
+                        Err(e) => {
+                            error!("An error occurred while running all zones: {}", e);
+                            exit(EXIT_FAILURE);
+                        }
+                    }
+                }
+                SysOpts::DryrunSchedule => {
+                    println!("{:<4} {:<20} {:<5} {:>8} {:>8} {:>9}", "ID", "NAME", "GPIO", "MINUTES", "ON FOR", "STARTS AT");
+                    let month = sqlsprinkler::system::current_month();
+                    let planned = sqlsprinkler::system::plan(&zone_list, month);
+                    for run in &planned {
+                        let offset = run.offset.as_secs();
+                        println!(
+                            "{:<4} {:<20} {:<5} {:>8.1} {:>8.1} {:>3}:{:02}:{:02}",
+                            run.zone.id,
+                            run.zone.Name,
+                            run.zone.GPIO,
+                            run.run_time.as_secs_f64() / 60.0,
+                            run.on_time.as_secs_f64() / 60.0,
+                            offset / 3600,
+                            offset / 60 % 60,
+                            offset % 60
+                        );
+                    }
+                    let total: Duration = planned.iter().map(|run| run.on_time).sum();
+                    println!("{} zones, {:.1} minutes in total", planned.len(), total.as_secs_f64() / 60.0);
+                }
                 SysOpts::Winterize => {
                     info!("Winterizing the system.");
                     match winterize().await {
@@ -317,7 +871,7 @@ async fn main() -> Result<(), sqlx::Error> {
                         }
                         Err(e) => {
                             error!("An error occurred while winterizing the system: {}", e);
-                            exit(1);
+                            exit(EXIT_FAILURE);
                         }
                     }
                 }
@@ -326,7 +880,7 @@ async fn main() -> Result<(), sqlx::Error> {
                         Ok(status) => status,
                         Err(e) => {
                             error!("An error occurred while getting the system status: {}", e);
-                            exit(1);
+                            exit(EXIT_DB);
                         }
                     };
                     let output = match system_status {
@@ -334,26 +888,275 @@ async fn main() -> Result<(), sqlx::Error> {
                         false => "disabled",
                     };
                     info!("The system is {}", output);
+                    if get_away_mode().await? {
+                        warn!("Away mode is on, nothing will be watered.");
+                    }
+                    if let Some(resume_on) = get_disabled_until().await? {
+                        warn!("Scheduled runs are disabled until {}.", resume_on);
+                    }
+                    if get_settings().commissioning_mode {
+                        warn!("Commissioning mode is on, zone runs are cut to {} seconds.", get_settings().commissioning_run_secs);
+                    }
+                    if zone::is_virtual_gpio() {
+                        warn!("GPIO is virtual, no valves will be switched.");
+                    }
+                }
+                SysOpts::Away { state } => {
+                    let enabled = state == "on";
+                    match set_away_mode(enabled).await {
+                        Ok(..) => info!("Away mode turned {} successfully.", state),
+                        Err(e) => {
+                            error!("An error occurred while setting away mode: {}", e);
+                            exit(EXIT_DB);
+                        }
+                    }
+                }
+                SysOpts::DisableUntil { date, cancel } => {
+                    let date = if cancel { None } else { date };
+                    if let Some(Err(e)) = date.map(|date| sqlsprinkler::system::check_resume_on(date, unix_now())) {
+                        error!("{}.", e);
+                        exit(EXIT_USAGE);
+                    }
+                    let before = get_disabled_until().await?;
+                    match set_disabled_until(date).await {
+                        Ok(..) => {
+                            match date {
+                                Some(date) => info!("Scheduled runs are disabled until {}.", date),
+                                None => info!("Scheduled runs are resumed."),
+                            }
+                            let (before, after) = (changelog::resume_on(before), changelog::resume_on(date));
+                            changelog::record(Source::Cli, "system.disable_until", before, after).await;
+                        }
+                        Err(e) => {
+                            error!("An error occurred while disabling the system until a day: {}", e);
+                            exit(EXIT_DB);
+                        }
+                    }
                 }
-                SysOpts::Test => {
+                SysOpts::Test { secs } => {
+                    exit_when_away("test every zone").await;
                     match turn_off_all_zones().await {
                         Ok(_) => (),
                         Err(e) => {
                             error!("An error occurred while turning off all zones: {}", e);
-                            exit(1);
+                            exit(EXIT_FAILURE);
                         }
                     }
-                    for zone in zone_list.zones {
-                        zone.test();
+                    let secs = secs.unwrap_or(get_settings().test_duration_secs);
+                    let mut failed = 0;
+                    for zone in &zone_list.zones {
+                        if !zone.test_for(Duration::from_secs(secs)) {
+                            error!("Zone {} ({}) failed its test.", zone.id, zone.Name);
+                            failed += 1;
+                        }
+                    }
+                    if failed > 0 {
+                        error!("{} of {} zones failed their test.", failed, zone_list.zones.len());
+                        exit(EXIT_FAILURE);
+                    }
+                }
+                SysOpts::Diagnose => {
+                    let checks = sqlsprinkler::system::diagnose().await;
+                    let failed = print_checks(&checks);
+                    if failed > 0 {
+                        error!("{} of {} checks failed.", failed, checks.len());
+                        exit(EXIT_FAILURE);
+                    }
+                    info!("All {} checks passed.", checks.len());
+                }
+                SysOpts::ClearOverride => {
+                    match clear_manual_override().await {
+                        Ok(..) => {
+                            info!("Manual override cleared successfully.");
+                        }
+                        Err(e) => {
+                            error!("An error occurred while clearing the manual override: {}", e);
+                            exit(EXIT_DB);
+                        }
                     }
                 }
+                // Handled before connecting to the database.
+                SysOpts::Now => (),
+                SysOpts::Sequence(sequence_opts) => match sequence_opts {
+                    SequenceOpts::Add { name, zones } => {
+                        let new_sequence = Sequence {
+                            name,
+                            entries: zones
+                                .iter()
+                                .map(|entry| SequenceEntry {
+                                    zone_id: find_zone(&zone_list, &entry.zone).id,
+                                    time: entry.time,
+                                })
+                                .collect(),
+                        };
+                        match sequence::save(&new_sequence).await {
+                            Ok(..) => info!("Sequence {} saved successfully.", new_sequence.name),
+                            Err(e) => {
+                                error!("An error occurred while saving the sequence: {}", e);
+                                exit(EXIT_DB);
+                            }
+                        }
+                    }
+                    SequenceOpts::List => {
+                        for listed in sequence::list().await? {
+                            let entries: Vec<String> = listed.entries.iter().map(|entry| entry.to_string()).collect();
+                            println!("{}: {}", listed.name, entries.join(", "));
+                        }
+                    }
+                    SequenceOpts::Run { name } => {
+                        let to_run = match sequence::get(&name).await? {
+                            Some(to_run) => to_run,
+                            None => {
+                                error!("Unable to find sequence {}", name);
+                                exit(EXIT_NOT_FOUND);
+                            }
+                        };
+                        match sqlsprinkler::system::run_sequence(&to_run).await {
+                            Ok(ran) => info!("Ran {} zones of sequence {} successfully.", ran, to_run.name),
+                            Err(e) => {
+                                error!("An error occurred while running the sequence: {}", e);
+                                exit(EXIT_FAILURE);
+                            }
+                        }
+                    }
+                },
             },
+            // Handled before the config is read.
+            Cli::Completions { .. } => (),
         }
     }
     Ok(())
 }
 
-fn log_formatter(buf: &mut Formatter, record: &Record) -> Result<(), std::io::Error> {
+/// Records a change to whether the system schedule is enabled in the changelog.
+/// # Params
+///     * `before` Whether the schedule was enabled before the change, if it could be read.
+///     * `after` Whether the schedule is enabled now.
+async fn record_system_enable(before: Option<bool>, after: bool) {
+    let status = |system_enabled: bool| serde_json::json!({ "system_enabled": system_enabled });
+    changelog::record(Source::Cli, "system.enable", before.map(status), Some(status(after))).await;
+}
+
+/// Starts a system run in the background when the daemon starts, if the system schedule is enabled.
+/// The daemon starts serving without waiting for the run to finish.
+async fn run_on_start() {
+    match get_system_status().await {
+        Ok(true) => {
+            info!("run_on_start is on, running the system");
+            // Zones block their thread while they run, so run them off of the daemon's runtime.
+            let handle = tokio::runtime::Handle::current();
+            tokio::task::spawn_blocking(move || {
+                match handle.block_on(sqlsprinkler::system::run()).map_err(|e| e.to_string()) {
+                    Ok(true) => info!("Start up run complete"),
+                    Ok(false) => warn!("The start up run was skipped"),
+                    Err(e) => error!("An error occurred during the start up run: {}", e),
+                }
+            });
+        }
+        Ok(false) => info!("run_on_start is on, but the system schedule is disabled, not running"),
+        Err(e) => error!("Unable to read the system status, not running on start: {}", e),
+    }
+}
+
+/// Gets whether a command may turn a zone on or off, and so needs the GPIO.
+/// # Params
+///     `commands` The subcommand given on the command line, if any.
+fn switches_valves(commands: &Option<Cli>) -> bool {
+    match commands {
+        Some(Cli::Zone(ZoneOpts::State(x))) => !matches!(x.state, ZoneOptsArgs::Status),
+        Some(Cli::Zone(opts)) => matches!(opts, ZoneOpts::Delete(..) | ZoneOpts::Test(..) | ZoneOpts::ResetGpio(..)),
+        Some(Cli::Sys(opts)) => matches!(
+            opts,
+            SysOpts::Run | SysOpts::RunAll | SysOpts::Winterize | SysOpts::Test { .. } | SysOpts::Sequence(SequenceOpts::Run { .. })
+        ),
+        Some(Cli::Completions { .. }) | None => false,
+    }
+}
+
+/// Prints the current time in the configured timezone and in UTC, the timezone's name, and whether
+/// the clock is synced with NTP.
+fn print_now() {
+    let now = Utc::now();
+    let time_format = "%Y-%m-%d %H:%M:%S %Z";
+    let (local, timezone) = match get_settings().timezone() {
+        Some(tz) => (now.with_timezone(&tz).format(time_format).to_string(), tz.name().to_string()),
+        None => {
+            let name = local_timezone_name().unwrap_or_else(|| "unknown".to_string());
+            (now.with_timezone(&Local).format(time_format).to_string(), format!("{} (system local)", name))
+        }
+    };
+    let ntp = match ntp_synchronized() {
+        Some(true) => "yes",
+        Some(false) => "no",
+        None => "unknown",
+    };
+    println!("Local time: {}", local);
+    println!("UTC time:   {}", now.format(time_format));
+    println!("Timezone:   {}", timezone);
+    println!("NTP synced: {}", ntp);
+}
+
+/// Prints a pass or fail line for each check.
+/// # Return
+///     How many of the checks failed.
+fn print_checks(checks: &[DiagnosticCheck]) -> usize {
+    for check in checks {
+        let mark = if check.passed { "ok" } else { "FAIL" };
+        println!("[{:>4}] {} {}", mark, check.name, check.detail);
+    }
+    checks.iter().filter(|check| !check.passed).count()
+}
+
+/// Exits if away mode is on, for refusing to water.
+/// # Params
+///     `action` What is being refused, for the log, such as `test every zone`.
+async fn exit_when_away(action: &str) {
+    match get_away_mode().await {
+        Ok(false) => (),
+        Ok(true) => {
+            error!("Away mode is on, refusing to {}.", action);
+            exit(EXIT_FAILURE);
+        }
+        Err(e) => {
+            error!("An error occurred while checking away mode: {}", e);
+            exit(EXIT_DB);
+        }
+    }
+}
+
+/// Gets the exit code for a zone that was refused when turned on by hand. A zone that doesn't allow
+/// manual runs never will, while the other refusals pass once whatever is in the way has stopped.
+/// # Params
+/// * `refusal` Why the zone was refused.
+fn refusal_exit_code(refusal: &Refusal) -> i32 {
+    match refusal {
+        Refusal::ManualNotAllowed => EXIT_FAILURE,
+        Refusal::Outranked(..) | Refusal::AwayMode | Refusal::TooManyRunning => EXIT_REFUSED,
+    }
+}
+
+/// Logs why a zone was refused when turned on by hand, and exits with its `refusal_exit_code`.
+/// # Params
+/// * `zone` The zone that was refused.
+/// * `refusal` Why it was refused.
+fn exit_refused(zone: &Zone, refusal: &Refusal) -> ! {
+    error!("Zone {} ({}) was not turned on: {}.", zone.id, zone.Name, refusal);
+    exit(refusal_exit_code(refusal));
+}
+
+/// Finds the zone a command line argument refers to, exiting with `EXIT_NOT_FOUND` if there isn't
+/// exactly one.
+fn find_zone(zone_list: &ZoneList, zone_ref: &ZoneRef) -> Zone {
+    match zone_list.find(zone_ref) {
+        Ok(zone) => zone,
+        Err(e) => {
+            error!("{}", e);
+            exit(EXIT_NOT_FOUND);
+        }
+    }
+}
+
+fn log_formatter(buf: &mut Formatter, record: &Record, timezone: Option<Tz>) -> Result<(), std::io::Error> {
     let mut style = buf.style();
     let mut time_style = buf.style();
     time_style.set_color(Color::Rgb(0, 238, 255));
@@ -370,10 +1173,15 @@ fn log_formatter(buf: &mut Formatter, record: &Record) -> Result<(), std::io::Er
         Level::Debug => style.set_color(Color::Cyan),
         Level::Trace => style.set_color(Color::Magenta),
     };
+    let time_format = "%m-%d-%Y %H:%M:%S";
+    let time = match timezone {
+        Some(tz) => Utc::now().with_timezone(&tz).format(time_format).to_string(),
+        None => Local::now().format(time_format).to_string(),
+    };
     writeln!(
         buf,
         "{} [{}] - {}",
-        time_style.value(Local::now().format("%m-%d-%Y %H:%M:%S")),
+        time_style.value(time),
         style.value(record.level()),
         style.value(record.args())
     )
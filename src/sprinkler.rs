@@ -0,0 +1,115 @@
+use crate::config::{set_settings, MyConfig};
+use crate::sqlsprinkler::system::{self, get_system_status, get_zones, set_system_status, turn_off_all_zones};
+use crate::sqlsprinkler::zone::{self, Zone, ZoneRef};
+use crate::sqlsprinkler::create_pool;
+use std::error::Error;
+
+/// A connection to a SQLSprinkler system. Every operation returns an error instead of exiting, so it
+/// can be used from other programs.
+///
+/// The database pool and settings are global, so only one `Sprinkler` should be connected at a time.
+#[derive(Debug)]
+pub struct Sprinkler {
+    _connected: (),
+}
+
+impl Sprinkler {
+    /// Connects to the database, running any pending migrations, and sets up the GPIO.
+    /// # Params
+    /// * `settings` The settings to use, such as those read by `config::read_settings`.
+    /// # Return
+    /// The connected system, or an error if the database settings are missing, the database
+    /// can't be reached, or the GPIO is unavailable and `require_real_gpio` is set.
+    /// # Example
+    /// ```no_run
+    /// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+    /// use sqlsprinkler_cli::{config, Sprinkler};
+    /// config::read_settings()?;
+    /// let sprinkler = Sprinkler::connect(config::get_settings()).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn connect(settings: MyConfig) -> Result<Sprinkler, Box<dyn Error>> {
+        set_settings(settings);
+        create_pool().await?;
+        zone::init_gpio()?;
+        Ok(Sprinkler { _connected: () })
+    }
+
+    /// Gets every zone, in system order.
+    pub async fn zones(&self) -> Result<Vec<Zone>, sqlx::Error> {
+        Ok(get_zones().await?.zones)
+    }
+
+    /// Gets a single zone.
+    /// # Params
+    /// * `zone_ref` The id or name of the zone.
+    /// # Return
+    /// The zone, or an error if no zone or more than one zone matches.
+    pub async fn zone(&self, zone_ref: &ZoneRef) -> Result<Zone, Box<dyn Error>> {
+        Ok(get_zones().await?.find(zone_ref)?)
+    }
+
+    /// Gets whether the system schedule is enabled.
+    pub async fn system_enabled(&self) -> Result<bool, sqlx::Error> {
+        get_system_status().await
+    }
+
+    /// Enables or disables the system schedule.
+    pub async fn set_system_enabled(&self, enabled: bool) -> Result<(), Box<dyn Error>> {
+        set_system_status(enabled).await
+    }
+
+    /// Runs the system schedule, if it is enabled. Zones block the current thread while they run, so
+    /// this should be run with `tokio::task::spawn_blocking` or on a runtime with threads to spare.
+    /// # Return
+    /// * `true` if the zones were run, `false` if the schedule is disabled or the run was skipped.
+    pub async fn run(&self) -> Result<bool, Box<dyn Error>> {
+        if !get_system_status().await? {
+            return Ok(false);
+        }
+        system::run().await
+    }
+
+    /// Turns a zone on or off by hand, as the API does. A zone turned on with auto off is turned off
+    /// again after its time.
+    /// # Params
+    /// * `zone_ref` The id or name of the zone.
+    /// * `on` Whether to turn the zone on.
+    /// # Return
+    /// An error if the zone can't be found, or turning it on is refused because it doesn't allow
+    /// manual runs, a higher priority zone is running, away mode is on, or too many zones are on.
+    /// # Example
+    /// ```no_run
+    /// # async fn example(sprinkler: sqlsprinkler_cli::Sprinkler) -> Result<(), Box<dyn std::error::Error>> {
+    /// use sqlsprinkler_cli::sqlsprinkler::zone::ZoneRef;
+    /// sprinkler.toggle_zone(&ZoneRef::Name("Front Lawn".to_string()), true).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn toggle_zone(&self, zone_ref: &ZoneRef, on: bool) -> Result<(), Box<dyn Error>> {
+        let zone = self.zone(zone_ref).await?;
+        match system::toggle_zone(&zone, on, None).await? {
+            None => Ok(()),
+            Some(refusal) => Err(format!("Zone {} ({}) was not turned on: {}", zone.id, zone.Name, refusal).into()),
+        }
+    }
+
+    /// Turns every zone off.
+    pub async fn turn_off_all(&self) -> Result<(), Box<dyn Error>> {
+        turn_off_all_zones().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Sprinkler;
+    use crate::config::MyConfig;
+
+    #[tokio::test]
+    async fn connecting_without_database_settings_is_an_error() {
+        let e = Sprinkler::connect(MyConfig::default()).await.unwrap_err();
+        assert!(e.to_string().contains("sqlsprinkler_user"));
+    }
+}
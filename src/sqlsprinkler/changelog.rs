@@ -0,0 +1,173 @@
+use crate::sqlsprinkler::zone::ZoneList;
+use crate::sqlsprinkler::{get_pool, read, unix_now};
+use chrono::{NaiveDate, TimeZone, Utc};
+use log::error;
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt;
+
+/// The most changes that will be returned from a single changelog query.
+pub const MAX_CHANGELOG_ROWS: u32 = 500;
+
+/// Where a change was made from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Cli,
+    Api,
+    /// The zones file, reconciled when the daemon starts.
+    File,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Cli => write!(f, "cli"),
+            Source::Api => write!(f, "api"),
+            Source::File => write!(f, "file"),
+        }
+    }
+}
+
+/// A single change, as stored in the database.
+#[derive(Debug, PartialEq, Eq, sqlx::FromRow)]
+pub struct Change {
+    pub changed_at: i64,
+    pub source: String,
+    pub operation: String,
+    pub before_json: Option<String>,
+    pub after_json: Option<String>,
+}
+
+/// A single change, with the time formatted as RFC3339 and the before and after parsed, for api
+/// responses.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ChangeEntry {
+    pub changed_at: String,
+    pub source: String,
+    pub operation: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+impl From<Change> for ChangeEntry {
+    fn from(change: Change) -> Self {
+        let parse = |json: Option<String>| json.and_then(|json| serde_json::from_str(&json).ok());
+        ChangeEntry {
+            changed_at: Utc
+                .timestamp_opt(change.changed_at, 0)
+                .single()
+                .map(|time| time.to_rfc3339())
+                .unwrap_or_default(),
+            source: change.source,
+            operation: change.operation,
+            before: parse(change.before_json),
+            after: parse(change.after_json),
+        }
+    }
+}
+
+/// Converts something that was changed into JSON for the changelog.
+/// # Params
+/// * `value` The zone, order or setting, before or after the change.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::changelog;
+/// let before = changelog::snapshot(&zone);
+/// ```
+pub fn snapshot<T: Serialize>(value: &T) -> Option<Value> {
+    serde_json::to_value(value).ok()
+}
+
+/// Gets the system order of every zone, for recording a reorder in the changelog.
+/// # Params
+/// * `zone_list` The zones, in their current order.
+pub fn orders(zone_list: &ZoneList) -> Option<Value> {
+    let orders: Vec<_> = zone_list.zones.iter().map(|zone| (zone.id, zone.SystemOrder)).collect();
+    snapshot(&orders)
+}
+
+/// Gets the day scheduled runs resume on, for recording a change to it in the changelog.
+/// # Params
+/// * `resume_on` The day, or `None` if scheduled runs aren't disabled.
+pub fn resume_on(resume_on: Option<NaiveDate>) -> Option<Value> {
+    Some(serde_json::json!({ "resume_on": resume_on.map(|day| day.to_string()) }))
+}
+
+/// Records a change to the configuration. Failing to record is logged, but never stops the change.
+/// # Params
+/// * `source` Where the change was made from.
+/// * `operation` What was changed, such as `zone.update`.
+/// * `before` What was changed, before the change, if it existed.
+/// * `after` What was changed, after the change, if it still exists.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::changelog::{self, Source};
+/// changelog::record(Source::Cli, "zone.delete", changelog::snapshot(&zone), None);
+/// ```
+pub async fn record(source: Source, operation: &str, before: Option<Value>, after: Option<Value>) {
+    let pool = match get_pool() {
+        Ok(pool) => pool,
+        Err(e) => {
+            error!("Error recording {} change: {}", operation, e);
+            return;
+        }
+    };
+    let source = source.to_string();
+    let before_json = before.map(|before| before.to_string());
+    let after_json = after.map(|after| after.to_string());
+    let query = sqlx::query!(
+        "INSERT INTO ChangeLog (changed_at, source, operation, before_json, after_json) VALUES (?, ?, ?, ?, ?)",
+        unix_now(),
+        source,
+        operation,
+        before_json,
+        after_json
+    )
+    .execute(&pool)
+    .await;
+    if let Err(e) = query {
+        error!("Error recording {} change: {}", operation, e);
+    }
+}
+
+/// Gets the most recent changes to the configuration, newest first.
+/// # Params
+/// * `limit` The most changes to return, capped at `MAX_CHANGELOG_ROWS`
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::changelog;
+/// let changes = changelog::list(10);
+/// ```
+pub async fn list(limit: u32) -> Result<Vec<Change>, sqlx::Error> {
+    read(|pool| async move {
+        sqlx::query_as::<_, Change>(
+            "SELECT changed_at, source, operation, before_json, after_json FROM ChangeLog \
+             ORDER BY id DESC LIMIT ?",
+        )
+        .bind(limit.min(MAX_CHANGELOG_ROWS))
+        .fetch_all(&pool)
+        .await
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Change, ChangeEntry};
+    use serde_json::json;
+
+    #[test]
+    fn changes_are_parsed_for_the_api() {
+        let change = Change {
+            changed_at: 0,
+            source: "api".to_string(),
+            operation: "system.enable".to_string(),
+            before_json: Some(r#"{"system_enabled":false}"#.to_string()),
+            after_json: None,
+        };
+        let entry = ChangeEntry::from(change);
+        assert_eq!(entry.changed_at, "1970-01-01T00:00:00+00:00");
+        assert_eq!(entry.before, Some(json!({ "system_enabled": false })));
+        assert_eq!(entry.after, None);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::sqlsprinkler::history::ZoneRun;
+use crate::sqlsprinkler::system::local_date;
+use crate::sqlsprinkler::unix_now;
+use chrono::{Duration, NaiveDate};
+use plotters::prelude::*;
+use std::error::Error;
+use std::path::Path;
+
+/// The size of the rendered chart, in pixels.
+const CHART_SIZE: (u32, u32) = (800, 400);
+
+/// Adds up the minutes a zone ran on each day of a period.
+/// # Params
+/// * `runs` The runs of the zone.
+/// * `first_day` The first day of the period.
+/// * `days` The number of days in the period.
+/// * `to_date` Gets the date a run started on from its start time.
+/// # Return
+/// The minutes watered on each day, oldest first. Runs outside the period are ignored.
+fn daily_minutes<F>(runs: &[ZoneRun], first_day: NaiveDate, days: u32, to_date: F) -> Vec<f64>
+where
+    F: Fn(i64) -> Option<NaiveDate>,
+{
+    let mut minutes = vec![0.0; days as usize];
+    for run in runs {
+        let day = match to_date(run.started_at) {
+            Some(date) => (date - first_day).num_days(),
+            None => continue,
+        };
+        if day < 0 {
+            continue;
+        }
+        if let Some(total) = minutes.get_mut(day as usize) {
+            *total += run.duration_secs as f64 / 60.0;
+        }
+    }
+    minutes
+}
+
+/// Renders a bar chart of the minutes a zone watered on each of the last `days` days, including
+/// today, as an SVG.
+/// # Params
+/// * `title` The title of the chart.
+/// * `runs` The runs of the zone, which should cover at least the last `days` days.
+/// * `days` The number of days to chart.
+/// * `out` The file to write the SVG to.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::{chart, history};
+/// let runs = history::for_zone(1, Some(from), None, history::MAX_HISTORY_ROWS).await?;
+/// chart::render_svg("Front Lawn", &runs, 7, Path::new("zone-1.svg"))?;
+/// ```
+pub fn render_svg(title: &str, runs: &[ZoneRun], days: u32, out: &Path) -> Result<(), Box<dyn Error>> {
+    let days = days.max(1);
+    let today = local_date(unix_now()).ok_or("Unable to get today's date")?;
+    let first_day = today - Duration::days(days as i64 - 1);
+    let minutes = daily_minutes(runs, first_day, days, local_date);
+    let most = minutes.iter().cloned().fold(0.0, f64::max).max(1.0);
+
+    let root = SVGBackend::new(out, CHART_SIZE).into_drawing_area();
+    root.fill(&WHITE)?;
+    let mut chart = ChartBuilder::on(&root)
+        .caption(title, ("sans-serif", 20))
+        .margin(10)
+        .x_label_area_size(30)
+        .y_label_area_size(40)
+        .build_cartesian_2d((0..days).into_segmented(), 0.0..most * 1.1)?;
+    chart
+        .configure_mesh()
+        .disable_x_mesh()
+        .y_desc("Minutes")
+        .x_label_formatter(&|day| match day {
+            SegmentValue::CenterOf(day) => {
+                (first_day + Duration::days(*day as i64)).format("%m-%d").to_string()
+            }
+            _ => String::new(),
+        })
+        .draw()?;
+    chart.draw_series(
+        Histogram::vertical(&chart)
+            .style(BLUE.filled())
+            .margin(5)
+            .data(minutes.iter().enumerate().map(|(day, minutes)| (day as u32, *minutes))),
+    )?;
+    root.present()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::daily_minutes;
+    use crate::sqlsprinkler::history::ZoneRun;
+    use chrono::NaiveDate;
+
+    const DAY_SECS: i64 = 24 * 60 * 60;
+
+    fn run(started_at: i64, duration_secs: i64) -> ZoneRun {
+        ZoneRun { zone_id: 1, started_at, duration_secs }
+    }
+
+    #[test]
+    fn runs_are_added_up_by_day() {
+        let first_day = NaiveDate::from_ymd_opt(2026, 10, 1).unwrap();
+        // Day n of the test period starts at n * DAY_SECS.
+        let to_date = |started_at: i64| {
+            Some(first_day + chrono::Duration::days(started_at.div_euclid(DAY_SECS)))
+        };
+        let runs = vec![
+            run(DAY_SECS - 1, 600),
+            run(DAY_SECS + 10, 300),
+            run(DAY_SECS + 20, 300),
+            run(-1, 600),
+            run(3 * DAY_SECS, 600),
+        ];
+        assert_eq!(daily_minutes(&runs, first_day, 3, to_date), vec![10.0, 10.0, 0.0]);
+    }
+}
@@ -1,24 +1,264 @@
-use crate::sqlsprinkler::zone::{Zone, ZoneList, ZoneOrder};
-use crate::sqlsprinkler::{zone, zone::get_zone_from_id};
-use crate::{get_system_status, set_system_status, turn_off_all_zones};
+use crate::sqlsprinkler::zone::{Zone, ZoneId, ZoneList, ZoneOrder};
+use crate::sqlsprinkler::changelog::{self, Source};
+use crate::sqlsprinkler::{history, sequence, supervisor, system, unix_now, zone, zone::get_zone_from_id};
+use chrono::{DateTime, NaiveDate};
+use crate::config::get_settings;
 use log::{error, info};
+use percent_encoding::percent_decode_str;
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::net::UnixListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_stream::wrappers::UnixListenerStream;
 use serde::{Deserialize, Serialize};
-use warp::{http, reject, Filter};
-use crate::sqlsprinkler::system::get_zones;
+use warp::hyper::body::{to_bytes, Body};
+use warp::hyper::service::Service;
+use warp::{http, reject, Filter, Reply};
+use crate::sqlsprinkler::system::{
+    get_away_mode, get_disabled_until, get_system_status, get_zones, set_away_mode,
+    set_disabled_until, set_system_status,
+};
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 struct SysStatus {
     system_enabled: bool,
 }
 
+/// The body of a request to turn away mode on or off.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct AwayMode {
+    away_mode: bool,
+}
+
+/// The day the system schedule is disabled until, as `YYYY-MM-DD`, or `None` if it isn't.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct DisableUntil {
+    resume_on: Option<String>,
+}
+
+/// The run `system::run` would make right now, returned by `GET /system/plan`.
+#[derive(Debug, Serialize)]
+struct SystemPlan {
+    /// Why the whole run would be skipped, or `None` if it would go ahead.
+    skip_reason: Option<String>,
+    runs: Vec<PlannedZone>,
+    skipped: Vec<SkippedZone>,
+}
+
+/// A zone in a `SystemPlan`, in the order it runs.
+#[derive(Debug, Serialize)]
+struct PlannedZone {
+    id: ZoneId,
+    name: String,
+    gpio: i8,
+    /// How many seconds after the start of the run the zone turns on.
+    starts_after_secs: f64,
+    /// How many seconds the zone waters for.
+    run_secs: f64,
+    /// How many seconds the zone is actually held on, see `Zone::effective_runtime`.
+    on_secs: f64,
+}
+
+/// A zone left out of a `SystemPlan`, and why.
+#[derive(Debug, Serialize)]
+struct SkippedZone {
+    id: ZoneId,
+    name: String,
+    reason: String,
+}
+
+/// The query string accepted when fetching a zone's run history.
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    from: Option<String>,
+    to: Option<String>,
+    limit: Option<u32>,
+}
+
+/// The query string accepted when fetching the changelog.
+#[derive(Debug, Deserialize)]
+struct ChangelogQuery {
+    limit: Option<u32>,
+}
+
+/// The query string accepted when testing every zone.
+#[derive(Debug, Deserialize)]
+struct TestQuery {
+    secs: Option<u64>,
+}
+
+/// A single request in a batch.
+#[derive(Debug, Deserialize)]
+struct BatchOp {
+    method: String,
+    path: String,
+}
+
+/// The outcome of a single request in a batch. Successful requests have a `body`, failed ones an
+/// `error`.
+#[derive(Debug, Serialize)]
+struct BatchResult {
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// The TCP port the daemon listens on when `daemon_unix_socket` isn't set.
+const DAEMON_PORT: u16 = 3030;
+
+/// The most requests that may be sent in a single batch.
+const MAX_BATCH_OPS: usize = 20;
+
+/// The number of runs returned from a history query when no limit is given.
+const DEFAULT_HISTORY_LIMIT: u32 = 100;
+
+/// The number of changes returned from a changelog query when no limit is given.
+const DEFAULT_CHANGELOG_LIMIT: u32 = 100;
+
+/// The page served at `/`, which lists the zones and turns them on and off through the API.
+const DASHBOARD: &str = include_str!("dashboard.html");
+
 #[derive(Debug)]
 struct LengthMismatch;
 
 impl reject::Reject for LengthMismatch {}
 
-/// Main function for the daemon.
+/// A zone id in a path that can't be the id of a zone, see `zone::is_valid_zone_id`.
+#[derive(Debug)]
+struct BadZoneId(ZoneId);
+
+impl reject::Reject for BadZoneId {}
+
+/// Main function for the daemon. Serves on the Unix socket at `daemon_unix_socket` if it is set,
+/// otherwise on TCP port 3030, until it is asked to stop with Ctrl-C or SIGTERM. Requests in flight
+/// are finished, then every zone is turned off. Timed manual runs are kept, so the next start
+/// resumes them.
 pub async fn run() {
     info!("Starting daemon");
+    tokio::spawn(supervisor::supervise("history pruning", history::prune_periodically));
+    let socket_path = get_settings().daemon_unix_socket;
+    if socket_path.is_empty() {
+        match bind_tcp(([0, 0, 0, 0], DAEMON_PORT).into(), shutdown_signal()) {
+            Ok((addr, server)) => {
+                info!("Daemon started on {}", addr);
+                server.await;
+                info!("Daemon stopped");
+            }
+            Err(e) => {
+                error!("Unable to bind to port {}: {}", DAEMON_PORT, e);
+                return;
+            }
+        }
+    } else if !serve_unix(&socket_path).await {
+        return;
+    }
+    match system::turn_off_zones_except(&[]).await {
+        Ok(..) => info!("Turned off every zone"),
+        Err(e) => error!("Unable to turn off every zone: {}", e),
+    }
+}
+
+/// Removes a socket left behind at `path`. Anything else found there, such as a regular file named
+/// by mistake in `daemon_unix_socket`, is left alone and is an error.
+/// # Params
+/// * `path` Where the daemon's socket goes.
+fn remove_stale_socket(path: &Path) -> Result<(), String> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("Unable to check old socket {}: {}", path.display(), e)),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(format!("{} exists and is not a socket, refusing to remove it", path.display()));
+    }
+    fs::remove_file(path).map_err(|e| format!("Unable to remove old socket {}: {}", path.display(), e))
+}
+
+/// Serves the daemon's routes on a Unix domain socket until it is asked to stop, then removes the
+/// socket.
+/// # Params
+/// * `socket_path` Where to create the socket.
+/// # Return
+/// * `false` if the socket could not be created.
+async fn serve_unix(socket_path: &str) -> bool {
+    // A socket left behind by a daemon that didn't shut down cleanly would stop us binding.
+    if let Err(e) = remove_stale_socket(Path::new(socket_path)) {
+        error!("{}", e);
+        return false;
+    }
+    let listener = match UnixListener::bind(socket_path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Unable to bind to socket {}: {}", socket_path, e);
+            return false;
+        }
+    };
+    info!("Daemon started on socket {}", socket_path);
+    warp::serve(routes().with(warp::log::custom(log_request)))
+        .serve_incoming_with_graceful_shutdown(UnixListenerStream::new(listener), shutdown_signal())
+        .await;
+    info!("Daemon stopped, removing socket {}", socket_path);
+    if let Err(e) = fs::remove_file(socket_path) {
+        error!("Unable to remove socket {}: {}", socket_path, e);
+    }
+    true
+}
+
+/// Binds the daemon's routes to a TCP address.
+/// # Params
+/// * `addr` The address to listen on. Port 0 picks any free port.
+/// * `shutdown` Completes when the daemon should stop.
+/// # Return
+/// The address that was bound, and the server, which finishes the requests in flight and
+/// completes once `shutdown` does. An error if the address could not be bound, such as when
+/// another daemon is already using the port.
+fn bind_tcp(
+    addr: SocketAddr,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(SocketAddr, impl Future<Output = ()>), warp::Error> {
+    warp::serve(routes().with(warp::log::custom(log_request))).try_bind_with_graceful_shutdown(addr, shutdown)
+}
+
+/// Waits for the daemon to be asked to stop, either with Ctrl-C or by systemd.
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            error!("Unable to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
+}
+
+/// Logs the method, path, status, latency and remote address of a request the daemon handled.
+fn log_request(request: warp::log::Info) {
+    let remote = request
+        .remote_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|| "-".to_string());
+    info!(
+        "{} {} {} {} {:?}",
+        remote,
+        request.method(),
+        request.path(),
+        request.status().as_u16(),
+        request.elapsed()
+    );
+}
+
+/// Builds the read-only routes, which are also the ones that may be used in a batch.
+fn read_routes() -> impl Filter<Extract=(impl warp::Reply, ), Error=warp::Rejection> + Clone {
     // Handle get requests to /system/state -> Used to get the current state of the sys schedule
     let get_sys_status = warp::get()
         .and(warp::path("system"))
@@ -26,13 +266,26 @@ pub async fn run() {
         .and(warp::path::end())
         .and_then(get_sys_status);
 
-    // Handle put requests to /system/state -> Used to update the current state of the sys schedule
-    let set_sys_status = warp::put()
+    // Handle get requests to /system/away -> Used to get whether away mode is on
+    let get_away = warp::get()
         .and(warp::path("system"))
-        .and(warp::path("state"))
+        .and(warp::path("away"))
         .and(warp::path::end())
-        .and(sys_status_put_json())
-        .and_then(set_sys_status);
+        .and_then(get_away);
+
+    // Handle get requests to /system/disable-until -> Used to get the day scheduled runs resume on
+    let get_disable_until = warp::get()
+        .and(warp::path("system"))
+        .and(warp::path("disable-until"))
+        .and(warp::path::end())
+        .and_then(get_disable_until);
+
+    // Handle get requests to /system/plan -> Used to preview the run the schedule would make now
+    let get_plan = warp::get()
+        .and(warp::path("system"))
+        .and(warp::path("plan"))
+        .and(warp::path::end())
+        .and_then(get_plan);
 
     // Handle get requests to /zone/info -> Used for getting the INFORMATION of all the zones.
     let get_zone_status = warp::get()
@@ -46,10 +299,82 @@ pub async fn run() {
     let check_zone_state = warp::get()
         .and(warp::path("zone"))
         .and(warp::path("info"))
-        .and(warp::path::param::<u32>())
+        .and(zone_id_param())
         .and(warp::path::end())
         .and_then(check_zone_state);
 
+    // Handles get requests to /zone/{id}/history -> Used to get the run HISTORY of a zone.
+    let zone_history = warp::get()
+        .and(warp::path("zone"))
+        .and(zone_id_param())
+        .and(warp::path("history"))
+        .and(warp::path::end())
+        .and(warp::query::<HistoryQuery>())
+        .and_then(get_zone_history);
+
+    // Handles get requests to /changelog -> Used to get the recent CHANGES to the configuration.
+    let changelog = warp::get()
+        .and(warp::path("changelog"))
+        .and(warp::path::end())
+        .and(warp::query::<ChangelogQuery>())
+        .and_then(get_changelog);
+
+    get_sys_status
+        .or(get_away)
+        .or(get_disable_until)
+        .or(get_plan)
+        .or(get_zone_status)
+        .or(check_zone_state)
+        .or(zone_history)
+        .or(changelog)
+}
+
+/// Builds all of the routes served by the daemon.
+fn routes() -> impl Filter<Extract=(impl warp::Reply, ), Error=warp::Rejection> + Clone {
+    // Handle get requests to / -> Used to serve the DASHBOARD page.
+    let dashboard = warp::get()
+        .and(warp::path::end())
+        .map(|| warp::reply::html(DASHBOARD));
+
+    // Handle post requests to /batch -> Used to run several read-only requests in one round trip.
+    let batch = warp::post()
+        .and(warp::path("batch"))
+        .and(warp::path::end())
+        .and(batch_json())
+        .and_then(batch);
+
+    // Handle put requests to /system/state -> Used to update the current state of the sys schedule
+    let set_sys_status = warp::put()
+        .and(warp::path("system"))
+        .and(warp::path("state"))
+        .and(warp::path::end())
+        .and(sys_status_put_json())
+        .and_then(set_sys_status);
+
+    // Handle put requests to /system/away -> Used to turn away mode on or off
+    let set_away = warp::put()
+        .and(warp::path("system"))
+        .and(warp::path("away"))
+        .and(warp::path::end())
+        .and(away_put_json())
+        .and_then(set_away);
+
+    // Handle put requests to /system/disable-until -> Used to disable scheduled runs until a day
+    let set_disable_until = warp::put()
+        .and(warp::path("system"))
+        .and(warp::path("disable-until"))
+        .and(warp::path::end())
+        .and(disable_until_put_json())
+        .and_then(set_disable_until);
+
+    // Handle post requests to /system/test -> Used to TEST every zone, one after another.
+    let test_zones = warp::post()
+        .and(warp::path("system"))
+        .and(warp::path("test"))
+        .and(warp::path::end())
+        .and(warp::query::<TestQuery>())
+        .and_then(test_zones);
+
     // Handle put requests to /zone -> Used for TOGGLING a zone.
     let set_zone_status = warp::put()
         .and(warp::path("zone"))
@@ -87,17 +412,103 @@ pub async fn run() {
         .and(order_json())
         .and_then(_update_order);
 
-    let routes = get_sys_status
+    // Handles put requests to /zone/swap -> Used to SWAP the order of two zones
+    let swap_zones = warp::put()
+        .and(warp::path("zone"))
+        .and(warp::path("swap"))
+        .and(warp::path::end())
+        .and(swap_json())
+        .and_then(_swap_zones);
+
+    // Handles put requests to /zone/{id}/reorder/{up,down} -> Used to MOVE a zone one step in the order.
+    let move_zone = warp::put()
+        .and(warp::path("zone"))
+        .and(zone_id_param())
+        .and(warp::path("reorder"))
+        .and(warp::path::param::<zone::Direction>())
+        .and(warp::path::end())
+        .and_then(_move_zone);
+
+    // Handles post requests to /zone/{id}/stop -> Used to STOP a single running zone.
+    let stop_zone = warp::post()
+        .and(warp::path("zone"))
+        .and(zone_id_param())
+        .and(warp::path("stop"))
+        .and(warp::path::end())
+        .and_then(stop_zone);
+
+    // Handle post requests to /zone/{id}/reset-gpio -> Used to force a zone's pin off.
+    let reset_zone_gpio = warp::post()
+        .and(warp::path("zone"))
+        .and(zone_id_param())
+        .and(warp::path("reset-gpio"))
+        .and(warp::path::end())
+        .and_then(reset_zone_gpio);
+
+    // Handle post requests to /system/sequence/{name}/run -> Used to RUN a named sequence of zones.
+    let run_sequence = warp::post()
+        .and(warp::path("system"))
+        .and(warp::path("sequence"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("run"))
+        .and(warp::path::end())
+        .and_then(run_sequence);
+
+    read_routes()
+        .or(dashboard)
+        .or(batch)
         .or(set_sys_status)
-        .or(get_zone_status)
+        .or(set_away)
+        .or(set_disable_until)
+        .or(test_zones)
         .or(set_zone_status)
         .or(add_zone)
-        .or(check_zone_state)
         .or(delete_zone)
         .or(update_zone)
-        .or(update_order);
-    info!("Daemon started on port 3030");
-    warp::serve(routes).run(([0, 0, 0, 0], 3030)).await;
+        .or(update_order)
+        .or(swap_zones)
+        .or(move_zone)
+        .or(stop_zone)
+        .or(reset_zone_gpio)
+        .or(run_sequence)
+        .recover(body_error_reply)
+}
+
+/// Matches a zone id in the path, refusing one below 1.
+fn zone_id_param() -> impl Filter<Extract=(ZoneId, ), Error=warp::Rejection> + Clone {
+    warp::path::param::<ZoneId>().and_then(|id: ZoneId| async move {
+        if zone::is_valid_zone_id(id) {
+            Ok(id)
+        } else {
+            Err(reject::custom(BadZoneId(id)))
+        }
+    })
+}
+
+/// Turns a request body that couldn't be deserialized into a 400 with a JSON body explaining which
+/// field was missing or mistyped, a zone id below 1 in the path into a 400, and a body that isn't
+/// JSON into a 415. Other rejections are passed on unchanged.
+async fn body_error_reply(rejection: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Some(BadZoneId(id)) = rejection.find::<BadZoneId>() {
+        let body = warp::reply::json(&serde_json::json!({ "error": format!("zone ids must be at least 1, got {}", id) }));
+        return Ok(warp::reply::with_status(body, http::StatusCode::BAD_REQUEST));
+    }
+    // warp::body::json() rejects a Content-Type other than JSON before reading the body. A missing
+    // Content-Type is let through, for clients that never sent one.
+    if rejection.find::<reject::UnsupportedMediaType>().is_some() {
+        let body = warp::reply::json(&serde_json::json!({ "error": "The body must be JSON, sent with Content-Type: application/json" }));
+        return Ok(warp::reply::with_status(body, http::StatusCode::UNSUPPORTED_MEDIA_TYPE));
+    }
+    let e = match rejection.find::<warp::body::BodyDeserializeError>() {
+        Some(e) => e,
+        None => return Err(rejection),
+    };
+    let message = match std::error::Error::source(e) {
+        Some(cause) => cause.to_string(),
+        None => e.to_string(),
+    };
+    let body = warp::reply::json(&serde_json::json!({ "error": message }));
+    Ok(warp::reply::with_status(body, http::StatusCode::BAD_REQUEST))
 }
 
 /// Used to filter a put request to change the system status
@@ -105,6 +516,16 @@ fn sys_status_put_json() -> impl Filter<Extract=(SysStatus, ), Error=warp::Rejec
     warp::body::content_length_limit(1024 * 16).and(warp::body::json())
 }
 
+/// Used to filter a put request to turn away mode on or off
+fn away_put_json() -> impl Filter<Extract=(AwayMode, ), Error=warp::Rejection> + Clone {
+    warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+}
+
+/// Used to filter a put request to disable scheduled runs until a day
+fn disable_until_put_json() -> impl Filter<Extract=(DisableUntil, ), Error=warp::Rejection> + Clone {
+    warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+}
+
 /// Used to filter a put request to toggle a specific zone.
 fn zone_status_put_json() -> impl Filter<Extract=(zone::ZoneToggle, ), Error=warp::Rejection> + Clone {
     warp::body::content_length_limit(1024 * 16).and(warp::body::json())
@@ -122,7 +543,7 @@ fn zone_delete_json() -> impl Filter<Extract=(zone::ZoneDelete, ), Error=warp::R
 }
 
 /// Used to filter a put request to update a zone.
-fn zone_json() -> impl Filter<Extract=(Zone, ), Error=warp::Rejection> + Clone {
+fn zone_json() -> impl Filter<Extract=(zone::ZoneUpdate, ), Error=warp::Rejection> + Clone {
     warp::body::content_length_limit(1024 * 16).and(warp::body::json())
 }
 
@@ -131,9 +552,18 @@ fn order_json() -> impl Filter<Extract=(ZoneOrder, ), Error=warp::Rejection> + C
     warp::body::content_length_limit(1024 * 16).and(warp::body::json())
 }
 
+/// Used to filter a post request to run a batch of requests
+fn batch_json() -> impl Filter<Extract=(Vec<BatchOp>, ), Error=warp::Rejection> + Clone {
+    warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+}
 
-async fn check_zone_state(id: u32) -> Result<impl warp::Reply, warp::Rejection> {
-    match get_zone_from_id(id as i8).await {
+/// Used to filter a put request to swap the order of two zones
+fn swap_json() -> impl Filter<Extract=(zone::ZoneSwap, ), Error=warp::Rejection> + Clone {
+    warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+}
+
+async fn check_zone_state(id: ZoneId) -> Result<impl warp::Reply, warp::Rejection> {
+    match get_zone_from_id(id).await {
         Ok(z) => {
             Ok(warp::reply::json(&z.get_with_state()))
         }
@@ -144,9 +574,61 @@ async fn check_zone_state(id: u32) -> Result<impl warp::Reply, warp::Rejection>
     }
 }
 
+/// Parses an optional RFC3339 timestamp from a query string into seconds since the unix epoch.
+fn parse_query_time(value: &Option<String>) -> Result<Option<i64>, String> {
+    match value {
+        None => Ok(None),
+        Some(v) => match DateTime::parse_from_rfc3339(v) {
+            Ok(t) => Ok(Some(t.timestamp())),
+            Err(e) => Err(format!("'{}' is not an RFC3339 timestamp: {}", v, e)),
+        },
+    }
+}
+
+/// Gets the run history of a zone, newest first.
+/// # Params
+/// * `id` The id of the zone.
+/// * `query` The optional `from` and `to` RFC3339 timestamps and `limit` on returned runs.
+async fn get_zone_history(id: ZoneId, query: HistoryQuery) -> Result<impl warp::Reply, warp::Rejection> {
+    let (from, to) = match (parse_query_time(&query.from), parse_query_time(&query.to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(e), _) | (_, Err(e)) => {
+            let body = warp::reply::json(&serde_json::json!({ "error": e }));
+            return Ok(warp::reply::with_status(body, http::StatusCode::BAD_REQUEST).into_response());
+        }
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_LIMIT);
+    match history::for_zone(id, from, to, limit).await {
+        Ok(runs) => {
+            let runs: Vec<history::ZoneRunEntry> = runs.into_iter().map(Into::into).collect();
+            Ok(warp::reply::json(&runs).into_response())
+        }
+        Err(e) => {
+            error!("Error getting zone history: {}", e);
+            Err(reject::reject())
+        }
+    }
+}
+
+/// Gets the most recent changes to the configuration, newest first.
+/// # Params
+/// * `query` The optional `limit` on returned changes.
+async fn get_changelog(query: ChangelogQuery) -> Result<impl warp::Reply, warp::Rejection> {
+    match changelog::list(query.limit.unwrap_or(DEFAULT_CHANGELOG_LIMIT)).await {
+        Ok(changes) => {
+            let changes: Vec<changelog::ChangeEntry> = changes.into_iter().map(Into::into).collect();
+            Ok(warp::reply::json(&changes))
+        }
+        Err(e) => {
+            error!("Error getting the changelog: {}", e);
+            Err(reject::reject())
+        }
+    }
+}
+
 /// Gets the system status
 /// # Returns
-///     * `json` A json object representing the current state of the system schedule.
+/// * `json` A json object representing the current state of the system schedule.
 async fn get_sys_status() -> Result<impl warp::Reply, warp::Rejection> {
     let status = match get_system_status().await {
         Ok(status) => status,
@@ -163,15 +645,201 @@ async fn get_sys_status() -> Result<impl warp::Reply, warp::Rejection> {
 
 /// Sets the system status
 /// # Params
-///     * `_status` The SysStatus object containing the value we are going to set the system status to.
+/// * `_status` The SysStatus object containing the value we are going to set the system status to.
 async fn set_sys_status(_status: SysStatus) -> Result<impl warp::Reply, warp::Rejection> {
-    return match set_system_status(_status.system_enabled).await {
-        Ok(_) => Ok(warp::reply::with_status("Success", http::StatusCode::OK)),
+    let before = match get_system_status().await {
+        Ok(system_enabled) => changelog::snapshot(&SysStatus { system_enabled }),
+        Err(_) => None,
+    };
+    // The error isn't Send, so it can't be held across recording the change.
+    let set = set_system_status(_status.system_enabled).await.map_err(|e| e.to_string());
+    match set {
+        Ok(_) => {
+            changelog::record(Source::Api, "system.enable", before, changelog::snapshot(&_status)).await;
+            Ok(warp::reply::with_status("Success", http::StatusCode::OK))
+        }
         Err(e) => {
             error!("Error setting system status: {}", e);
             Err(reject::not_found())
         }
+    }
+}
+
+/// Gets whether away mode is on
+async fn get_away() -> Result<impl warp::Reply, warp::Rejection> {
+    match get_away_mode().await {
+        Ok(away_mode) => Ok(warp::reply::json(&AwayMode { away_mode })),
+        Err(e) => {
+            error!("Error getting away mode: {}", e);
+            Err(reject::reject())
+        }
+    }
+}
+
+/// Turns away mode on or off
+/// # Params
+/// * `away` The AwayMode object containing whether away mode should be on.
+async fn set_away(away: AwayMode) -> Result<impl warp::Reply, warp::Rejection> {
+    match set_away_mode(away.away_mode).await {
+        Ok(..) => Ok(warp::reply::with_status("Success", http::StatusCode::OK)),
+        Err(e) => {
+            error!("Error setting away mode: {}", e);
+            Err(reject::reject())
+        }
+    }
+}
+
+/// Gets the day scheduled runs resume on, if the system schedule is disabled until then
+async fn get_disable_until() -> Result<impl warp::Reply, warp::Rejection> {
+    match get_disabled_until().await {
+        Ok(resume_on) => Ok(warp::reply::json(&DisableUntil { resume_on: resume_on.map(|date| date.to_string()) })),
+        Err(e) => {
+            error!("Error getting the day the system is disabled until: {}", e);
+            Err(reject::reject())
+        }
+    }
+}
+
+/// Gets the run the system schedule would make right now, without running anything
+async fn get_plan() -> Result<impl warp::Reply, warp::Rejection> {
+    let skip_reason = match get_system_status().await {
+        Ok(true) => system::skip_reason().await.map_err(|e| e.to_string()),
+        Ok(false) => Ok(Some("The system schedule is disabled".to_string())),
+        Err(e) => Err(e.to_string()),
+    };
+    let skip_reason = match skip_reason {
+        Ok(skip_reason) => skip_reason,
+        Err(e) => {
+            error!("Error checking whether the scheduled run would be skipped: {}", e);
+            return Err(reject::reject());
+        }
     };
+    let zone_list = match get_zones().await {
+        Ok(zone_list) => zone_list,
+        Err(e) => {
+            error!("Error getting the zones for the plan: {}", e);
+            return Err(reject::reject());
+        }
+    };
+    let month = system::current_month();
+    let runs = system::plan(&zone_list, month)
+        .iter()
+        .map(|run| PlannedZone {
+            id: run.zone.id,
+            name: run.zone.Name.clone(),
+            gpio: run.zone.GPIO,
+            starts_after_secs: run.offset.as_secs_f64(),
+            run_secs: run.run_time.as_secs_f64(),
+            on_secs: run.on_time.as_secs_f64(),
+        })
+        .collect();
+    let skipped = zone_list
+        .zones
+        .iter()
+        .filter_map(|zone| {
+            let reason = system::plan_skip_reason(zone, month)?;
+            Some(SkippedZone { id: zone.id, name: zone.Name.clone(), reason })
+        })
+        .collect();
+    Ok(warp::reply::json(&SystemPlan { skip_reason, runs, skipped }))
+}
+
+/// Disables scheduled runs until a day, or resumes them now
+/// # Params
+/// * `disable` The DisableUntil object with the day scheduled runs resume on, or `null` to resume now.
+async fn set_disable_until(disable: DisableUntil) -> Result<warp::reply::Response, warp::Rejection> {
+    let resume_on = match disable.resume_on.as_deref().map(str::parse::<NaiveDate>).transpose() {
+        Ok(resume_on) => resume_on,
+        Err(e) => {
+            let body = warp::reply::json(&serde_json::json!({
+                "error": format!("resume_on must be a date such as 2026-10-20: {}", e)
+            }));
+            return Ok(warp::reply::with_status(body, http::StatusCode::BAD_REQUEST).into_response());
+        }
+    };
+    if let Some(Err(e)) = resume_on.map(|resume_on| system::check_resume_on(resume_on, unix_now())) {
+        let body = warp::reply::json(&serde_json::json!({ "error": format!("resume_on must be after today: {}", e) }));
+        return Ok(warp::reply::with_status(body, http::StatusCode::BAD_REQUEST).into_response());
+    }
+    let before = match get_disabled_until().await {
+        Ok(before) => before,
+        Err(e) => {
+            error!("Error getting the day the system is disabled until: {}", e);
+            return Err(reject::reject());
+        }
+    };
+    match set_disabled_until(resume_on).await {
+        Ok(..) => {
+            let (before, after) = (changelog::resume_on(before), changelog::resume_on(resume_on));
+            changelog::record(Source::Api, "system.disable_until", before, after).await;
+            Ok(warp::reply::with_status("Success", http::StatusCode::OK).into_response())
+        }
+        Err(e) => {
+            error!("Error setting the day the system is disabled until: {}", e);
+            Err(reject::reject())
+        }
+    }
+}
+
+/// Tests every zone, one after another, responding once they are all done. Refused while away mode
+/// is on. Pending runs are cancelled first, and zones that don't allow manual runs are skipped.
+/// # Params
+/// * `query` The optional `secs` to run each zone for, defaulting to `test_duration_secs`.
+/// # Returns
+/// * `json` The result of each zone's test, in system order.
+async fn test_zones(query: TestQuery) -> Result<warp::reply::Response, warp::Rejection> {
+    match get_away_mode().await {
+        Ok(false) => {}
+        Ok(true) => {
+            info!("Refusing to test the zones, away mode is on");
+            return Ok(warp::reply::with_status("Away mode is on", http::StatusCode::CONFLICT).into_response());
+        }
+        Err(e) => {
+            error!("Error checking away mode: {}", e);
+            return Err(reject::reject());
+        }
+    }
+    let zone_list = get_zone_list().await?;
+    let secs = query.secs.unwrap_or(get_settings().test_duration_secs);
+    let run_time = std::time::Duration::from_secs(secs);
+    for zone in &zone_list.zones {
+        system::stop_zone(zone).await;
+    }
+    // Zones block their thread while they're tested, so test them off of the daemon's runtime.
+    let tested = tokio::task::spawn_blocking(move || {
+        zone_list
+            .zones
+            .iter()
+            .map(|zone| test_or_skip(zone, run_time))
+            .collect::<Vec<zone::ZoneTestResult>>()
+    })
+    .await;
+    match tested {
+        Ok(results) => Ok(warp::reply::json(&results).into_response()),
+        Err(e) => {
+            error!("Error testing zones: {}", e);
+            Err(reject::reject())
+        }
+    }
+}
+
+/// Tests the given zone, unless it doesn't allow manual runs.
+/// # Params
+/// * `zone` The zone to test.
+/// * `run_time` How long to run the zone for.
+/// # Returns
+/// * `ZoneTestResult` The outcome of the test, failed if the zone was skipped.
+fn test_or_skip(zone: &Zone, run_time: std::time::Duration) -> zone::ZoneTestResult {
+    if zone.AllowManual {
+        return zone.test_report(run_time);
+    }
+    info!("Not testing zone {}, it does not allow manual runs", zone.id);
+    zone::ZoneTestResult {
+        zone_id: zone.id,
+        name: zone.Name.clone(),
+        passed: false,
+        detail: "not tested, it does not allow manual runs".to_string(),
+    }
 }
 
 /// Gets the status of all the zones.
@@ -208,39 +876,182 @@ async fn set_zone_status(_zone: zone::ZoneToggle) -> Result<impl warp::Reply, wa
             return Err(reject::not_found());
         }
     };
-    if state {
-        /*
-        NOTE:
-         Here we want to run the zone instead of just turning it on. This is because we are running
-         inside the daemon, and we can use the "auto-off" feature to automatically turn off the zone
-         if unattended.
-         */
-
-        // Ensure that all zones are off
-        match turn_off_all_zones().await {
-            Ok(..) => {}
+    let requested = _zone.time.map(|time| time.duration(get_settings().time_unit));
+    match system::toggle_zone(&zone, state, requested).await {
+        Ok(None) => {}
+        Ok(Some(refusal)) => {
+            let status = match refusal {
+                system::Refusal::ManualNotAllowed => http::StatusCode::FORBIDDEN,
+                _ => http::StatusCode::CONFLICT,
+            };
+            return Ok(warp::reply::with_status(refusal.to_string(), status));
+        }
+        Err(e) => {
+            error!("Error toggling zone {}: {}", zone.id, e);
+            return Err(reject::reject());
+        }
+    }
+    Ok(warp::reply::with_status("Ok".to_string(), http::StatusCode::OK))
+}
+
+/// Stops a single zone, turning it off and cancelling its pending auto off.
+/// # Params
+///    * `id` The id of the zone to stop.
+async fn stop_zone(id: ZoneId) -> Result<impl warp::Reply, warp::Rejection> {
+    let zone_list = get_zone_list().await?;
+    let zone = match zone_list.zones.into_iter().find(|z| z.id == id) {
+        Some(zone) => zone,
+        None => return Err(reject::not_found()),
+    };
+    system::stop_zone(&zone).await;
+    Ok(warp::reply::with_status("Stopped zone", http::StatusCode::OK))
+}
+
+/// Forces the given zone's GPIO pin to off and cancels any pending auto off.
+/// # Params
+/// * `id` The id of the zone to reset.
+async fn reset_zone_gpio(id: ZoneId) -> Result<impl warp::Reply, warp::Rejection> {
+    let zone_list = get_zone_list().await?;
+    let zone = match zone_list.zones.into_iter().find(|z| z.id == id) {
+        Some(zone) => zone,
+        None => return Err(reject::not_found()),
+    };
+    match zone.reset_gpio() {
+        Ok(..) => Ok(warp::reply::with_status("Reset zone GPIO", http::StatusCode::OK)),
+        Err(e) => {
+            error!("Error resetting zone GPIO: {}", e);
+            Err(reject::reject())
+        }
+    }
+}
+
+/// Runs a batch of read-only requests, one after another, against the same handlers as the routes.
+/// # Params
+/// * `ops` The method and path of each request. Only GET is supported.
+/// # Returns
+/// * `json` The status and body or error of each request, in the order they were given.
+async fn batch(ops: Vec<BatchOp>) -> Result<impl warp::Reply, warp::Rejection> {
+    if ops.len() > MAX_BATCH_OPS {
+        let body = warp::reply::json(&serde_json::json!({
+            "error": format!("A batch may have at most {} requests", MAX_BATCH_OPS)
+        }));
+        return Ok(warp::reply::with_status(body, http::StatusCode::BAD_REQUEST));
+    }
+    let service = warp::service(read_routes());
+    let mut results = Vec::with_capacity(ops.len());
+    for op in ops {
+        if !op.method.eq_ignore_ascii_case("GET") {
+            results.push(BatchResult {
+                status: http::StatusCode::METHOD_NOT_ALLOWED.as_u16(),
+                body: None,
+                error: Some("Only GET requests may be batched".to_string()),
+            });
+            continue;
+        }
+        let request = match http::Request::get(op.path.as_str()).body(Body::empty()) {
+            Ok(request) => request,
+            Err(e) => {
+                results.push(BatchResult {
+                    status: http::StatusCode::BAD_REQUEST.as_u16(),
+                    body: None,
+                    error: Some(format!("'{}' is not a valid path: {}", op.path, e)),
+                });
+                continue;
+            }
+        };
+        // Dispatched through the routes themselves, so a batched request behaves just like a lone one.
+        // warp refuses to route from inside a handler, so each request is routed on its own task.
+        let mut service = service.clone();
+        let response = match tokio::spawn(async move { service.call(request).await }).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(never)) => match never {},
             Err(e) => {
-                error!("Error turning off all zones: {}", e);
-                // return Ok(warp::reply::with_status("Error", http::StatusCode::INTERNAL_SERVER_ERROR));
+                error!("Batched request to {} failed: {}", op.path, e);
+                results.push(BatchResult {
+                    status: http::StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    body: None,
+                    error: Some("The request failed".to_string()),
+                });
+                continue;
             }
+        };
+        let status = response.status();
+        let bytes = to_bytes(response.into_body()).await.unwrap_or_default();
+        let result = if status.is_success() {
+            BatchResult {
+                status: status.as_u16(),
+                body: Some(serde_json::from_slice(&bytes).unwrap_or_else(|_| {
+                    serde_json::Value::String(String::from_utf8_lossy(&bytes).to_string())
+                })),
+                error: None,
+            }
+        } else {
+            let error = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+                Ok(body) if body["error"].is_string() => body["error"].as_str().unwrap_or_default().to_string(),
+                _ if bytes.is_empty() => status.canonical_reason().unwrap_or_default().to_string(),
+                _ => String::from_utf8_lossy(&bytes).to_string(),
+            };
+            BatchResult { status: status.as_u16(), body: None, error: Some(error) }
+        };
+        results.push(result);
+    }
+    Ok(warp::reply::with_status(warp::reply::json(&results), http::StatusCode::OK))
+}
+
+/// Starts running a named sequence of zones, responding before the zones have finished.
+/// # Params
+/// * `name` The percent encoded name of the sequence.
+async fn run_sequence(name: String) -> Result<impl warp::Reply, warp::Rejection> {
+    let name = percent_decode_str(&name).decode_utf8_lossy().to_string();
+    let sequence = match sequence::get(&name).await {
+        Ok(Some(sequence)) => sequence,
+        Ok(None) => return Err(reject::not_found()),
+        Err(e) => {
+            error!("Error getting sequence {}: {}", name, e);
+            return Err(reject::reject());
+        }
+    };
+    match get_away_mode().await {
+        Ok(false) => {}
+        Ok(true) => {
+            info!("Refusing to run sequence {}, away mode is on", sequence.name);
+            return Ok(warp::reply::with_status("Away mode is on", http::StatusCode::CONFLICT));
+        }
+        Err(e) => {
+            error!("Error checking away mode: {}", e);
+            return Err(reject::reject());
         }
-        zone.run_async();
-    } else {
-        zone.turn_off();
     }
-    Ok(warp::reply::with_status("Ok", http::StatusCode::OK))
+    // Zones block their thread while they run, so run them off of the daemon's runtime.
+    let handle = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = handle.block_on(system::run_sequence(&sequence)) {
+            error!("Error running sequence {}: {}", sequence.name, e);
+        }
+    });
+    Ok(warp::reply::with_status("Running sequence", http::StatusCode::ACCEPTED))
 }
 
 /// Adds a new zone to the system
 /// # Params
-///     * `_zone` The new zone we are wanting to add to the system.
+/// * `_zone` The new zone we are wanting to add to the system.
 async fn _add_zone(_zone: zone::ZoneAdd) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Err(e) = zone::check_gpio_not_reserved(_zone.gpio) {
+        return Ok(reserved_gpio_reply(e));
+    }
+    let after = changelog::snapshot(&_zone);
     match zone::add(_zone).await {
-        Ok(..) => {
+        Ok(true) => {
+            changelog::record(Source::Api, "zone.add", None, after).await;
             Ok(warp::reply::with_status(
                 "Adding zone",
                 http::StatusCode::CREATED,
-            ))
+            ).into_response())
+        }
+        Ok(false) => {
+            let error = format!("There are already max_zones ({}) zones", get_settings().max_zones);
+            let body = warp::reply::json(&serde_json::json!({ "error": error }));
+            Ok(warp::reply::with_status(body, http::StatusCode::CONFLICT).into_response())
         }
         Err(e) => {
             error!("Error adding zone: {}", e);
@@ -251,10 +1062,23 @@ async fn _add_zone(_zone: zone::ZoneAdd) -> Result<impl warp::Reply, warp::Rejec
 
 /// Deletes a zone
 /// # Params
-///     * `_zone` The zone we are wanting to delete.
+/// * `_zone` The zone we are wanting to delete.
 async fn _delete_zone(_zone: zone::ZoneDelete) -> Result<impl warp::Reply, warp::Rejection> {
+    let zone_list = get_zone_list().await?;
+    if let Some(running) = zone_list.zones.iter().find(|z| z.id == _zone.id && z.is_running()) {
+        if !_zone.force {
+            return Ok(warp::reply::with_status(
+                "Zone is running, stop it or set force to delete it",
+                http::StatusCode::CONFLICT,
+            ));
+        }
+        info!("Stopping zone {} before deleting it", running.id);
+        running.stop();
+    }
+    let before = zone_list.zones.iter().find(|z| z.id == _zone.id).and_then(changelog::snapshot);
     match zone::delete(_zone).await {
         Ok(_) => {
+            changelog::record(Source::Api, "zone.delete", before, None).await;
             Ok(warp::reply::with_status(
                 "Deleted zone",
                 http::StatusCode::OK,
@@ -269,8 +1093,8 @@ async fn _delete_zone(_zone: zone::ZoneDelete) -> Result<impl warp::Reply, warp:
 
 /// Updates a zone
 /// # Params
-///     * `_zone` The zone we want to update.
-async fn _update_zone(_zone: Zone) -> Result<impl warp::Reply, warp::Rejection> {
+/// * `_zone` The zone we want to update.
+async fn _update_zone(_zone: zone::ZoneUpdate) -> Result<impl warp::Reply, warp::Rejection> {
     let zone = match get_zone_from_id(_zone.id).await {
         Ok(zone) => zone,
         Err(e) => {
@@ -278,11 +1102,20 @@ async fn _update_zone(_zone: Zone) -> Result<impl warp::Reply, warp::Rejection>
             return Err(reject::not_found());
         }
     };
+    // A zone already on a reserved pin is flagged by `sys diagnose`, but can still be edited.
+    if _zone.GPIO != zone.GPIO {
+        if let Err(e) = zone::check_gpio_not_reserved(_zone.GPIO) {
+            return Ok(reserved_gpio_reply(e));
+        }
+    }
     return match zone.update(_zone).await {
         Ok(_) => {
+            let after = get_zone_from_id(zone.id).await.ok();
+            let after = after.as_ref().and_then(changelog::snapshot);
+            changelog::record(Source::Api, "zone.update", changelog::snapshot(&zone), after).await;
             Ok(warp::reply::with_status(
                 "Updated zone",
-                http::StatusCode::OK))
+                http::StatusCode::OK).into_response())
         }
         Err(e) => {
             error!("Error updating zone: {}", e);
@@ -291,21 +1124,332 @@ async fn _update_zone(_zone: Zone) -> Result<impl warp::Reply, warp::Rejection>
     };
 }
 
+/// Builds the 400 response for a zone that would use a reserved GPIO pin.
+/// # Params
+/// * `message` Which pin is reserved.
+fn reserved_gpio_reply(message: String) -> warp::reply::Response {
+    let body = warp::reply::json(&serde_json::json!({ "error": message }));
+    warp::reply::with_status(body, http::StatusCode::BAD_REQUEST).into_response()
+}
+
 /// Updates the order of all zones in the system
 /// # Params
-///     * `_order` The new ordering of the system
+/// * `_order` The new ordering of the system
 async fn _update_order(_order: ZoneOrder) -> Result<impl warp::Reply, warp::Rejection> {
-    let zone_list = get_zone_list().await?;
-    let mut counter = 0;
-    return if zone_list.zones.len() == _order.order.len() {
-        for zone in zone_list.zones.iter() {
-            let mut _zone = &zone;
-            let new_order = _order.order.as_slice()[counter];
-            _zone.set_order(new_order).await;
-            counter += 1;
-        }
-        Ok(warp::reply::with_status("ok", http::StatusCode::OK))
-    } else {
-        Err(reject::custom(LengthMismatch))
-    };
+    let before = changelog::orders(&get_zone_list().await?);
+    match zone::reorder(&_order).await {
+        Ok(true) => {
+            changelog::record(Source::Api, "zone.reorder", before, changelog::snapshot(&_order)).await;
+            Ok(warp::reply::with_status("ok", http::StatusCode::OK))
+        }
+        Ok(false) => Err(reject::custom(LengthMismatch)),
+        Err(e) => {
+            error!("Error updating zone order: {}", e);
+            Err(reject::reject())
+        }
+    }
+}
+
+/// Swaps the order of two zones
+/// # Params
+/// * `_swap` The ids of the two zones we want to swap.
+async fn _swap_zones(_swap: zone::ZoneSwap) -> Result<impl warp::Reply, warp::Rejection> {
+    let before = changelog::orders(&get_zone_list().await?);
+    let after = changelog::snapshot(&_swap);
+    match zone::swap(_swap).await {
+        Ok(true) => {
+            changelog::record(Source::Api, "zone.swap", before, after).await;
+            Ok(warp::reply::with_status("Swapped zones", http::StatusCode::OK))
+        }
+        Ok(false) => Err(reject::not_found()),
+        Err(e) => {
+            error!("Error swapping zones: {}", e);
+            Err(reject::reject())
+        }
+    }
+}
+
+/// Moves a zone one step up or down the system order
+/// # Params
+/// * `id` The id of the zone to move.
+/// * `direction` Which way to move it.
+async fn _move_zone(id: ZoneId, direction: zone::Direction) -> Result<impl warp::Reply, warp::Rejection> {
+    let before = changelog::orders(&get_zone_list().await?);
+    match zone::move_zone(id, direction).await {
+        Ok(Some(order)) => {
+            changelog::record(Source::Api, "zone.reorder", before, changelog::snapshot(&order)).await;
+            Ok(warp::reply::json(&serde_json::json!({ "zone_ids": order })))
+        }
+        Ok(None) => Err(reject::not_found()),
+        Err(e) => {
+            error!("Error moving zone {}: {}", id, e);
+            Err(reject::reject())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bind_tcp, remove_stale_socket, routes, test_or_skip, zone_id_param, zone_json};
+    use crate::sqlsprinkler::zone::Zone;
+    use std::future::pending;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+    use tokio::sync::oneshot;
+    use tokio::time::timeout;
+    use warp::http::StatusCode;
+
+    #[tokio::test]
+    async fn daemon_binds_its_port() {
+        let (addr, server) = bind_tcp(([127, 0, 0, 1], 0).into(), pending()).unwrap();
+        tokio::spawn(server);
+        assert!(TcpStream::connect(addr).await.is_ok());
+        // The port is taken now, so a second daemon can't start on it.
+        assert!(bind_tcp(addr, pending()).is_err());
+    }
+
+    #[tokio::test]
+    async fn daemon_stops_when_asked() {
+        let (stop, stopped) = oneshot::channel::<()>();
+        let (_, server) = bind_tcp(([127, 0, 0, 1], 0).into(), async {
+            let _ = stopped.await;
+        })
+        .unwrap();
+        let server = tokio::spawn(server);
+        stop.send(()).unwrap();
+        assert!(timeout(Duration::from_secs(5), server).await.is_ok());
+    }
+
+    #[test]
+    fn only_a_socket_is_removed_from_the_socket_path() {
+        let path = std::env::temp_dir().join("sqlsprinkler_stale_socket_test");
+        let _ = std::fs::remove_file(&path);
+        assert!(remove_stale_socket(&path).is_ok());
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(remove_stale_socket(&path).is_err());
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(remove_stale_socket(&path).is_ok());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn zone_without_manual_runs_is_not_tested() {
+        let zone = Zone { id: 2, AllowManual: false, ..Zone::default() };
+        let result = test_or_skip(&zone, Duration::from_secs(1));
+        assert_eq!(result.zone_id, 2);
+        assert!(!result.passed);
+        assert!(result.detail.contains("does not allow manual runs"));
+    }
+
+    #[tokio::test]
+    async fn test_with_bad_secs_is_bad_request() {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/system/test?secs=soon")
+            .reply(&routes())
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn dashboard_is_served_at_the_root() {
+        let res = warp::test::request().path("/").reply(&routes()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+        let page = String::from_utf8_lossy(res.body());
+        assert!(page.contains("/zone/info"));
+        // Every field the page reads from a zone must be one that /zone/info sends.
+        let sent = serde_json::to_value(Zone::default().get_with_state()).unwrap();
+        let read: Vec<&str> = page
+            .split("zone.")
+            .skip(1)
+            .map(|rest| rest.split(|c: char| !c.is_ascii_alphanumeric() && c != '_').next().unwrap())
+            .collect();
+        assert!(read.contains(&"name"));
+        for field in read {
+            assert!(sent.get(field).is_some(), "the dashboard reads zone.{}, which /zone/info doesn't send", field);
+        }
+    }
+
+    #[tokio::test]
+    async fn unsupported_method_is_rejected() {
+        let res = warp::test::request()
+            .method("PATCH")
+            .path("/zone")
+            .reply(&routes())
+            .await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn batch_reports_each_request_on_its_own() {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/batch")
+            .json(&serde_json::json!([
+                { "method": "PUT", "path": "/system/state" },
+                { "method": "GET", "path": "/nowhere" },
+            ]))
+            .reply(&routes())
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body[0]["status"], 405);
+        assert!(body[0]["error"].is_string());
+        assert_eq!(body[1]["status"], 404);
+        assert_eq!(body[1]["error"], "Not Found");
+        assert!(body[1].get("body").is_none());
+    }
+
+    #[tokio::test]
+    async fn zone_id_below_one_is_bad_request() {
+        for path in ["/zone/info/0", "/zone/-1/history"] {
+            let res = warp::test::request().path(path).reply(&routes()).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", path);
+        }
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/zone")
+            .json(&serde_json::json!({ "id": 0, "state": true }))
+            .reply(&routes())
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert!(body["error"].as_str().unwrap().contains("at least 1"));
+    }
+
+    #[tokio::test]
+    async fn zone_ids_in_the_path_reach_the_most_a_zone_id_holds() {
+        for id in [127, 128, i16::MAX] {
+            let path = format!("/{}", id);
+            let matched = warp::test::request().path(&path).filter(&zone_id_param()).await;
+            assert_eq!(matched.ok(), Some(id), "{}", path);
+        }
+        let too_big = format!("/{}", i16::MAX as i32 + 1);
+        assert!(warp::test::request().path(&too_big).filter(&zone_id_param()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn update_without_allow_manual_leaves_it_unchanged() {
+        let update = warp::test::request()
+            .method("PUT")
+            .path("/zone/update")
+            .json(&serde_json::json!({
+                "id": 1, "Name": "Lawn", "GPIO": 12, "Time": 10, "Enabled": true, "Autooff": true, "SystemOrder": 0,
+            }))
+            .filter(&zone_json())
+            .await
+            .unwrap();
+        assert_eq!(update.AllowManual, None);
+    }
+
+    #[tokio::test]
+    async fn disabling_until_a_past_day_is_bad_request() {
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/system/disable-until")
+            .json(&serde_json::json!({ "resume_on": "2020-01-01" }))
+            .reply(&routes())
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert!(body["error"].as_str().unwrap().contains("after today"));
+    }
+
+    #[tokio::test]
+    async fn toggle_without_state_is_bad_request() {
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/zone")
+            .json(&serde_json::json!({ "id": 1 }))
+            .reply(&routes())
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert!(body["error"].as_str().unwrap().contains("missing field `state`"));
+    }
+
+    #[tokio::test]
+    async fn toggle_with_form_body_is_unsupported_media_type() {
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/zone")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("id=1&state=true")
+            .reply(&routes())
+            .await;
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert!(body["error"].as_str().unwrap().contains("application/json"));
+    }
+
+    #[tokio::test]
+    async fn add_with_invalid_json_is_bad_request() {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/zone")
+            .header("content-type", "application/json")
+            .body("not json")
+            .reply(&routes())
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn delete_with_mistyped_id_is_bad_request() {
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/zone")
+            .json(&serde_json::json!({ "id": "one" }))
+            .reply(&routes())
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn order_without_list_is_bad_request() {
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/zone/order")
+            .json(&serde_json::json!({ "order": 1 }))
+            .reply(&routes())
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn history_with_malformed_date_is_bad_request() {
+        let res = warp::test::request()
+            .method("GET")
+            .path("/zone/1/history?from=yesterday")
+            .reply(&routes())
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert!(body["error"].as_str().unwrap().contains("yesterday"));
+    }
+
+    #[tokio::test]
+    async fn system_state_without_value_is_bad_request() {
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/system/state")
+            .json(&serde_json::json!({}))
+            .reply(&routes())
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn away_mode_without_value_is_bad_request() {
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/system/away")
+            .json(&serde_json::json!({ "away": true }))
+            .reply(&routes())
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
 }
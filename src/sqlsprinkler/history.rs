@@ -0,0 +1,187 @@
+use crate::config::get_settings;
+use crate::sqlsprinkler::zone::{Zone, ZoneId};
+use crate::sqlsprinkler::{get_pool, unix_now};
+use chrono::{TimeZone, Utc};
+use log::{error, info};
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::time;
+
+/// The most runs that will be returned from a single history query.
+pub const MAX_HISTORY_ROWS: u32 = 500;
+
+/// How often the daemon deletes runs older than `history_retention_days`.
+const PRUNE_INTERVAL: time::Duration = time::Duration::from_secs(60 * 60);
+
+/// A single run of a zone, as stored in the database.
+#[derive(Debug, PartialEq, Eq, sqlx::FromRow)]
+pub struct ZoneRun {
+    pub zone_id: ZoneId,
+    pub started_at: i64,
+    pub duration_secs: i64,
+}
+
+/// A single run of a zone, with the start time formatted as RFC3339 for api responses.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct ZoneRunEntry {
+    pub zone_id: ZoneId,
+    pub started_at: String,
+    pub duration_secs: i64,
+}
+
+impl From<ZoneRun> for ZoneRunEntry {
+    fn from(run: ZoneRun) -> Self {
+        ZoneRunEntry {
+            zone_id: run.zone_id,
+            started_at: Utc
+                .timestamp_opt(run.started_at, 0)
+                .single()
+                .map(|time| time.to_rfc3339())
+                .unwrap_or_default(),
+            duration_secs: run.duration_secs,
+        }
+    }
+}
+
+/// Records that a zone ran, and adds the run to the zone's lifetime totals. Failing to record is
+/// logged, but never stops a run.
+/// # Params
+/// * `zone` The zone that ran
+/// * `started_at` When the zone was turned on, in seconds since the unix epoch
+/// * `duration` How long the zone ran for
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::history;
+/// history::record(&zone, started_at, zone.run_time());
+/// ```
+pub async fn record(zone: &Zone, started_at: i64, duration: time::Duration) {
+    let pool = match get_pool() {
+        Ok(pool) => pool,
+        Err(e) => {
+            error!("Error recording run of zone {}: {}", zone.id, e);
+            return;
+        }
+    };
+    let duration_secs = duration.as_secs() as i64;
+    let query = sqlx::query!(
+        "INSERT INTO ZoneHistory (zone_id, started_at, duration_secs) VALUES (?, ?, ?)",
+        zone.id,
+        started_at,
+        duration_secs
+    )
+    .execute(&pool)
+    .await;
+    match query {
+        Ok(_) => info!("Recorded run of zone {} for {} seconds", zone.id, duration_secs),
+        Err(e) => error!("Error recording run of zone {}: {}", zone.id, e),
+    }
+    // Incremented in SQL so that runs finishing at the same time can't overwrite each other.
+    let query = sqlx::query!(
+        "UPDATE Zones SET total_runs = total_runs + 1, total_runtime_secs = total_runtime_secs + ? WHERE id = ?",
+        duration_secs,
+        zone.id
+    )
+    .execute(&pool)
+    .await;
+    if let Err(e) = query {
+        error!("Error updating run totals of zone {}: {}", zone.id, e);
+    }
+}
+
+/// Gets the runs of a zone between two times, newest first.
+/// # Params
+/// * `zone_id` The zone to get the runs of
+/// * `from` The earliest start time to include, in seconds since the unix epoch
+/// * `to` The latest start time to include, in seconds since the unix epoch
+/// * `limit` The most runs to return, capped at `MAX_HISTORY_ROWS`
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::history;
+/// let runs = history::for_zone(1, None, None, 10);
+/// ```
+pub async fn for_zone(
+    zone_id: ZoneId,
+    from: Option<i64>,
+    to: Option<i64>,
+    limit: u32,
+) -> Result<Vec<ZoneRun>, sqlx::Error> {
+    sqlx::query_as::<_, ZoneRun>(
+        "SELECT zone_id, started_at, duration_secs FROM ZoneHistory \
+         WHERE zone_id = ? AND started_at >= ? AND started_at <= ? \
+         ORDER BY started_at DESC LIMIT ?",
+    )
+    .bind(zone_id)
+    .bind(from.unwrap_or(i64::MIN))
+    .bind(to.unwrap_or(i64::MAX))
+    .bind(limit.min(MAX_HISTORY_ROWS))
+    .fetch_all(&get_pool()?)
+    .await
+}
+
+/// Deletes the runs that started before a time. The zones' lifetime totals are kept.
+/// # Params
+/// * `before` The time to delete runs before, in seconds since the unix epoch, or `None` to delete
+///   every run.
+/// # Return
+/// How many runs were deleted.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::history;
+/// let pruned = history::prune(Some(cutoff));
+/// ```
+pub async fn prune(before: Option<i64>) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM ZoneHistory WHERE started_at < ?",
+        before.unwrap_or(i64::MAX)
+    )
+    .execute(&get_pool()?)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// Gets the time before which runs are past their retention.
+/// # Params
+/// * `now` The current time, in seconds since the unix epoch.
+/// * `retention_days` How many days of runs to keep, 0 keeping them forever.
+/// # Return
+/// The cutoff, or `None` if runs are kept forever.
+pub fn retention_cutoff(now: i64, retention_days: u64) -> Option<i64> {
+    if retention_days == 0 {
+        return None;
+    }
+    let retention_days = i64::try_from(retention_days).unwrap_or(i64::MAX);
+    Some(now.saturating_sub(retention_days.saturating_mul(24 * 60 * 60)))
+}
+
+/// Deletes runs older than `history_retention_days` every hour, for as long as the daemon runs. Does
+/// nothing if runs are kept forever.
+pub async fn prune_periodically() {
+    if get_settings().history_retention_days == 0 {
+        return;
+    }
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let retention_days = get_settings().history_retention_days;
+        let cutoff = match retention_cutoff(unix_now(), retention_days) {
+            Some(cutoff) => cutoff,
+            None => continue,
+        };
+        match prune(Some(cutoff)).await {
+            Ok(pruned) => info!("Pruned {} runs older than {} days from the history", pruned, retention_days),
+            Err(e) => error!("Error pruning the history: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::retention_cutoff;
+
+    #[test]
+    fn retention_cutoff_is_days_before_now() {
+        assert_eq!(retention_cutoff(10 * 24 * 60 * 60, 7), Some(3 * 24 * 60 * 60));
+        assert_eq!(retention_cutoff(10 * 24 * 60 * 60, 0), None);
+        assert_eq!(retention_cutoff(0, u64::MAX), Some(-i64::MAX));
+    }
+}
@@ -0,0 +1,187 @@
+use crate::sqlsprinkler::system::{find_blocking_zone, get_away_mode, get_zones, make_room_for, stop_zone};
+use crate::sqlsprinkler::zone::{Zone, ZoneId};
+use crate::sqlsprinkler::{get_pool, unix_now};
+use log::{error, info, warn};
+use std::time;
+
+/// A timed manual run of a zone, persisted so the daemon can finish it after a restart.
+#[derive(Debug, PartialEq, Eq, sqlx::FromRow)]
+pub struct ManualRun {
+    pub zone_id: ZoneId,
+    pub started_at: i64,
+    pub duration_secs: i64,
+}
+
+impl ManualRun {
+    /// Gets how much of this run is left.
+    /// # Params
+    /// * `now` The current time, in seconds since the unix epoch.
+    /// # Return
+    /// The time left, or `None` if the run should already have ended.
+    pub fn remaining(&self, now: i64) -> Option<time::Duration> {
+        let left = self.started_at + self.duration_secs - now;
+        if left > 0 {
+            Some(time::Duration::from_secs(left as u64))
+        } else {
+            None
+        }
+    }
+}
+
+/// Records a timed manual run of a zone, replacing any earlier one of the same zone. Failing to
+/// record is logged, but never stops a run.
+/// # Params
+/// * `zone` The zone that was turned on.
+/// * `started_at` When the zone was turned on, in seconds since the unix epoch.
+/// * `duration` How long until the zone is turned off.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::manual_run;
+/// manual_run::record(&zone, started_at, zone.run_time());
+/// ```
+pub async fn record(zone: &Zone, started_at: i64, duration: time::Duration) {
+    let pool = match get_pool() {
+        Ok(pool) => pool,
+        Err(e) => {
+            error!("Error recording manual run of zone {}: {}", zone.id, e);
+            return;
+        }
+    };
+    let duration_secs = duration.as_secs() as i64;
+    let query = sqlx::query!(
+        "REPLACE INTO ManualRuns (zone_id, started_at, duration_secs) VALUES (?, ?, ?)",
+        zone.id,
+        started_at,
+        duration_secs
+    )
+    .execute(&pool)
+    .await;
+    if let Err(e) = query {
+        error!("Error recording manual run of zone {}: {}", zone.id, e);
+    }
+}
+
+/// Forgets the manual run of a zone, once it has finished or been stopped.
+/// # Params
+/// * `zone_id` The zone whose run ended.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::manual_run;
+/// manual_run::clear(1);
+/// ```
+pub async fn clear(zone_id: ZoneId) {
+    let pool = match get_pool() {
+        Ok(pool) => pool,
+        Err(e) => {
+            error!("Error clearing manual run of zone {}: {}", zone_id, e);
+            return;
+        }
+    };
+    let query = sqlx::query!("DELETE FROM ManualRuns WHERE zone_id = ?", zone_id)
+        .execute(&pool)
+        .await;
+    if let Err(e) = query {
+        error!("Error clearing manual run of zone {}: {}", zone_id, e);
+    }
+}
+
+/// Forgets every manual run, for when all the zones are turned off.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::manual_run;
+/// manual_run::clear_all();
+/// ```
+pub async fn clear_all() {
+    let pool = match get_pool() {
+        Ok(pool) => pool,
+        Err(e) => {
+            error!("Error clearing manual runs: {}", e);
+            return;
+        }
+    };
+    let query = sqlx::query!("DELETE FROM ManualRuns")
+        .execute(&pool)
+        .await;
+    if let Err(e) = query {
+        error!("Error clearing manual runs: {}", e);
+    }
+}
+
+/// Resumes the manual runs that were interrupted by a restart. A run with time left turns its zone
+/// back on and counts down the rest, one that should already have ended turns its zone off. A run is
+/// resumed the way a zone is turned on by hand, so it is dropped and its zone turned off while away
+/// mode is on, a higher priority zone is running, or too many zones are running. Must be called from
+/// within a tokio runtime.
+/// # Return
+/// The ids of the zones whose runs were resumed.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::manual_run;
+/// let resumed = manual_run::resume();
+/// ```
+pub async fn resume() -> Result<Vec<ZoneId>, sqlx::Error> {
+    let runs = sqlx::query_as::<_, ManualRun>(
+        "SELECT zone_id, started_at, duration_secs FROM ManualRuns",
+    )
+    .fetch_all(&get_pool()?)
+    .await?;
+    let zone_list = get_zones().await?;
+    let away = get_away_mode().await?;
+    let now = unix_now();
+    let mut resumed = Vec::new();
+    for run in runs {
+        let zone = match zone_list.zones.iter().find(|zone| zone.id == run.zone_id) {
+            Some(zone) => zone,
+            None => {
+                warn!("Zone {} no longer exists, dropping its manual run", run.zone_id);
+                clear(run.zone_id).await;
+                continue;
+            }
+        };
+        let remaining = match run.remaining(now) {
+            Some(remaining) => remaining,
+            None => {
+                info!("Manual run of zone {} ended during the restart, turning it off", zone.id);
+                stop_zone(zone).await;
+                continue;
+            }
+        };
+        if away {
+            warn!("Away mode is on, dropping the manual run of zone {}", zone.id);
+            stop_zone(zone).await;
+            continue;
+        }
+        if let Some(running) = find_blocking_zone(zone).await? {
+            warn!("Zone {} outranks zone {}, dropping its manual run", running.id, zone.id);
+            stop_zone(zone).await;
+            continue;
+        }
+        match make_room_for(zone, || zone.run_async_for(remaining)).await? {
+            Some(()) => {
+                info!("Resuming manual run of zone {} with {} seconds left", zone.id, remaining.as_secs());
+                resumed.push(zone.id);
+            }
+            None => {
+                warn!("Too many zones are running, dropping the manual run of zone {}", zone.id);
+                stop_zone(zone).await;
+            }
+        }
+    }
+    // A run resumed later may have replaced one resumed earlier.
+    resumed.retain(|id| zone_list.zones.iter().any(|zone| zone.id == *id && zone.is_running()));
+    Ok(resumed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ManualRun;
+    use std::time::Duration;
+
+    #[test]
+    fn remaining_counts_down_to_none() {
+        let run = ManualRun { zone_id: 1, started_at: 1000, duration_secs: 20 * 60 };
+        assert_eq!(run.remaining(1000 + 5 * 60), Some(Duration::from_secs(15 * 60)));
+        assert_eq!(run.remaining(1000 + 20 * 60), None);
+        assert_eq!(run.remaining(1000 + 30 * 60), None);
+    }
+}
@@ -1,72 +1,272 @@
 #![allow(non_snake_case)]
-use log::error;
-use std::process::exit;
+use log::{error, info, warn};
+use std::future::Future;
+use std::convert::TryFrom;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use lazy_static::lazy_static;
-use sqlx::{MySqlPool, Pool, MySql};
+use sqlx::mysql::MySqlPoolOptions;
+use sqlx::{Pool, MySql};
 use std::sync::RwLock;
 
-use crate::get_settings;
+use crate::config::DB_PASS_ENV_VAR;
+use crate::config::get_settings;
 
+pub mod changelog;
+pub mod chart;
 pub mod daemon;
+pub mod history;
+pub mod manual_run;
+pub mod notify;
+pub mod sensor;
+pub mod sequence;
+pub mod supervisor;
 pub mod system;
+pub mod watchdog;
 pub mod zone;
+pub mod zones_file;
 
 // create a static pool for the sql database
 lazy_static! {
     static ref POOL: RwLock<Option<Pool<MySql>>> = RwLock::new(None);
+    /// A pool connected to the read replica at `sqlsprinkler_read_host`, if one is configured.
+    static ref READ_POOL: RwLock<Option<Pool<MySql>>> = RwLock::new(None);
 }
 
 
-pub fn get_pool() -> Pool<MySql> {
-    let pool = POOL.read().unwrap();
-    match *pool {
-        Some(ref p) => p.clone(),
+/// Gets the pool connected to the database by `create_pool`.
+/// # Return
+/// * `Pool` A connection to the SQL database. A `PoolClosed` error if `create_pool` hasn't
+///   connected yet.
+pub fn get_pool() -> Result<Pool<MySql>, sqlx::Error> {
+    match *POOL.read().unwrap() {
+        Some(ref pool) => Ok(pool.clone()),
         None => {
-            error!("No pool found");
-            exit(1);
+            error!("No pool found, create_pool must connect to the database first");
+            Err(sqlx::Error::PoolClosed)
+        }
+    }
+}
+
+/// Runs a read only query against the read replica if one is configured, falling back to the
+/// primary database if there is no replica or the query against it fails.
+/// # Params
+/// * `query` Runs the query against the given pool.
+/// # Example
+/// ```ignore
+/// let zones = read(|pool| async move {
+///     sqlx::query_as::<_, Zone>("SELECT * FROM Zones").fetch_all(&pool).await
+/// }).await?;
+/// ```
+pub(crate) async fn read<T, F, Fut>(query: F) -> Result<T, sqlx::Error>
+where
+    F: Fn(Pool<MySql>) -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    let replica = READ_POOL.read().unwrap().clone();
+    if let Some(replica) = replica {
+        match query(replica).await {
+            Ok(result) => return Ok(result),
+            Err(e) => warn!("Query against the read replica failed, using the primary: {}", e),
         }
     }
+    query(get_pool()?).await
+}
+
+/// Gets the current time as seconds since the unix epoch.
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Describes a duration for the logs and the webhook: in seconds below a minute, such as `30 seconds`,
+/// and in minutes to at most two decimal places otherwise, such as `2.5 minutes`.
+/// # Params
+/// * `duration` The duration to describe.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs_f64();
+    if secs < 60.0 {
+        let secs = trim_decimals(secs);
+        let unit = if secs == "1" { "second" } else { "seconds" };
+        return format!("{} {}", secs, unit);
+    }
+    let minutes = trim_decimals(secs / 60.0);
+    let unit = if minutes == "1" { "minute" } else { "minutes" };
+    format!("{} {}", minutes, unit)
 }
 
-/// Gets a connection to a MySQL database
+/// Writes a number to at most two decimal places, without trailing zeros.
+fn trim_decimals(value: f64) -> String {
+    let written = format!("{:.2}", value);
+    written.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// Divides the given duration by the configured `time_scale`.
+/// # Params
+/// * `duration` The real time duration.
+pub(crate) fn scaled(duration: Duration) -> Duration {
+    scale_down(duration, get_settings().time_scale)
+}
+
+/// Divides a duration by `time_scale`, treating 0 as 1 and anything too big to divide by as the
+/// biggest divisor there is.
+pub(crate) fn scale_down(duration: Duration, time_scale: u64) -> Duration {
+    duration / u32::try_from(time_scale).unwrap_or(u32::MAX).max(1)
+}
+
+/// Sleeps the current thread for the given duration, divided by the configured `time_scale`. Sends
+/// the watchdog a heartbeat every second while sleeping.
+/// # Params
+/// * `duration` The real time duration to sleep for.
+pub(crate) fn sleep(duration: Duration) {
+    sleep_unscaled(scaled(duration));
+}
+
+/// Sleeps the current thread for the given duration, which isn't divided by `time_scale`. Sends the
+/// watchdog a heartbeat every second while sleeping.
+/// # Params
+/// * `duration` How long to sleep for.
+pub(crate) fn sleep_unscaled(duration: Duration) {
+    let mut remaining = duration;
+    while remaining > Duration::ZERO {
+        let chunk = remaining.min(Duration::from_secs(1));
+        thread::sleep(chunk);
+        remaining -= chunk;
+        watchdog::heartbeat();
+    }
+}
+
+/// Gets a connection to a MySQL database, running any pending migrations.
 /// # Return
-///     `Pool` A connection to the SQL database.
+/// * `Pool` A connection to the SQL database. A `Configuration` error if the database settings are
+///   missing from the config file.
 ///
-pub(crate) async fn create_pool() -> Result<(), sqlx::Error> {
+pub async fn create_pool() -> Result<(), sqlx::Error> {
+    connect(true).await
+}
+
+/// Gets a connection to a MySQL database like `create_pool`, but leaves the schema as it is, for
+/// checking the database without changing it.
+/// # Return
+/// * A `Configuration` error if the database settings are missing from the config file.
+pub async fn create_pool_without_migrating() -> Result<(), sqlx::Error> {
+    connect(false).await
+}
+
+/// Counts the migrations that haven't been run against the database yet.
+/// # Return
+/// * How many migrations `create_pool` would run. Every migration is pending in a database that has
+///   never been migrated.
+pub async fn pending_migrations() -> Result<usize, sqlx::Error> {
+    let applied: Vec<i64> = match sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+        .fetch_all(&get_pool()?)
+        .await
+    {
+        Ok(applied) => applied,
+        // The migrations table is made by the first migration run.
+        Err(sqlx::Error::Database(..)) => vec![],
+        Err(e) => return Err(e),
+    };
+    Ok(sqlx::migrate!()
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration() && !applied.contains(&migration.version))
+        .count())
+}
+
+/// Connects the pools, see `create_pool`.
+/// # Params
+/// * `migrate` Whether to run any pending migrations once connected.
+async fn connect(migrate: bool) -> Result<(), sqlx::Error> {
     // Build the url for the connection
     let reader = get_settings();
+    let missing = |setting: &str| {
+        sqlx::Error::Configuration(
+            format!("Missing configuration for {} in /etc/sqlsprinkler/sqlsprinkler.conf", setting).into(),
+        )
+    };
 
     if reader.sqlsprinkler_user.is_empty() {
-        error!(
-            "Missing configuration for sqlsprinkler_user in /etc/sqlsprinkler/sqlsprinkler.conf"
-        );
-        exit(1);
+        return Err(missing("sqlsprinkler_user"));
     }
-    if reader.sqlsprinkler_pass.is_empty() {
-        error!(
-            "Missing configuration for sqlsprinkler_pass in /etc/sqlsprinkler/sqlsprinkler.conf"
-        );
-        exit(1);
+    let pass = reader.db_pass().map_err(|e| {
+        sqlx::Error::Configuration(
+            format!("Unable to read sqlsprinkler_pass_file {}: {}", reader.sqlsprinkler_pass_file, e).into(),
+        )
+    })?;
+    if pass.is_empty() {
+        return Err(sqlx::Error::Configuration(
+            format!(
+                "Missing configuration for sqlsprinkler_pass in /etc/sqlsprinkler/sqlsprinkler.conf, \
+                 set it there, in {}, or in a file named by sqlsprinkler_pass_file",
+                DB_PASS_ENV_VAR
+            )
+            .into(),
+        ));
     }
     if reader.sqlsprinkler_host.is_empty() {
-        error!(
-            "Missing configuration for sqlsprinkler_host in /etc/sqlsprinkler/sqlsprinkler.conf"
-        );
-        exit(1);
+        return Err(missing("sqlsprinkler_host"));
     }
     if reader.sqlsprinkler_db.is_empty() {
-        error!("Missing configuration for sqlsprinkler_db in /etc/sqlsprinkler/sqlsprinkler.conf");
-        exit(1);
+        return Err(missing("sqlsprinkler_db"));
     }
 
-    let url = format!(
-        "mysql://{}:{}@{}:3306/{}",
-        reader.sqlsprinkler_user,
-        reader.sqlsprinkler_pass,
-        reader.sqlsprinkler_host,
-        reader.sqlsprinkler_db
+    let url_for = |host: &str| {
+        format!(
+            "mysql://{}:{}@{}:3306/{}",
+            reader.sqlsprinkler_user,
+            pass.expose(),
+            host,
+            reader.sqlsprinkler_db
+        )
+    };
+    info!(
+        "Connecting to the database with at most {} connections and a {} second acquire timeout",
+        reader.db_max_connections, reader.db_acquire_timeout_secs
     );
-    let pool = MySqlPool::connect(&url).await?;
+    let pool_options = || {
+        MySqlPoolOptions::new()
+            .max_connections(reader.db_max_connections)
+            .acquire_timeout(Duration::from_secs(reader.db_acquire_timeout_secs))
+    };
+    let pool = pool_options()
+        .connect(&url_for(&reader.sqlsprinkler_host))
+        .await?;
+    if migrate {
+        sqlx::migrate!().run(&pool).await?;
+    }
     *POOL.write().unwrap() = Some(pool);
+
+    if !reader.sqlsprinkler_read_host.is_empty() {
+        info!("Connecting to the read replica at {}", reader.sqlsprinkler_read_host);
+        match pool_options()
+            .connect(&url_for(&reader.sqlsprinkler_read_host))
+            .await
+        {
+            Ok(read_pool) => *READ_POOL.write().unwrap() = Some(read_pool),
+            Err(e) => warn!(
+                "Unable to connect to the read replica, reading from the primary: {}",
+                e
+            ),
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::format_duration;
+    use std::time::Duration;
+
+    #[test]
+    fn durations_are_described_in_seconds_then_minutes() {
+        assert_eq!(format_duration(Duration::from_secs(30)), "30 seconds");
+        assert_eq!(format_duration(Duration::from_secs(1)), "1 second");
+        assert_eq!(format_duration(Duration::from_millis(1500)), "1.5 seconds");
+        assert_eq!(format_duration(Duration::from_secs(60)), "1 minute");
+        assert_eq!(format_duration(Duration::from_secs(150)), "2.5 minutes");
+        assert_eq!(format_duration(Duration::from_secs(100)), "1.67 minutes");
+        assert_eq!(format_duration(Duration::from_secs(600)), "10 minutes");
+    }
+}
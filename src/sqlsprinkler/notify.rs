@@ -0,0 +1,126 @@
+use crate::config::get_settings;
+use crate::sqlsprinkler::unix_now;
+use crate::sqlsprinkler::zone::Zone;
+use chrono::{TimeZone, Utc};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// The kinds of event that can be sent to the webhook.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    /// A zone started running.
+    Started,
+    /// A zone finished running.
+    Finished,
+    /// A scheduled run was skipped.
+    Skipped,
+    /// A background task of the daemon kept failing and was given up on.
+    TaskFailed,
+}
+
+/// The JSON body posted to the webhook.
+#[derive(Serialize, Debug)]
+struct Event<'a> {
+    #[serde(rename = "type")]
+    event_type: EventType,
+    zone: Option<&'a str>,
+    timestamp: String,
+    detail: &'a str,
+}
+
+/// Posts an event to `notify_webhook_url`, if it is set and `event_type` is in `notify_events`.
+/// Failing to notify, including timing out after `http_timeout_secs`, is logged, but never stops a
+/// run.
+/// # Params
+/// * `event_type` What happened.
+/// * `zone` The zone it happened to, if any.
+/// * `detail` A human readable description of the event.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::notify::{self, EventType};
+/// notify::send(EventType::Skipped, None, "A zone is being run manually").await;
+/// ```
+pub async fn send(event_type: EventType, zone: Option<&Zone>, detail: &str) {
+    let settings = get_settings();
+    if settings.notify_webhook_url.is_empty() || !settings.notify_events.contains(&event_type) {
+        return;
+    }
+    let body = match serde_json::to_value(Event {
+        event_type,
+        zone: zone.map(|zone| zone.Name.as_str()),
+        timestamp: Utc
+            .timestamp_opt(unix_now(), 0)
+            .single()
+            .map(|time| time.to_rfc3339())
+            .unwrap_or_default(),
+        detail,
+    }) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Unable to build the webhook event: {}", e);
+            return;
+        }
+    };
+    let url = settings.notify_webhook_url;
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(settings.http_connect_timeout_secs))
+        .timeout(Duration::from_secs(settings.http_timeout_secs))
+        .build();
+    let sent = tokio::task::spawn_blocking(move || {
+        agent
+            .post(&url)
+            .send_json(body)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    })
+    .await;
+    match sent {
+        Ok(Ok(..)) => debug!("Sent {:?} to the webhook", event_type),
+        Ok(Err(e)) => warn!("Unable to send {:?} to the webhook: {}", event_type, e),
+        Err(e) => warn!("Unable to send {:?} to the webhook: {}", event_type, e),
+    }
+}
+
+/// Sends an event like `send`, but in the background, so a slow webhook doesn't hold up watering.
+/// Must be called from within a tokio runtime.
+/// # Params
+/// * `event_type` What happened.
+/// * `zone` The zone it happened to, if any.
+/// * `detail` A human readable description of the event.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::notify::{self, EventType};
+/// notify::send_in_background(EventType::Started, Some(&zone), "Running for 10 minutes".to_string());
+/// ```
+pub fn send_in_background(event_type: EventType, zone: Option<&Zone>, detail: String) {
+    let zone = zone.cloned();
+    tokio::spawn(async move {
+        send(event_type, zone.as_ref(), &detail).await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, EventType};
+
+    #[test]
+    fn events_are_sent_as_json() {
+        let event = Event {
+            event_type: EventType::Started,
+            zone: Some("Garden"),
+            timestamp: "2026-10-16T06:00:00+00:00".to_string(),
+            detail: "Running for 10 minutes",
+        };
+        assert_eq!(
+            serde_json::to_value(event).unwrap(),
+            serde_json::json!({
+                "type": "started",
+                "zone": "Garden",
+                "timestamp": "2026-10-16T06:00:00+00:00",
+                "detail": "Running for 10 minutes",
+            })
+        );
+    }
+}
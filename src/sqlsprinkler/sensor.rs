@@ -0,0 +1,65 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// Where the kernel's 1-wire driver exposes its devices.
+const W1_DEVICES: &str = "/sys/bus/w1/devices";
+
+/// The reading a DS18B20 gives after a power-on reset, before it has measured anything.
+const POWER_ON_RESET_MILLI_C: i64 = 85_000;
+
+/// Reads the temperature from a 1-wire DS18B20 sensor.
+/// # Params
+/// * `device_id` The id of the sensor, such as `28-0316a2792fff`.
+/// # Return
+/// The temperature in Fahrenheit, or an error if the sensor could not be read or failed its
+/// CRC check.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::sensor;
+/// let temp_f = sensor::read_temp_f("28-0316a2792fff");
+/// ```
+pub fn read_temp_f(device_id: &str) -> Result<f64, Box<dyn Error>> {
+    let path = Path::new(W1_DEVICES).join(device_id).join("w1_slave");
+    let text = fs::read_to_string(&path)?;
+    let temp_c = parse_w1_slave(&text)
+        .ok_or_else(|| format!("Unable to read a temperature from {}", path.display()))?;
+    Ok(temp_c * 9.0 / 5.0 + 32.0)
+}
+
+/// Parses the `w1_slave` file of a DS18B20.
+/// # Params
+/// * `text` The contents of the file, a line ending in the CRC check result followed by a line
+///   ending in `t=` and the temperature in thousandths of a degree Celsius.
+/// # Return
+/// The temperature in Celsius, or `None` if the CRC check failed or the reading is the
+/// power-on reset value.
+fn parse_w1_slave(text: &str) -> Option<f64> {
+    let mut lines = text.lines();
+    if !lines.next()?.trim_end().ends_with("YES") {
+        return None;
+    }
+    let (_, milli_c) = lines.next()?.split_once("t=")?;
+    match milli_c.trim().parse::<i64>().ok()? {
+        POWER_ON_RESET_MILLI_C => None,
+        milli_c => Some(milli_c as f64 / 1000.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_w1_slave;
+
+    #[test]
+    fn w1_slave_readings_are_checked() {
+        let good = "72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+        assert_eq!(parse_w1_slave(good), Some(23.125));
+        let below_zero = "5e ff 4b 46 7f ff 02 10 d9 : crc=d9 YES\n5e ff 4b 46 7f ff 02 10 d9 t=-10125\n";
+        assert_eq!(parse_w1_slave(below_zero), Some(-10.125));
+        let bad_crc = "72 01 4b 46 7f ff 0e 10 57 : crc=00 NO\n72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+        assert_eq!(parse_w1_slave(bad_crc), None);
+        let reset = "50 05 4b 46 7f ff 0c 10 1c : crc=1c YES\n50 05 4b 46 7f ff 0c 10 1c t=85000\n";
+        assert_eq!(parse_w1_slave(reset), None);
+        assert_eq!(parse_w1_slave(""), None);
+    }
+}
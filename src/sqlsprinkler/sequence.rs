@@ -0,0 +1,190 @@
+use crate::config::get_settings;
+use crate::sqlsprinkler::zone::{self, Zone, ZoneId, ZoneTime};
+use crate::sqlsprinkler::{get_pool, read};
+use log::{info, warn};
+use serde::Serialize;
+use sqlx::mysql::MySqlRow;
+use sqlx::Row;
+use std::fmt;
+use std::time;
+
+/// A named group of zones that can be run on its own, outside of the system order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Sequence {
+    pub name: String,
+    /// The zones to run, in the order they run in.
+    pub entries: Vec<SequenceEntry>,
+}
+
+/// A zone in a sequence, and how long it runs for in that sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SequenceEntry {
+    pub zone_id: ZoneId,
+    /// How long the zone runs for in this sequence, in the configured `time_unit`, and fractional
+    /// like the zone's own time. `None` uses the zone's own time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<ZoneTime>,
+}
+
+impl SequenceEntry {
+    /// Gets how long the zone of this entry runs for in the sequence, cut down in commissioning mode.
+    /// # Params
+    /// * `zone` The zone of this entry, whose own time is used if the entry doesn't set one.
+    pub fn run_time(&self, zone: &Zone) -> time::Duration {
+        match self.time {
+            Some(time) => zone::commissioning_cap(time.duration(get_settings().time_unit)),
+            None => zone.run_time(),
+        }
+    }
+}
+
+impl fmt::Display for SequenceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.time {
+            Some(time) => write!(f, "{}:{}", self.zone_id, time),
+            None => write!(f, "{}", self.zone_id),
+        }
+    }
+}
+
+impl<'r> sqlx::FromRow<'r, MySqlRow> for Sequence {
+    fn from_row(row: &'r MySqlRow) -> Result<Self, sqlx::Error> {
+        let name: String = row.try_get("name")?;
+        let zone_ids: String = row.try_get("zone_ids")?;
+        let entries = parse_entries(&zone_ids).unwrap_or_else(|e| {
+            warn!("Sequence {} has invalid zone ids, ignoring them: {}", name, e);
+            vec![]
+        });
+        Ok(Sequence { name, entries })
+    }
+}
+
+/// Parses a comma separated list of zone ids, each optionally followed by `:` and the time it runs
+/// for in the sequence, such as `1:10,3,2:2.5`.
+/// # Params
+/// * `input` The list of entries.
+/// # Return
+/// The entries in the order they were given, or an error if any id or time is invalid.
+pub fn parse_entries(input: &str) -> Result<Vec<SequenceEntry>, String> {
+    input
+        .split(',')
+        .map(|entry| {
+            let (id, time) = match entry.split_once(':') {
+                Some((id, time)) => (id, Some(time)),
+                None => (entry, None),
+            };
+            let zone_id = id
+                .trim()
+                .parse::<ZoneId>()
+                .map_err(|e| format!("'{}' is not a zone id: {}", id.trim(), e))?;
+            let time = time
+                .map(|time| {
+                    time.trim()
+                        .parse::<ZoneTime>()
+                        .map_err(|e| format!("'{}' is not a time: {}", time.trim(), e))
+                })
+                .transpose()?;
+            Ok(SequenceEntry { zone_id, time })
+        })
+        .collect()
+}
+
+/// Joins entries into the comma separated list they are stored as.
+fn join_entries(entries: &[SequenceEntry]) -> String {
+    entries
+        .iter()
+        .map(|entry| entry.to_string())
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Saves a sequence, replacing any sequence with the same name.
+/// # Params
+/// * `sequence` The sequence to save.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::sequence::{self, Sequence};
+/// let entries = sequence::parse_entries("1:5,3")?;
+/// sequence::save(&Sequence { name: "Quick Morning".to_string(), entries }).await?;
+/// ```
+pub async fn save(sequence: &Sequence) -> Result<(), sqlx::Error> {
+    let zone_ids = join_entries(&sequence.entries);
+    sqlx::query!(
+        "REPLACE INTO Sequences (name, zone_ids) VALUES (?, ?)",
+        sequence.name,
+        zone_ids
+    )
+    .execute(&get_pool()?)
+    .await?;
+    info!("Saved sequence {} with zones {}", sequence.name, zone_ids);
+    Ok(())
+}
+
+/// Gets every sequence, sorted by name.
+pub async fn list() -> Result<Vec<Sequence>, sqlx::Error> {
+    read(|pool| async move {
+        sqlx::query_as::<_, Sequence>("SELECT name, zone_ids FROM Sequences ORDER BY name")
+            .fetch_all(&pool)
+            .await
+    })
+    .await
+}
+
+/// Gets the sequence with the given name.
+/// # Params
+/// * `name` The name of the sequence.
+/// # Return
+/// The sequence, or `None` if there is no sequence with that name.
+pub async fn get(name: &str) -> Result<Option<Sequence>, sqlx::Error> {
+    read(|pool| async move {
+        sqlx::query_as::<_, Sequence>("SELECT name, zone_ids FROM Sequences WHERE name = ?")
+            .bind(name)
+            .fetch_optional(&pool)
+            .await
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{join_entries, parse_entries, SequenceEntry};
+    use crate::sqlsprinkler::zone::{Zone, ZoneTime};
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    #[test]
+    fn zone_ids_keep_their_order() {
+        let ids = |entries: Vec<SequenceEntry>| entries.iter().map(|entry| entry.zone_id).collect::<Vec<_>>();
+        assert_eq!(parse_entries("3, 1,2").map(ids), Ok(vec![3, 1, 2]));
+        assert_eq!(join_entries(&parse_entries("3,1,2").unwrap()), "3,1,2");
+        assert!(parse_entries("1,,2").is_err());
+        assert!(parse_entries("one").is_err());
+    }
+
+    #[test]
+    fn entries_may_override_the_zone_time() {
+        let entries = parse_entries("1:10, 3,2 : 2.5").unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                SequenceEntry { zone_id: 1, time: Some(10.into()) },
+                SequenceEntry { zone_id: 3, time: None },
+                SequenceEntry { zone_id: 2, time: Some(ZoneTime::try_from(2.5).unwrap()) },
+            ]
+        );
+        assert_eq!(join_entries(&entries), "1:10,3,2:2.5");
+        assert!(parse_entries("1:").is_err());
+        assert!(parse_entries("1:-5").is_err());
+    }
+
+    #[test]
+    fn an_entry_time_is_used_over_the_zone_time() {
+        let zone = Zone { Time: 10.into(), ..Zone::default() };
+        let entry = SequenceEntry { zone_id: 1, time: Some(5.into()) };
+        assert_eq!(entry.run_time(&zone), Duration::from_secs(5 * 60));
+        let entry = SequenceEntry { zone_id: 1, time: Some(ZoneTime::try_from(2.5).unwrap()) };
+        assert_eq!(entry.run_time(&zone), Duration::from_secs(150));
+        let entry = SequenceEntry { zone_id: 1, time: None };
+        assert_eq!(entry.run_time(&zone), Duration::from_secs(10 * 60));
+    }
+}
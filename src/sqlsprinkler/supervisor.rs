@@ -0,0 +1,124 @@
+use crate::config::get_settings;
+use crate::sqlsprinkler::notify::{self, EventType};
+use log::{error, info};
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// How long to wait before the first restart of a failed task. Each restart after that waits twice
+/// as long as the one before, up to `MAX_BACKOFF`.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest wait between restarts of a failed task.
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// How long a task has to run before it is considered healthy again, and its restarts are no longer
+/// counted against `task_max_restarts`.
+const HEALTHY_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// Runs a background task of the daemon, restarting it with a growing backoff if it panics. Once it
+/// has been restarted `task_max_restarts` times in a row without staying up, it is given up on and a
+/// `task_failed` event is sent. A task that returns has finished, and isn't restarted.
+/// # Params
+/// * `name` What the task does, for the log.
+/// * `task` Starts the task.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::{history, supervisor};
+/// tokio::spawn(supervisor::supervise("history pruning", history::prune_periodically));
+/// ```
+pub async fn supervise<F, Fut>(name: &'static str, task: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let max_restarts = get_settings().task_max_restarts;
+    let restarts = match supervise_with(name, task, max_restarts, FIRST_BACKOFF).await {
+        Some(restarts) => restarts,
+        None => return,
+    };
+    let detail = format!("The {} task failed {} times and was not restarted", name, restarts + 1);
+    error!("{}", detail);
+    notify::send(EventType::TaskFailed, None, &detail).await;
+}
+
+/// Runs a task, restarting it if it panics, until it panics more than `max_restarts` times in a row.
+/// # Params
+/// * `name` What the task does, for the log.
+/// * `task` Starts the task.
+/// * `max_restarts` How many times in a row the task may be restarted.
+/// * `first_backoff` How long to wait before the first restart.
+/// # Return
+/// How many times the task was restarted in a row before it was given up on, or `None` if it
+/// finished.
+async fn supervise_with<F, Fut>(
+    name: &'static str,
+    task: F,
+    max_restarts: u32,
+    first_backoff: Duration,
+) -> Option<u32>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut restarts = 0;
+    loop {
+        let started = Instant::now();
+        match tokio::spawn(task()).await {
+            Ok(..) => {
+                info!("The {} task finished", name);
+                return None;
+            }
+            Err(e) => error!("The {} task failed: {}", name, e),
+        }
+        if started.elapsed() >= HEALTHY_AFTER {
+            restarts = 0;
+        }
+        if restarts >= max_restarts {
+            return Some(restarts);
+        }
+        let wait = backoff(first_backoff, restarts);
+        restarts += 1;
+        info!("Restarting the {} task in {:?}, restart {} of {}", name, wait, restarts, max_restarts);
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Gets how long to wait before restarting a task, doubling with each restart up to `MAX_BACKOFF`.
+/// # Params
+/// * `first_backoff` How long to wait before the first restart.
+/// * `restarts` How many times the task has already been restarted in a row.
+fn backoff(first_backoff: Duration, restarts: u32) -> Duration {
+    first_backoff.saturating_mul(2u32.saturating_pow(restarts)).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backoff, supervise_with, MAX_BACKOFF};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let first = Duration::from_secs(1);
+        assert_eq!(backoff(first, 0), Duration::from_secs(1));
+        assert_eq!(backoff(first, 3), Duration::from_secs(8));
+        assert_eq!(backoff(first, 40), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn panicking_tasks_are_restarted_until_the_cap() {
+        let starts = Arc::new(AtomicU32::new(0));
+        let task = || {
+            let starts = starts.clone();
+            async move {
+                starts.fetch_add(1, Ordering::SeqCst);
+                panic!("task failed");
+            }
+        };
+        let restarts = supervise_with("test", task, 2, Duration::from_millis(1)).await;
+        assert_eq!(restarts, Some(2));
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+        assert_eq!(supervise_with("test", || async {}, 2, Duration::from_millis(1)).await, None);
+    }
+}
@@ -1,19 +1,43 @@
-use crate::sqlsprinkler::{get_pool, zone};
-use log::info;
-use std::{thread, time};
+use crate::config::{self, get_settings};
+use chrono::{Datelike, Local, NaiveDate, TimeZone, Utc};
+use crate::sqlsprinkler::notify::{self, EventType};
+use crate::sqlsprinkler::{create_pool_without_migrating, format_duration, get_pool, pending_migrations, history, manual_run, read, sensor, sleep, unix_now, watchdog, zone};
+use log::{error, info, warn};
+use std::{fmt, time};
+use std::env;
 use std::error::Error;
-use crate::sqlsprinkler::zone::Zone;
+use std::fs;
+use std::future::Future;
+use std::path::Path;
+use std::process::Command;
+use crate::sqlsprinkler::sequence::Sequence;
+use crate::sqlsprinkler::zone::{Zone, ZoneId};
+use lazy_static::lazy_static;
+use tokio::signal::unix::{signal, SignalKind};
+
+lazy_static! {
+    /// Held while a zone is let in under `max_concurrent_zones`, see `make_room_for`.
+    static ref ROOM: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
 
 #[derive(Debug, PartialEq, Eq, sqlx::FromRow)]
 pub struct SysStatus {
     status: bool,
 }
 
-/// Enables or disables the system schedule
+/// Represents a zone that was turned on by hand, and when the schedule may take over again.
+#[derive(Debug, PartialEq, Eq, sqlx::FromRow)]
+pub struct ManualOverride {
+    pub zone_id: ZoneId,
+    pub expires_at: i64,
+}
+
+/// Enables or disables the system schedule. If the `Enabled` table has no row, one is inserted, and
+/// the flag is read back to check that it really changed.
 /// # Arguments
-///     * `enabled` If true is passed in, the system is enabled. If false is used, the system is disabled.
+/// * `enabled` If true is passed in, the system is enabled. If false is used, the system is disabled.
 /// # Example
-/// ```
+/// ```ignore
 /// use sqlsprinkler::system::set_system_status;
 /// set_system_status(true);
 /// ```
@@ -21,97 +45,1318 @@ pub async fn set_system_status(enabled: bool) -> Result<(), Box<dyn Error>> {
     // let pool = &get_pool();
     // let query = "UPDATE Enabled set enabled = ?";
     // pool.prep_exec(query, (enabled, )).unwrap();
-    sqlx::query!("UPDATE Enabled set enabled = ?", enabled)
-        .execute(&get_pool())
-        .await?;
+    upsert_system_status(
+        enabled,
+        || async move {
+            let result = sqlx::query!("UPDATE Enabled set enabled = ?", enabled)
+                .execute(&get_pool()?)
+                .await?;
+            Ok(result.rows_affected())
+        },
+        || async move {
+            sqlx::query!("INSERT INTO Enabled (enabled) VALUES (?)", enabled)
+                .execute(&get_pool()?)
+                .await?;
+            Ok(())
+        },
+        // Read from the primary, a replica may not have the write yet.
+        || async move {
+            sqlx::query_scalar::<_, bool>("SELECT enabled FROM Enabled LIMIT 1")
+                .fetch_optional(&get_pool()?)
+                .await
+        },
+    )
+    .await?;
     info!("System status set to {}", enabled);
     Ok(())
 }
 
+/// Writes the system status with `update`, falling back to `insert` if `update` changed no rows,
+/// then checks the status that `read_back` returns.
+/// # Arguments
+/// * `enabled` The status being set.
+/// * `update` Updates every row of the `Enabled` table, returning how many rows matched.
+/// * `insert` Inserts a row into the `Enabled` table.
+/// * `read_back` Reads the status from the `Enabled` table, if it has a row.
+async fn upsert_system_status<U, UF, I, IF, R, RF>(
+    enabled: bool,
+    update: U,
+    insert: I,
+    read_back: R,
+) -> Result<(), Box<dyn Error>>
+where
+    U: FnOnce() -> UF,
+    UF: Future<Output = Result<u64, sqlx::Error>>,
+    I: FnOnce() -> IF,
+    IF: Future<Output = Result<(), sqlx::Error>>,
+    R: FnOnce() -> RF,
+    RF: Future<Output = Result<Option<bool>, sqlx::Error>>,
+{
+    if update().await? == 0 {
+        warn!("The Enabled table has no row, inserting one");
+        insert().await?;
+    }
+    match read_back().await? {
+        Some(status) if status == enabled => Ok(()),
+        Some(status) => Err(format!("The system status reads back as {} after setting it to {}", status, enabled).into()),
+        None => Err("The Enabled table still has no row after setting the system status".into()),
+    }
+}
+
 /// Gets whether the system schedule is enabled or disabled
 /// # Return
 /// A bool representing whether the system is enabled or disabled.
 /// # Example
-/// ```
+/// ```ignore
 /// use sqlsprinkler::system::get_system_status;
 /// let status = get_system_status();
 /// ```
-pub(crate) async fn get_system_status() -> Result<bool, sqlx::Error> {
-    let rows = sqlx::query_as::<_, SysStatus>("SELECT enabled as status from Enabled")
-        .fetch_all(&get_pool()).await?;
-    Ok(rows[0].status)
+pub async fn get_system_status() -> Result<bool, sqlx::Error> {
+    let rows = read(|pool| async move {
+        sqlx::query_as::<_, SysStatus>("SELECT enabled as status from Enabled")
+            .fetch_all(&pool).await
+    }).await?;
+    rows.first().map(|row| row.status).ok_or(sqlx::Error::RowNotFound)
 }
 
 /// Gets a list of all the zones in this database
 /// # Returns
-///     A `ZoneList` containing all the zones ordered by their system order.
+/// A `ZoneList` containing all the zones ordered by their system order.
 /// # Example
-/// ```
+/// ```ignore
 /// use sqlsprinkler::system;
 /// let zones = system::get_zones();
 /// ```
-pub(crate) async fn get_zones() -> Result<zone::ZoneList, sqlx::Error> {
-    let mut rows = sqlx::query_as::<_, Zone>("SELECT * FROM Zones ORDER BY SystemOrder")
-        .fetch_all(&get_pool()).await?;
-    let mut res = vec![];
-    for row in rows.iter_mut() {
-        res.push(row.clone());
+pub async fn get_zones() -> Result<zone::ZoneList, sqlx::Error> {
+    let max_zones = get_settings().max_zones;
+    // Fetch one more than the cap, so going over it can be told apart from being right at it.
+    let limit = max_zones.saturating_add(1) as u64;
+    let rows = read(|pool| async move {
+        sqlx::query_as::<_, Zone>("SELECT * FROM Zones ORDER BY SystemOrder LIMIT ?")
+            .bind(limit)
+            .fetch_all(&pool).await
+    }).await?;
+    Ok(zone::ZoneList { zones: cap_zones(rows, max_zones) })
+}
+
+/// Drops the zones past `max_zones`, logging an error if there are any.
+/// # Params
+/// * `zones` The zones read from the database, in system order.
+/// * `max_zones` The most zones to keep.
+fn cap_zones<T>(mut zones: Vec<T>, max_zones: usize) -> Vec<T> {
+    if zones.len() > max_zones {
+        error!("There are more than max_zones ({}) zones, only the first {} are used", max_zones, max_zones);
+        zones.truncate(max_zones);
     }
-    Ok(zone::ZoneList { zones: res })
+    zones
 }
 
-/// Runs the system based on the schedule configured. Skips over any zones that are not enabled in the database.
+/// Turns away mode on or off. While it is on, every way of watering is refused, but the system
+/// schedule toggle is left as it is.
+/// # Params
+/// * `enabled` Whether away mode should be on.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::system::set_away_mode;
+/// set_away_mode(true);
+/// ```
+pub async fn set_away_mode(enabled: bool) -> Result<(), sqlx::Error> {
+    sqlx::query!("REPLACE INTO AwayMode (id, enabled) VALUES (1, ?)", enabled)
+        .execute(&get_pool()?)
+        .await?;
+    info!("Away mode set to {}", enabled);
+    Ok(())
+}
+
+/// Gets whether away mode is on.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::system::get_away_mode;
+/// let away = get_away_mode();
+/// ```
+pub async fn get_away_mode() -> Result<bool, sqlx::Error> {
+    let away = read(|pool| async move {
+        sqlx::query_scalar::<_, bool>("SELECT enabled FROM AwayMode WHERE id = 1")
+            .fetch_optional(&pool)
+            .await
+    })
+    .await?;
+    Ok(away.unwrap_or(false))
+}
+
+/// Disables the system schedule until a day, after which scheduled runs resume on their own. Unlike
+/// away mode, zones can still be run by hand, and the system schedule toggle is left as it is.
+/// # Params
+/// * `resume_on` The day scheduled runs resume on, in the configured timezone, or `None` to resume
+///   them now.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::system::set_disabled_until;
+/// set_disabled_until(NaiveDate::from_ymd_opt(2026, 10, 20));
+/// ```
+pub async fn set_disabled_until(resume_on: Option<NaiveDate>) -> Result<(), sqlx::Error> {
+    match resume_on {
+        Some(resume_on) => {
+            sqlx::query!(
+                "REPLACE INTO DisableUntil (id, resume_at) VALUES (1, ?)",
+                start_of_day(resume_on)
+            )
+            .execute(&get_pool()?)
+            .await?;
+            info!("System schedule disabled until {}", resume_on);
+        }
+        None => {
+            sqlx::query!("DELETE FROM DisableUntil").execute(&get_pool()?).await?;
+            info!("System schedule no longer disabled until a date");
+        }
+    }
+    Ok(())
+}
+
+/// Checks that scheduled runs would resume on a day after today, in the configured timezone.
+/// # Params
+/// * `resume_on` The day scheduled runs would resume on.
+/// * `now` The current time, in seconds since the unix epoch.
+/// # Return
+/// An error saying why the day can't be used, if it is today or earlier.
+pub fn check_resume_on(resume_on: NaiveDate, now: i64) -> Result<(), String> {
+    match local_date(now) {
+        Some(today) if resume_on <= today => Err(format!("{} is not after today, {}", resume_on, today)),
+        _ => Ok(()),
+    }
+}
+
+/// Gets the day scheduled runs resume on, if the system schedule is disabled until then.
+/// # Return
+/// The day, or `None` if the schedule isn't disabled until a day or that day has come.
+pub async fn get_disabled_until() -> Result<Option<NaiveDate>, sqlx::Error> {
+    let resume_at = read(|pool| async move {
+        sqlx::query_scalar::<_, i64>("SELECT resume_at FROM DisableUntil WHERE id = 1")
+            .fetch_optional(&pool)
+            .await
+    })
+    .await?;
+    Ok(resume_at.filter(|resume_at| *resume_at > unix_now()).and_then(local_date))
+}
+
+/// Fails if away mode is on, for refusing to water.
+async fn refuse_when_away() -> Result<(), Box<dyn Error>> {
+    if get_away_mode().await? {
+        warn!("Away mode is on, refusing to water");
+        return Err("Away mode is on".into());
+    }
+    Ok(())
+}
+
+/// Checks the freeze guard's sensor against `freeze_guard_temp_f`. A sensor that can't be read never
+/// stops watering, but is logged.
+/// # Return
+/// The temperature in Fahrenheit if it is at or below the threshold, otherwise `None`.
+fn freeze_guard_temp() -> Option<f64> {
+    let settings = get_settings();
+    read_temp_f(&settings.freeze_guard_sensor_id, "freeze guard sensor")
+        .filter(|temp_f| *temp_f <= settings.freeze_guard_temp_f as f64)
+}
+
+/// Checks the soil probe against `min_soil_temp_f`. A probe that can't be read never stops
+/// watering, but is logged.
+/// # Return
+/// The soil temperature in Fahrenheit if it is below the minimum, otherwise `None`.
+fn soil_too_cold_temp() -> Option<f64> {
+    let settings = get_settings();
+    read_temp_f(&settings.soil_temp_sensor_id, "soil temperature probe")
+        .filter(|temp_f| *temp_f < settings.min_soil_temp_f as f64)
+}
+
+/// Reads a DS18B20 temperature sensor, logging a sensor that can't be read.
+/// # Params
+/// * `device_id` The id of the sensor, empty if it isn't configured.
+/// * `name` What the sensor is, for the log.
+/// # Return
+/// The temperature in Fahrenheit, or `None` if the sensor isn't configured or can't be read.
+fn read_temp_f(device_id: &str, name: &str) -> Option<f64> {
+    if device_id.is_empty() {
+        return None;
+    }
+    match sensor::read_temp_f(device_id) {
+        Ok(temp_f) => Some(temp_f),
+        Err(e) => {
+            warn!("Unable to read the {}, watering anyway: {}", name, e);
+            None
+        }
+    }
+}
+
+/// Marks the given zone as manually running, so that the schedule will not interrupt it. Each zone
+/// holds its own override, replacing any earlier one of the same zone.
+/// # Arguments
+/// * `zone` The zone that was turned on by hand.
+/// * `run_time` How long the zone will run for, or `None` if it stays on until turned off.
+///   Zones that stay on hold the override for `manual_override_timeout_mins`.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::system::set_manual_override;
+/// set_manual_override(&zone, Some(zone.run_time()));
+/// ```
+pub async fn set_manual_override(zone: &Zone, run_time: Option<time::Duration>) -> Result<(), sqlx::Error> {
+    let run_time = run_time.unwrap_or_else(|| {
+        time::Duration::from_secs(get_settings().manual_override_timeout_mins * 60)
+    });
+    let expires_at = unix_now() + run_time.as_secs() as i64;
+    sqlx::query!(
+        "REPLACE INTO ManualOverride (zone_id, expires_at) VALUES (?, ?)",
+        zone.id,
+        expires_at
+    )
+    .execute(&get_pool()?)
+    .await?;
+    info!("Manual override set for zone {} for {} seconds", zone.id, run_time.as_secs());
+    Ok(())
+}
+
+/// Clears any manual override, letting the schedule run again.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::system::clear_manual_override;
+/// clear_manual_override();
+/// ```
+pub async fn clear_manual_override() -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM ManualOverride")
+        .execute(&get_pool()?)
+        .await?;
+    info!("Manual override cleared");
+    Ok(())
+}
+
+/// Clears the manual override if it belongs to the given zone, such as when that zone is turned
+/// off. An override set by another zone that is still on is left alone.
+/// # Params
+/// * `zone_id` The id of the zone that was turned off.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::system::clear_manual_override_for;
+/// clear_manual_override_for(zone.id);
+/// ```
+pub async fn clear_manual_override_for(zone_id: ZoneId) -> Result<(), sqlx::Error> {
+    let cleared = sqlx::query!("DELETE FROM ManualOverride WHERE zone_id = ?", zone_id)
+        .execute(&get_pool()?)
+        .await?;
+    if cleared.rows_affected() > 0 {
+        info!("Manual override for zone {} cleared", zone_id);
+    }
+    Ok(())
+}
+
+/// Gets an active manual override, if any zone holds one that has not yet expired. The one that
+/// expires last is returned.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::system::get_manual_override;
+/// let manual_override = get_manual_override();
+/// ```
+pub async fn get_manual_override() -> Result<Option<ManualOverride>, sqlx::Error> {
+    Ok(get_manual_overrides().await?.into_iter().next())
+}
+
+/// Gets every active manual override, one for each zone that holds one that has not yet expired.
+/// # Return
+/// The overrides, the one that expires last first.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::system::get_manual_overrides;
+/// let manual_overrides = get_manual_overrides();
+/// ```
+pub async fn get_manual_overrides() -> Result<Vec<ManualOverride>, sqlx::Error> {
+    sqlx::query_as::<_, ManualOverride>(
+        "SELECT zone_id, expires_at FROM ManualOverride WHERE expires_at > ? ORDER BY expires_at DESC",
+    )
+    .bind(unix_now())
+    .fetch_all(&get_pool()?)
+    .await
+}
+
+/// Finds a running zone that the given zone is not allowed to preempt, as decided by
+/// `Zone::outranks`.
+/// # Params
+/// * `zone` The zone that has been asked to turn on.
+/// # Return
+/// The running zone that blocks `zone`, or `None` if `zone` may be turned on.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::system::find_blocking_zone;
+/// let blocker = find_blocking_zone(&zone);
+/// ```
+pub async fn find_blocking_zone(zone: &Zone) -> Result<Option<Zone>, sqlx::Error> {
+    let zone_list = get_zones().await?;
+    Ok(zone_list
+        .zones
+        .into_iter()
+        .find(|running| running.id != zone.id && running.is_on() && !zone.outranks(running)))
+}
+
+/// Makes room for the given zone to turn on without going over `max_concurrent_zones`, then turns it
+/// on with `start`. With the default of 1, every zone is turned off first, so zones replace each
+/// other as they always have, and the manual run of a zone that is replaced ends as if it had been
+/// stopped, see `stop_zone`. With a higher cap, running zones are left on, and the zone may only
+/// turn on while fewer than the cap are running. Only one zone is let in at a time, so two requests
+/// can't both count the same free slot.
+/// # Params
+/// * `zone` The zone that has been asked to turn on.
+/// * `start` Turns the zone on, such as `Zone::turn_on` or `Zone::run_async`.
+/// # Return
+/// What `start` returned, or `None` if as many zones as allowed are running and it wasn't called.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::system::make_room_for;
+/// if make_room_for(&zone, || zone.turn_on()).await?.is_none() {
+///     println!("Too many zones are running");
+/// }
+/// ```
+pub async fn make_room_for<T, F>(zone: &Zone, start: F) -> Result<Option<T>, sqlx::Error>
+where
+    F: FnOnce() -> T,
+{
+    let _room = ROOM.lock().await;
+    let max = get_settings().max_concurrent_zones;
+    let zone_list = get_zones().await?;
+    if max <= 1 {
+        info!("Turning off all zones");
+        for other in &zone_list.zones {
+            if other.id != zone.id && other.is_running() {
+                // Its manual run is over, so it mustn't be resumed after a restart.
+                stop_zone(other).await;
+            } else {
+                other.turn_off();
+            }
+        }
+        return Ok(Some(start()));
+    }
+    let running = count_running_others(zone.id, &zone_list.zones, Zone::is_running);
+    if !has_room(running, max) {
+        warn!(
+            "{} zones are running, the most max_concurrent_zones allows, refusing to turn on zone {}",
+            running, zone.id
+        );
+        return Ok(None);
+    }
+    Ok(Some(start()))
+}
+
+/// Why turning a zone on by hand was refused, see `check_manual_start` and `start_manual`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Refusal {
+    /// The zone doesn't allow manual runs.
+    ManualNotAllowed,
+    /// The given zone is running, and outranks the zone.
+    Outranked(Zone),
+    /// Away mode is on.
+    AwayMode,
+    /// As many zones as `max_concurrent_zones` allows are running.
+    TooManyRunning,
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Refusal::ManualNotAllowed => write!(f, "Zone does not allow manual runs"),
+            Refusal::Outranked(running) => write!(f, "Zone {} ({}) is running with a higher priority", running.id, running.Name),
+            Refusal::AwayMode => write!(f, "Away mode is on"),
+            Refusal::TooManyRunning => write!(f, "Too many zones are running"),
+        }
+    }
+}
+
+/// Turns a zone on or off by hand, as the API and `Sprinkler::toggle_zone` do. A zone turned on
+/// runs in the background, and one with auto off is turned off again after its time. Turning a zone
+/// on is checked with `check_manual_start` and then started with `start_manual`.
+/// # Params
+/// * `zone` The zone to turn on or off.
+/// * `on` Whether to turn the zone on.
+/// * `requested` How long to run the zone for when turning it on, see `Zone::run_async`.
+/// # Return
+/// Why turning the zone on was refused, or `None` if the zone was turned on or off.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::system::toggle_zone;
+/// if let Some(refusal) = toggle_zone(&zone, true, None).await? {
+///     println!("{}", refusal);
+/// }
+/// ```
+pub async fn toggle_zone(zone: &Zone, on: bool, requested: Option<time::Duration>) -> Result<Option<Refusal>, sqlx::Error> {
+    if !on {
+        stop_zone(zone).await;
+        return Ok(None);
+    }
+    if let Some(refusal) = check_manual_start(zone).await? {
+        return Ok(Some(refusal));
+    }
+    let run_time = zone.manual_run_time(requested.map(Some));
+    // The zone is run rather than just turned on, so that auto off turns it off if unattended.
+    match start_manual(zone, run_time, || zone.run_async(requested)).await? {
+        Ok(Some(off_in)) => manual_run::record(zone, unix_now(), off_in).await,
+        Ok(None) => (),
+        Err(refusal) => return Ok(Some(refusal)),
+    }
+    Ok(None)
+}
+
+/// Checks whether a zone may be turned on by hand. It may not if it doesn't allow manual runs, a
+/// higher priority zone is running, or away mode is on. Whether too many zones are running is only
+/// known once the zone is started, see `start_manual`. Every way of turning a zone on by hand goes
+/// through this, so they are all refused alike.
+/// # Params
+/// * `zone` The zone that has been asked to turn on.
+/// # Return
+/// Why the zone may not turn on, or `None` if it may.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::system::check_manual_start;
+/// if let Some(refusal) = check_manual_start(&zone).await? {
+///     println!("{}", refusal);
+/// }
+/// ```
+pub async fn check_manual_start(zone: &Zone) -> Result<Option<Refusal>, sqlx::Error> {
+    if !zone.AllowManual {
+        info!("Refusing to turn on zone {}, it does not allow manual runs", zone.id);
+        return Ok(Some(Refusal::ManualNotAllowed));
+    }
+    if let Some(running) = find_blocking_zone(zone).await? {
+        info!("Refusing to turn on zone {}, zone {} outranks it", zone.id, running.id);
+        return Ok(Some(Refusal::Outranked(running)));
+    }
+    if get_away_mode().await? {
+        info!("Refusing to turn on zone {}, away mode is on", zone.id);
+        return Ok(Some(Refusal::AwayMode));
+    }
+    Ok(None)
+}
+
+/// Turns a zone on by hand once `check_manual_start` has allowed it. Room is made for it with
+/// `make_room_for`, it is turned on with `start`, and the manual override is set so the schedule
+/// won't interrupt it.
+/// # Params
+/// * `zone` The zone to turn on.
+/// * `run_time` How long the zone will stay on, or `None` if it stays on until turned off, see
+///   `Zone::manual_run_time`.
+/// * `start` Turns the zone on, such as `Zone::turn_on` or `Zone::run_async`.
+/// # Return
+/// What `start` returned, or `Refusal::TooManyRunning` if it wasn't called.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::system::start_manual;
+/// if let Err(refusal) = start_manual(&zone, zone.manual_run_time(None), || zone.turn_on()).await? {
+///     println!("{}", refusal);
+/// }
+/// ```
+pub async fn start_manual<T, F>(zone: &Zone, run_time: Option<time::Duration>, start: F) -> Result<Result<T, Refusal>, sqlx::Error>
+where
+    F: FnOnce() -> T,
+{
+    let started = match make_room_for(zone, start).await? {
+        Some(started) => started,
+        None => return Ok(Err(Refusal::TooManyRunning)),
+    };
+    if let Err(e) = set_manual_override(zone, run_time).await {
+        error!("Error setting manual override: {}", e);
+    }
+    Ok(Ok(started))
+}
+
+/// Stops a zone and ends its manual run, so it is neither resumed after a restart nor holds off the
+/// schedule. A timed run that is cut short is recorded in the zone's history for the time it ran.
+/// Failing to forget the run is logged, the zone is turned off either way.
+/// # Params
+/// * `zone` The zone to stop.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::system::stop_zone;
+/// stop_zone(&zone).await;
+/// ```
+pub async fn stop_zone(zone: &Zone) {
+    if let Some(started_at) = zone.stop() {
+        let ran_for = time::Duration::from_secs((unix_now() - started_at).max(0) as u64);
+        history::record(zone, started_at, ran_for).await;
+    }
+    manual_run::clear(zone.id).await;
+    if let Err(e) = clear_manual_override_for(zone.id).await {
+        error!("Error clearing manual override: {}", e);
+    }
+}
+
+/// Waits out a run of a zone that was just turned on by hand, as the CLI does, then turns it off.
+/// The run is recorded so that a daemon started later can finish it, and the `started` and `finished`
+/// events are sent. Ctrl-C, SIGTERM such as from `kill`, and SIGHUP such as from the SSH session
+/// closing turn the zone off early.
+/// # Params
+/// * `zone` The zone that was turned on.
+/// * `run_time` How long the zone should water for.
+/// # Return
+/// How long the zone was on for.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::system::finish_manual_run;
+/// zone.turn_on();
+/// finish_manual_run(&zone, zone.run_time()).await;
+/// ```
+pub async fn finish_manual_run(zone: &Zone, run_time: time::Duration) -> time::Duration {
+    let started_at = unix_now();
+    let off_in = zone.effective_runtime(run_time);
+    info!("Running {} for {}, press Ctrl-C to stop early", zone.Name, format_duration(off_in));
+    notify::send_in_background(EventType::Started, Some(zone), format!("Running for {}", format_duration(off_in)));
+    manual_run::record(zone, started_at, off_in).await;
+    tokio::select! {
+        _ = tokio::time::sleep(off_in) => (),
+        signal = stop_requested() => warn!("Got {}, turning off {}", signal, zone.Name),
+    }
+    zone.turn_off();
+    manual_run::clear(zone.id).await;
+    if let Err(e) = clear_manual_override_for(zone.id).await {
+        warn!("Unable to clear the manual override: {}", e);
+    }
+    let ran_for = time::Duration::from_secs((unix_now() - started_at).max(0) as u64);
+    history::record(zone, started_at, ran_for).await;
+    let detail = format!("Ran for {}", format_duration(ran_for));
+    notify::send(EventType::Finished, Some(zone), &detail).await;
+    ran_for
+}
+
+/// Waits for this process to be asked to stop, by Ctrl-C, SIGTERM or SIGHUP. Only Ctrl-C is waited
+/// for if the others can't be listened for.
+/// # Return
+/// The name of the signal that was received.
+async fn stop_requested() -> &'static str {
+    let (mut terminate, mut hangup) = match (signal(SignalKind::terminate()), signal(SignalKind::hangup())) {
+        (Ok(terminate), Ok(hangup)) => (terminate, hangup),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Unable to listen for SIGTERM and SIGHUP, only Ctrl-C stops the run early: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+        _ = hangup.recv() => "SIGHUP",
+    }
+}
+
+/// Gets whether another zone may turn on while `running` zones are on.
+/// # Params
+/// * `running` How many other zones are running.
+/// * `max_concurrent_zones` The most zones that may be on at once.
+fn has_room(running: usize, max_concurrent_zones: usize) -> bool {
+    running < max_concurrent_zones
+}
+
+/// Counts the running zones, other than the zone with the given id.
+/// # Params
+/// * `zone_id` The zone to leave out of the count.
+/// * `zones` The zones of the system.
+/// * `is_running` Gets whether a zone is running.
+fn count_running_others<F>(zone_id: ZoneId, zones: &[Zone], is_running: F) -> usize
+where
+    F: Fn(&Zone) -> bool,
+{
+    zones
+        .iter()
+        .filter(|zone| zone.id != zone_id && is_running(zone))
+        .count()
+}
+
+/// The highest BCM GPIO number on the Raspberry Pi header.
+const MAX_GPIO: i8 = 27;
+
+/// The outcome of one check made by `diagnose`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DiagnosticCheck {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl DiagnosticCheck {
+    fn new(name: impl Into<String>, passed: bool, detail: impl Into<String>) -> Self {
+        DiagnosticCheck { name: name.into(), passed, detail: detail.into() }
+    }
+}
+
+/// Checks that the database is reachable, and that every zone's GPIO pin is usable, without
+/// turning any zone on.
+/// # Return
+/// Every check that was made, in order. Pin checks are skipped if the database is unreachable.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::system;
+/// let failed = system::diagnose().await.iter().any(|check| !check.passed);
+/// ```
+pub async fn diagnose() -> Vec<DiagnosticCheck> {
+    let mut checks = vec![];
+    let zone_list = match get_zones().await {
+        Ok(zone_list) => {
+            checks.push(DiagnosticCheck::new("Database", true, format!("{} zones", zone_list.zones.len())));
+            zone_list
+        }
+        Err(e) => {
+            checks.push(DiagnosticCheck::new("Database", false, e.to_string()));
+            return checks;
+        }
+    };
+    checks.push(check_enabled_row().await);
+    checks.extend(check_pins(&zone_list.zones, &get_settings().reserved_gpios));
+    for zone in zone_list.zones.iter().filter(|zone| (0..=MAX_GPIO).contains(&zone.GPIO)) {
+        let name = format!("Zone {} GPIO {}", zone.id, zone.GPIO);
+        checks.push(match zone.probe_gpio() {
+            Ok(..) => DiagnosticCheck::new(name, true, "acquired"),
+            Err(e) => DiagnosticCheck::new(name, false, e.to_string()),
+        });
+    }
+    checks
+}
+
+/// Checks that everything the daemon needs is ready, without connecting to the GPIO or turning
+/// anything on: the config file parses, the database settings are present, the database can be
+/// reached, and the system state row exists. Pending migrations are counted but not run, so
+/// checking never changes the database.
+/// # Return
+/// Every check that was made, in order. Each check is only made if the ones before it passed.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::system;
+/// let ready = system::preflight().await.iter().all(|check| check.passed);
+/// ```
+pub async fn preflight() -> Vec<DiagnosticCheck> {
+    let mut checks = vec![];
+    if let Err(e) = config::read_settings() {
+        checks.push(DiagnosticCheck::new("Config file", false, e.to_string()));
+        return checks;
+    }
+    checks.push(DiagnosticCheck::new("Config file", true, ""));
+    // create_pool_without_migrating checks the settings before it connects, so a configuration error means they're missing.
+    match create_pool_without_migrating().await {
+        Ok(..) => {
+            checks.push(DiagnosticCheck::new("Database settings", true, ""));
+            checks.push(DiagnosticCheck::new("Database", true, "connected"));
+        }
+        Err(sqlx::Error::Configuration(e)) => {
+            checks.push(DiagnosticCheck::new("Database settings", false, e.to_string()));
+            return checks;
+        }
+        Err(e) => {
+            checks.push(DiagnosticCheck::new("Database settings", true, ""));
+            checks.push(DiagnosticCheck::new("Database", false, e.to_string()));
+            return checks;
+        }
+    }
+    match pending_migrations().await {
+        Ok(0) => checks.push(DiagnosticCheck::new("Migrations", true, "up to date")),
+        Ok(pending) => {
+            // The tables the next checks read may not exist until the daemon runs the migrations.
+            let detail = format!("{} pending, run when the daemon starts", pending);
+            checks.push(DiagnosticCheck::new("Migrations", true, detail));
+            return checks;
+        }
+        Err(e) => {
+            checks.push(DiagnosticCheck::new("Migrations", false, e.to_string()));
+            return checks;
+        }
+    }
+    checks.push(check_enabled_row().await);
+    checks
+}
+
+/// Checks that the `Enabled` table has the row holding whether the system schedule is enabled.
+async fn check_enabled_row() -> DiagnosticCheck {
+    let pool = match get_pool() {
+        Ok(pool) => pool,
+        Err(e) => return DiagnosticCheck::new("System state", false, e.to_string()),
+    };
+    let enabled_rows = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM Enabled")
+        .fetch_one(&pool)
+        .await;
+    match enabled_rows {
+        Ok(0) => DiagnosticCheck::new("System state", false, "the Enabled table has no row"),
+        Ok(_) => DiagnosticCheck::new("System state", true, ""),
+        Err(e) => DiagnosticCheck::new("System state", false, e.to_string()),
+    }
+}
+
+/// Checks that each zone's GPIO number is on the Pi's header, isn't used by another zone, and isn't
+/// one of the `reserved` pins.
+fn check_pins(zones: &[Zone], reserved: &[u8]) -> Vec<DiagnosticCheck> {
+    let mut checks = vec![];
+    for zone in zones {
+        if zone::is_reserved_gpio(zone.GPIO, reserved) {
+            checks.push(DiagnosticCheck::new(
+                format!("Zone {} GPIO {}", zone.id, zone.GPIO),
+                false,
+                "reserved by reserved_gpios",
+            ));
+        }
+        if !(0..=MAX_GPIO).contains(&zone.GPIO) {
+            checks.push(DiagnosticCheck::new(
+                format!("Zone {} GPIO {}", zone.id, zone.GPIO),
+                false,
+                format!("out of range, must be 0 to {}", MAX_GPIO),
+            ));
+        }
+        let shared: Vec<String> = zones
+            .iter()
+            .filter(|other| other.id != zone.id && other.GPIO == zone.GPIO)
+            .map(|other| other.id.to_string())
+            .collect();
+        if !shared.is_empty() {
+            checks.push(DiagnosticCheck::new(
+                format!("Zone {} GPIO {}", zone.id, zone.GPIO),
+                false,
+                format!("also used by zone {}", shared.join(", ")),
+            ));
+        }
+    }
+    checks
+}
+
+/// A zone run that `run` would make, and when it would start.
+#[derive(Debug)]
+pub struct PlannedRun<'a> {
+    pub zone: &'a Zone,
+    /// How long after the start of the system run this zone turns on.
+    pub offset: time::Duration,
+    pub run_time: time::Duration,
+    /// How long the zone is held on in real time, see `Zone::effective_runtime`.
+    pub on_time: time::Duration,
+}
+
+/// Gets the current month, from 1 to 12, in the configured timezone.
+pub fn current_month() -> u32 {
+    match get_settings().timezone() {
+        Some(tz) => Utc::now().with_timezone(&tz).month(),
+        None => Local::now().month(),
+    }
+}
+
+/// Gets when a day starts in the configured timezone.
+/// # Params
+/// * `date` The day.
+/// # Return
+/// The start of the day, in seconds since the unix epoch.
+pub fn start_of_day(date: NaiveDate) -> i64 {
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap_or_default();
+    let start = match get_settings().timezone() {
+        Some(tz) => tz.from_local_datetime(&midnight).earliest().map(|time| time.timestamp()),
+        None => Local.from_local_datetime(&midnight).earliest().map(|time| time.timestamp()),
+    };
+    // Only a day that skips midnight for daylight saving has no midnight, so fall back to UTC.
+    start.unwrap_or_else(|| Utc.from_utc_datetime(&midnight).timestamp())
+}
+
+/// Gets the date of a time in the configured timezone.
+/// # Params
+/// * `timestamp` The time, in seconds since the unix epoch.
+pub fn local_date(timestamp: i64) -> Option<NaiveDate> {
+    let time = Utc.timestamp_opt(timestamp, 0).single()?;
+    Some(match get_settings().timezone() {
+        Some(tz) => time.with_timezone(&tz).date_naive(),
+        None => time.with_timezone(&Local).date_naive(),
+    })
+}
+
+/// Gets the name of the system's local timezone, best effort, from `TZ` or where `/etc/localtime`
+/// links to.
+/// # Return
+/// The timezone name, such as `America/Denver`, or `None` if it couldn't be found.
+pub fn local_timezone_name() -> Option<String> {
+    if let Ok(tz) = env::var("TZ") {
+        if !tz.is_empty() {
+            return Some(tz.trim_start_matches(':').to_string());
+        }
+    }
+    zone_name_from_path(&fs::read_link("/etc/localtime").ok()?)
+}
+
+/// Gets the timezone name from the path of a zoneinfo file, such as
+/// `/usr/share/zoneinfo/America/Denver`.
+fn zone_name_from_path(path: &Path) -> Option<String> {
+    let (_, name) = path.to_str()?.split_once("zoneinfo/")?;
+    Some(name.to_string())
+}
+
+/// Gets whether the system clock is synchronized with NTP, best effort, by asking `timedatectl`.
+/// # Return
+/// Whether the clock is synchronized, or `None` if it couldn't be found out.
+pub fn ntp_synchronized() -> Option<bool> {
+    let output = Command::new("timedatectl")
+        .args(["show", "--property=NTPSynchronized", "--value"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    match String::from_utf8_lossy(&output.stdout).trim() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// Builds the runs `run` makes for the given zones, in order, without touching any hardware.
+/// # Params
+/// * `zone_list` The zones of the system, in system order.
+/// * `month` The month the run happens in, from 1 to 12.
+/// # Return
+/// The enabled zones that are active in `month`, with their run time and start offset. Each zone
+/// starts once the ones before it have been held on for their effective runtime.
 /// # Example
+/// ```ignore
+/// use sqlsprinkler::system;
+/// let zone_list = system::get_zones().await?;
+/// let plan = system::plan(&zone_list, system::current_month());
 /// ```
+pub fn plan(zone_list: &zone::ZoneList, month: u32) -> Vec<PlannedRun<'_>> {
+    let mut offset = time::Duration::from_secs(0);
+    let mut planned = Vec::new();
+    for zone in zone_list.zones.iter().filter(|zone| plan_skip_reason(zone, month).is_none()) {
+        let run_time = zone.run_time();
+        let on_time = zone.effective_runtime(run_time);
+        planned.push(PlannedRun { zone, offset, run_time, on_time });
+        offset += on_time;
+    }
+    planned
+}
+
+/// Gets why `plan` leaves a zone out of a run.
+/// # Params
+/// * `zone` The zone.
+/// * `month` The month the run happens in, from 1 to 12.
+/// # Return
+/// The reason, or `None` if the zone is part of the run.
+pub fn plan_skip_reason(zone: &Zone, month: u32) -> Option<String> {
+    if !zone.Enabled {
+        Some("it isn't enabled".to_string())
+    } else if !zone.is_active_in(month) {
+        Some(format!("it doesn't water in month {}", month))
+    } else {
+        None
+    }
+}
+
+/// Gets why a scheduled run would be skipped right now, if it would be: a zone being run manually,
+/// away mode, the schedule being disabled until a later day, the freeze guard or cold soil.
+/// # Return
+/// The reason, or `None` if a scheduled run would go ahead.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::system;
+/// if let Some(reason) = system::skip_reason().await? {
+///     println!("{}", reason);
+/// }
+/// ```
+pub async fn skip_reason() -> Result<Option<String>, Box<dyn Error>> {
+    if let Some(manual_override) = get_manual_override().await? {
+        return Ok(Some(format!(
+            "Zone {} is being run manually, skipping the scheduled run",
+            manual_override.zone_id
+        )));
+    }
+    if get_away_mode().await? {
+        return Ok(Some("Away mode is on, skipping the scheduled run".to_string()));
+    }
+    if let Some(resume_on) = get_disabled_until().await? {
+        return Ok(Some(format!(
+            "The system schedule is disabled until {}, skipping the scheduled run",
+            resume_on
+        )));
+    }
+    if let Some(temp_f) = freeze_guard_temp() {
+        return Ok(Some(format!("It is {:.1}°F, skipping the scheduled run to avoid freezing", temp_f)));
+    }
+    if let Some(temp_f) = soil_too_cold_temp() {
+        return Ok(Some(format!("The soil is {:.1}°F, skipping the scheduled run until it warms up", temp_f)));
+    }
+    Ok(None)
+}
+
+/// Runs the system based on the schedule configured. Skips over any zones that are not enabled in the database.
+/// The run is skipped entirely while a zone is being run manually, away mode is on or the schedule
+/// is disabled until a later day.
+/// # Return
+/// * `true` if the system ran, `false` if it was skipped.
+/// # Example
+/// ```ignore
 /// use sqlsprinkler::system;
 /// system::run();
 /// ```
-pub async fn run() -> Result<(), Box<dyn Error>> {
+pub async fn run() -> Result<bool, Box<dyn Error>> {
+    let skipped = skip_reason().await?;
+    if let Some(detail) = skipped {
+        warn!("{}", detail);
+        notify::send(EventType::Skipped, None, &detail).await;
+        return Ok(false);
+    }
     let zone_list = get_zones().await?;
     info!("Running system as scheduled");
+    if get_settings().commissioning_mode {
+        warn!("Commissioning mode is on, every zone runs for {} seconds", get_settings().commissioning_run_secs);
+    }
+    let _watchdog = watchdog::watch(&zone_list.zones);
+    let month = current_month();
     for zone in &zone_list.zones {
-        // Skip over zones that aren't enabled in the database.
-        if zone.Enabled {
-            zone.run();
+        if let Some(reason) = plan_skip_reason(zone, month) {
+            info!("Skipping zone {}, {}", zone.Name, reason);
         }
     }
+    for planned in plan(&zone_list, month) {
+        run_zone(planned.zone, planned.run_time).await;
+    }
     info!("System run complete");
-    Ok(())
+    Ok(true)
+}
+
+/// Runs a single zone of a system run, recording it in the zone's history and sending the start and
+/// finish events to the webhook. The zone is skipped if `max_concurrent_zones` are already running.
+/// # Return
+/// * `true` if the zone ran.
+async fn run_zone(zone: &Zone, run_time: time::Duration) -> bool {
+    match make_room_for(zone, || zone.turn_on()).await {
+        Ok(Some(())) => (),
+        Ok(None) => {
+            warn!("Skipping zone {}, too many zones are running", zone.Name);
+            return false;
+        }
+        Err(e) => {
+            warn!("Skipping zone {}, unable to check the running zones: {}", zone.Name, e);
+            return false;
+        }
+    }
+    let on_for = zone.effective_runtime(run_time);
+    notify::send_in_background(EventType::Started, Some(zone), format!("Running for {}", format_duration(on_for)));
+    let started_at = unix_now();
+    zone.finish_run(run_time);
+    history::record(zone, started_at, on_for).await;
+    notify::send(EventType::Finished, Some(zone), &format!("Ran for {}", format_duration(on_for))).await;
+    true
 }
 
-/// Turns off all the zones in the system
+/// Runs every enabled zone right now, one after another, regardless of whether the system schedule
+/// is enabled. Fails while away mode is on.
+/// # Return
+/// The number of zones that were run.
 /// # Example
+/// ```ignore
+/// use sqlsprinkler::system;
+/// system::run_all();
 /// ```
+pub async fn run_all() -> Result<usize, Box<dyn Error>> {
+    refuse_when_away().await?;
+    let zone_list = get_zones().await?;
+    warn!("Running all enabled zones now, ignoring the system schedule enabled setting");
+    let _watchdog = watchdog::watch(&zone_list.zones);
+    let mut ran = 0;
+    for zone in &zone_list.zones {
+        if !zone.Enabled {
+            info!("Skipping disabled zone {}", zone.Name);
+        } else if run_zone(zone, zone.run_time()).await {
+            ran += 1;
+        }
+    }
+    info!("Ran {} of {} zones", ran, zone_list.zones.len());
+    Ok(ran)
+}
+
+/// Runs the zones of a sequence now, one after another in the sequence's order, regardless of
+/// whether the system schedule is enabled. Each zone runs for the time its entry gives, or its own
+/// time if the entry doesn't give one. Disabled zones and ids without a zone are skipped. Fails
+/// while away mode is on.
+/// # Params
+/// * `sequence` The sequence to run.
+/// # Return
+/// The number of zones that were run.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::{sequence, system};
+/// if let Some(sequence) = sequence::get("Quick Morning").await? {
+///     system::run_sequence(&sequence).await?;
+/// }
+/// ```
+pub async fn run_sequence(sequence: &Sequence) -> Result<usize, Box<dyn Error>> {
+    refuse_when_away().await?;
+    let zone_list = get_zones().await?;
+    info!("Running sequence {}", sequence.name);
+    let _watchdog = watchdog::watch(&zone_list.zones);
+    let mut ran = 0;
+    for entry in &sequence.entries {
+        match zone_list.zones.iter().find(|zone| zone.id == entry.zone_id) {
+            Some(zone) if zone.Enabled => {
+                if run_zone(zone, entry.run_time(zone)).await {
+                    ran += 1;
+                }
+            }
+            Some(zone) => info!("Skipping disabled zone {}", zone.Name),
+            None => warn!("Sequence {} has no zone with id {}, skipping it", sequence.name, entry.zone_id),
+        }
+    }
+    info!("Ran {} of {} zones in sequence {}", ran, sequence.entries.len(), sequence.name);
+    Ok(ran)
+}
+
+/// Turns off all the zones in the system, and forgets their manual runs.
+/// # Example
+/// ```ignore
 /// use sqlsprinkler::system;
 /// system::turn_off_all_zones();
 /// ```
-pub(crate) async fn turn_off_all_zones() -> Result<bool, rppal::gpio::Error> {
+pub async fn turn_off_all_zones() -> Result<bool, sqlx::Error> {
+    turn_off_zones_except(&[]).await?;
+    manual_run::clear_all().await;
+    Ok(true)
+}
+
+/// Turns off all the zones in the system except the given ones.
+/// # Params
+/// * `keep` The ids of the zones to leave alone.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::system;
+/// system::turn_off_zones_except(&resumed);
+/// ```
+pub async fn turn_off_zones_except(keep: &[ZoneId]) -> Result<bool, sqlx::Error> {
     info!("Turning off all zones");
-    let zone_list = get_zones().await.unwrap();
-    for zone_in_list in &zone_list.zones {
+    let zone_list = match get_zones().await {
+        Ok(zone_list) => zone_list,
+        Err(e) => {
+            error!("Unable to get the zones to turn off: {}", e);
+            return Err(e);
+        }
+    };
+    for zone_in_list in zone_list.zones.iter().filter(|zone| !keep.contains(&zone.id)) {
         zone_in_list.turn_off();
     }
     Ok(true)
 }
 
-/// Winterizes the system by turning on a zone for a minute, followed by a three minute delay.
+/// Reconciles the zones that are on with the persisted manual runs, for when the daemon restarts
+/// without turning every zone off. A zone whose manual run was resumed, or that is held on by the
+/// manual override, is left on. Any other zone that is on is turned off.
+/// # Params
+/// * `resumed` The ids of the zones whose manual runs were resumed.
 /// # Example
+/// ```ignore
+/// use sqlsprinkler::system;
+/// system::reconcile_running_zones(&resumed);
 /// ```
+pub async fn reconcile_running_zones(resumed: &[ZoneId]) -> Result<(), sqlx::Error> {
+    let manual_overrides = get_manual_overrides().await?;
+    let zone_list = get_zones().await?;
+    for zone in zone_list.zones.into_iter().filter(|zone| zone.is_on()) {
+        let held = manual_overrides.iter().any(|manual_override| manual_override.zone_id == zone.id);
+        if resumed.contains(&zone.id) || held {
+            info!("Zone {} is still running from before the restart, leaving it on", zone.id);
+        } else {
+            warn!("Zone {} is on without a manual run, turning it off", zone.id);
+            zone.turn_off();
+        }
+    }
+    Ok(())
+}
+
+/// Winterizes the system by turning on a zone for a minute, followed by a three minute delay. Fails
+/// while away mode is on.
+/// # Example
+/// ```ignore
 /// use sqlsprinkler::system;
 /// system::winterize();
 /// ```
-pub(crate) async fn winterize() -> Result<(), Box<dyn Error>> {
+pub async fn winterize() -> Result<(), Box<dyn Error>> {
+    refuse_when_away().await?;
     let zone_list = get_zones().await?;
+    let _watchdog = watchdog::watch(&zone_list.zones);
     for zone in &zone_list.zones {
         info!("Winterizing zone {}", zone.Name);
         zone.turn_on();
         let _zone = zone.clone();
         let run_time = time::Duration::from_secs(60);
-        thread::sleep(run_time);
+        sleep(run_time);
         _zone.turn_off();
         let run_time = time::Duration::from_secs(3 * 60);
-        thread::sleep(run_time);
+        sleep(run_time);
         info!("Winterized zone {}", zone.Name);
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        cap_zones, check_pins, check_resume_on, count_running_others, finish_manual_run, has_room, local_date, plan, plan_skip_reason,
+        start_of_day, upsert_system_status, zone_name_from_path, Refusal,
+    };
+    use crate::sqlsprinkler::zone::{self, Zone, ZoneId, ZoneList};
+    use chrono::NaiveDate;
+    use std::path::Path;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    fn zone(id: ZoneId, time: u32, enabled: bool) -> Zone {
+        Zone {
+            id,
+            Time: time.into(),
+            Enabled: enabled,
+            SystemOrder: id as i8,
+            ..Zone::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn manual_runs_turn_the_zone_off_after_their_time() {
+        zone::use_virtual_gpio();
+        let zone = Zone { id: 104, GPIO: 44, ..Zone::default() };
+        zone.turn_on();
+        let run = finish_manual_run(&zone, Duration::from_millis(100));
+        tokio::time::timeout(Duration::from_secs(5), run).await.unwrap();
+        assert!(!zone.is_on());
+    }
+
+    #[tokio::test]
+    async fn manual_runs_turn_the_zone_off_when_the_session_hangs_up() {
+        zone::use_virtual_gpio();
+        // Keeps SIGHUP from ending the test process, should it come before the run listens for it.
+        let _hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).unwrap();
+        let zone = Zone { id: 105, GPIO: 45, ..Zone::default() };
+        zone.turn_on();
+        let run = tokio::spawn({
+            let zone = zone.clone();
+            async move { finish_manual_run(&zone, Duration::from_secs(60 * 60)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let killed = std::process::Command::new("kill")
+            .args(["-s", "HUP", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(killed.success());
+        let ran_for = tokio::time::timeout(Duration::from_secs(5), run).await.unwrap().unwrap();
+        assert!(ran_for < Duration::from_secs(60));
+        assert!(!zone.is_on());
+    }
+
+    #[test]
+    fn scheduled_runs_only_resume_after_today() {
+        let now = start_of_day(NaiveDate::from_ymd_opt(2026, 10, 16).unwrap()) + 3600;
+        assert!(check_resume_on(NaiveDate::from_ymd_opt(2026, 10, 17).unwrap(), now).is_ok());
+        assert!(check_resume_on(NaiveDate::from_ymd_opt(2026, 10, 16).unwrap(), now).is_err());
+        assert!(check_resume_on(NaiveDate::from_ymd_opt(2025, 1, 1).unwrap(), now).is_err());
+    }
+
+    #[test]
+    fn an_outranked_refusal_names_the_running_zone() {
+        let running = Zone { id: 3, Name: "Garden".to_string(), ..Zone::default() };
+        assert_eq!(Refusal::Outranked(running).to_string(), "Zone 3 (Garden) is running with a higher priority");
+        assert_eq!(Refusal::AwayMode.to_string(), "Away mode is on");
+    }
+
+    #[test]
+    fn plan_skips_disabled_zones_and_offsets_by_earlier_runs() {
+        let zone_list = ZoneList {
+            zones: vec![zone(1, 5, true), zone(2, 10, false), zone(3, 7, true), zone(4, 1, true)],
+        };
+        let planned: Vec<(ZoneId, u64)> = plan(&zone_list, 1)
+            .iter()
+            .map(|run| (run.zone.id, run.offset.as_secs()))
+            .collect();
+        assert_eq!(planned, vec![(1, 0), (3, 5 * 60), (4, 12 * 60)]);
+        assert_eq!(plan(&zone_list, 1)[1].run_time, Duration::from_secs(7 * 60));
+    }
+
+    #[test]
+    fn plan_offsets_include_valve_lag() {
+        let mut laggy = zone(1, 5, true);
+        laggy.OpenLagMs = 2000;
+        let zone_list = ZoneList { zones: vec![laggy, zone(2, 5, true)] };
+        let planned = plan(&zone_list, 1);
+        assert_eq!(planned[0].on_time, Duration::from_secs(5 * 60 + 2));
+        assert_eq!(planned[1].offset, Duration::from_secs(5 * 60 + 2));
+    }
+
+    #[test]
+    fn plan_skips_zones_out_of_season() {
+        let mut summer = zone(1, 5, true);
+        summer.ActiveMonths = 0b0000_1110_0000;
+        let zone_list = ZoneList { zones: vec![summer, zone(2, 5, true)] };
+        let ids = |month| plan(&zone_list, month).iter().map(|run| run.zone.id).collect::<Vec<_>>();
+        assert_eq!(ids(7), vec![1, 2]);
+        assert_eq!(ids(1), vec![2]);
+        assert_eq!(plan_skip_reason(&zone_list.zones[0], 1), Some("it doesn't water in month 1".to_string()));
+        assert_eq!(plan_skip_reason(&zone(3, 5, false), 7), Some("it isn't enabled".to_string()));
+    }
+
+    #[test]
+    fn shared_and_out_of_range_pins_fail() {
+        let zones = vec![
+            Zone { id: 1, GPIO: 5, ..Zone::default() },
+            Zone { id: 2, GPIO: 5, ..Zone::default() },
+            Zone { id: 3, GPIO: 40, ..Zone::default() },
+            Zone { id: 4, GPIO: 6, ..Zone::default() },
+        ];
+        let failed: Vec<String> = check_pins(&zones, &[]).into_iter().map(|check| check.name).collect();
+        assert_eq!(failed, vec!["Zone 1 GPIO 5", "Zone 2 GPIO 5", "Zone 3 GPIO 40"]);
+        let failed: Vec<String> = check_pins(&zones, &[6]).into_iter().map(|check| check.detail).collect();
+        assert_eq!(failed.last().unwrap(), "reserved by reserved_gpios");
+    }
+
+    #[tokio::test]
+    async fn system_status_is_inserted_into_an_empty_table() {
+        let table: Mutex<Vec<bool>> = Mutex::new(vec![]);
+        let update = || async {
+            let mut rows = table.lock().unwrap();
+            rows.iter_mut().for_each(|row| *row = true);
+            Ok(rows.len() as u64)
+        };
+        let insert = || async {
+            table.lock().unwrap().push(true);
+            Ok(())
+        };
+        let read_back = || async { Ok(table.lock().unwrap().first().copied()) };
+        assert!(upsert_system_status(true, update, insert, read_back).await.is_ok());
+        assert_eq!(*table.lock().unwrap(), vec![true]);
+    }
+
+    #[tokio::test]
+    async fn system_status_that_reads_back_wrong_is_an_error() {
+        let update = || async { Ok(1) };
+        let insert = || async { Ok(()) };
+        let read_back = || async { Ok(Some(false)) };
+        assert!(upsert_system_status(true, update, insert, read_back).await.is_err());
+    }
+
+    #[test]
+    fn the_start_of_a_day_falls_on_that_day() {
+        let date = NaiveDate::from_ymd_opt(2026, 10, 20).unwrap();
+        assert_eq!(local_date(start_of_day(date)), Some(date));
+        assert_eq!(local_date(start_of_day(date) - 1), date.pred_opt());
+    }
+
+    #[test]
+    fn zones_past_the_cap_are_left_out() {
+        assert_eq!(cap_zones(vec![1, 2, 3], 2), vec![1, 2]);
+        assert_eq!(cap_zones(vec![1, 2], 2), vec![1, 2]);
+    }
+
+    #[test]
+    fn zone_name_is_read_from_the_zoneinfo_path() {
+        assert_eq!(
+            zone_name_from_path(Path::new("/usr/share/zoneinfo/America/Denver")),
+            Some("America/Denver".to_string())
+        );
+        assert_eq!(zone_name_from_path(Path::new("/etc/localtime")), None);
+    }
+
+    #[test]
+    fn running_zones_are_counted_against_the_cap() {
+        let zones = vec![zone(1, 10, true), zone(2, 10, true), zone(3, 10, true)];
+        let running = |zone: &Zone| zone.id != 3;
+        // Zone 3 would be a third zone on, exceeding the cap.
+        assert!(!has_room(count_running_others(3, &zones, running), 2));
+        // Zone 1 is already on, so turning it on again stays within the cap.
+        assert!(has_room(count_running_others(1, &zones, running), 2));
+        assert!(has_room(0, 1));
+        assert!(!has_room(1, 1));
+    }
+}
@@ -0,0 +1,103 @@
+use crate::config::{get_settings, WatchdogAction};
+use crate::sqlsprinkler::unix_now;
+use crate::sqlsprinkler::zone::Zone;
+use log::{error, info};
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The last time, in seconds since the unix epoch, that a run showed it was still making progress.
+static LAST_HEARTBEAT: AtomicI64 = AtomicI64::new(0);
+
+/// How often the watchdog checks for a heartbeat.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Watches a run for as long as it is held, stopping the watchdog when dropped.
+pub struct WatchdogGuard {
+    done: Arc<AtomicBool>,
+}
+
+impl Drop for WatchdogGuard {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Records that the current run is still making progress.
+pub(crate) fn heartbeat() {
+    LAST_HEARTBEAT.store(unix_now(), Ordering::SeqCst);
+}
+
+/// Gets whether a run has gone too long without a heartbeat.
+/// # Params
+/// * `last_heartbeat` When the last heartbeat was, in seconds since the unix epoch.
+/// * `now` The current time, in seconds since the unix epoch.
+/// * `timeout_secs` How many seconds without a heartbeat are allowed.
+fn is_stalled(last_heartbeat: i64, now: i64, timeout_secs: u64) -> bool {
+    now - last_heartbeat > timeout_secs as i64
+}
+
+/// Starts watching a run of the given zones. If the run goes `watchdog_timeout_secs` without a
+/// heartbeat, every zone is turned off and `watchdog_action` is taken.
+/// # Params
+/// * `zones` The zones that may be turned on during the run.
+/// # Return
+/// A guard that stops the watchdog when dropped, or `None` if the watchdog is disabled.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::watchdog;
+/// let zone_list = system::get_zones().await?;
+/// let _watchdog = watchdog::watch(&zone_list.zones);
+/// ```
+pub(crate) fn watch(zones: &[Zone]) -> Option<WatchdogGuard> {
+    let settings = get_settings();
+    let timeout_secs = settings.watchdog_timeout_secs;
+    if timeout_secs == 0 {
+        return None;
+    }
+    heartbeat();
+    let done = Arc::new(AtomicBool::new(false));
+    let guard = WatchdogGuard { done: done.clone() };
+    let zones = zones.to_vec();
+    let action = settings.watchdog_action;
+    thread::spawn(move || {
+        while !done.load(Ordering::SeqCst) {
+            thread::sleep(CHECK_INTERVAL);
+            if !is_stalled(LAST_HEARTBEAT.load(Ordering::SeqCst), unix_now(), timeout_secs) {
+                continue;
+            }
+            error!(
+                "CRITICAL: the run has not made progress in {} seconds, turning off all zones",
+                timeout_secs
+            );
+            for zone in &zones {
+                zone.turn_off();
+            }
+            match action {
+                WatchdogAction::TurnOff => {
+                    // Give the run a fresh timeout, in case it recovers and turns a zone back on.
+                    heartbeat();
+                }
+                WatchdogAction::Exit => {
+                    info!("Exiting so the service manager can restart SQLSprinkler");
+                    exit(1);
+                }
+            }
+        }
+    });
+    Some(guard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_stalled;
+
+    #[test]
+    fn stalls_only_after_the_timeout_passes() {
+        assert!(!is_stalled(100, 100, 30));
+        assert!(!is_stalled(100, 130, 30));
+        assert!(is_stalled(100, 131, 30));
+    }
+}
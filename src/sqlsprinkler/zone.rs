@@ -1,28 +1,338 @@
-use crate::sqlsprinkler::get_pool;
-use log::{error, info, warn};
-use rppal::gpio::{Gpio, OutputPin};
+use crate::config::{get_settings, MyConfig, TimeUnit};
+use crate::sqlsprinkler::{history, manual_run};
+use crate::sqlsprinkler::notify::{self, EventType};
+use crate::sqlsprinkler::{format_duration, get_pool, read, scale_down, sleep, sleep_unscaled, unix_now};
+use log::{debug, error, info, warn};
+use rppal::gpio::{Gpio, Level, OutputPin};
 use serde::{Deserialize, Serialize};
-use std::{fmt, thread, time};
+use sqlx::mysql::MySqlRow;
+use sqlx::Row;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::{fmt, time};
+use tokio::task::JoinHandle;
 use structopt::StructOpt;
 
+lazy_static! {
+    /// The current `run_async` run of each zone, so a pending auto off can be cancelled.
+    static ref RUNS: Mutex<HashMap<ZoneId, Run>> = Mutex::new(HashMap::new());
+    /// Whether each pin is on, for when GPIO is virtual.
+    static ref VIRTUAL_PINS: Mutex<HashMap<i8, bool>> = Mutex::new(HashMap::new());
+}
+
+/// A `run_async` run of a zone that has yet to be turned off.
+struct Run {
+    /// Tells this run apart from a newer run of the same zone.
+    id: u64,
+    /// When the zone was turned on, in seconds since the unix epoch.
+    started_at: i64,
+    /// The task that turns the zone off.
+    task: JoinHandle<()>,
+}
+
+/// The id given to the next `run_async` run.
+static NEXT_RUN_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Whether zones are switched in memory instead of on real GPIO pins, see `init_gpio`.
+static VIRTUAL_GPIO: AtomicBool = AtomicBool::new(false);
+
+/// The id of a zone. Every id of a zone, whether in a struct, a table, a CLI argument or an API path,
+/// uses this type, so ids can't wrap when they're converted between types.
+pub type ZoneId = i16;
+
+/// Gets whether `id` could be the id of a zone. Ids start at 1, so 0 and negative ids never are.
+pub fn is_valid_zone_id(id: ZoneId) -> bool {
+    id >= 1
+}
+
+/// Deserializes a zone id, refusing one that can't be the id of a zone, see `is_valid_zone_id`.
+pub fn deserialize_zone_id<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<ZoneId, D::Error> {
+    let id = ZoneId::deserialize(deserializer)?;
+    if is_valid_zone_id(id) {
+        Ok(id)
+    } else {
+        Err(serde::de::Error::custom(format!("zone ids must be at least 1, got {}", id)))
+    }
+}
+
+/// How long a zone runs, in the configured `time_unit`. It may be fractional, such as a 2.5 minute
+/// drip pulse, and is held as thousandths of the unit so zones can still be compared exactly. It is
+/// written as a plain number in JSON, TOML and on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Deserialize)]
+#[serde(try_from = "f64")]
+pub struct ZoneTime(i64);
+
+impl ZoneTime {
+    /// Gets the time in the configured `time_unit`, such as `2.5` for two and a half minutes.
+    pub fn units(self) -> f64 {
+        self.0 as f64 / 1000.0
+    }
+
+    /// Gets how long this time is.
+    /// # Params
+    /// * `unit` The unit the time is in.
+    pub fn duration(self, unit: TimeUnit) -> time::Duration {
+        time::Duration::from_millis(unit.to_secs(self.0.max(0) as u64))
+    }
+}
+
+impl From<u32> for ZoneTime {
+    fn from(units: u32) -> Self {
+        ZoneTime(units as i64 * 1000)
+    }
+}
+
+impl TryFrom<f64> for ZoneTime {
+    type Error = String;
+    fn try_from(units: f64) -> Result<Self, Self::Error> {
+        if !units.is_finite() || units < 0.0 || units > (i64::MAX / 1000) as f64 {
+            return Err(format!("A zone's time must be a number of at least 0, got {}", units));
+        }
+        Ok(ZoneTime((units * 1000.0).round() as i64))
+    }
+}
+
+/// Whole times are written as integers, so they read the same as before times could be fractional.
+impl Serialize for ZoneTime {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.0 % 1000 == 0 {
+            serializer.serialize_i64(self.0 / 1000)
+        } else {
+            serializer.serialize_f64(self.units())
+        }
+    }
+}
+
+impl FromStr for ZoneTime {
+    type Err = String;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let units: f64 = input.parse().map_err(|_| format!("'{}' is not a number", input))?;
+        ZoneTime::try_from(units)
+    }
+}
+
+impl fmt::Display for ZoneTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.units())
+    }
+}
+
+/// The `ActiveMonths` of a zone that waters all year round.
+pub const ALL_MONTHS: i16 = 0x0FFF;
+
 /// Represents a SQLSprinkler zone.
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Default, sqlx::FromRow)]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Zone {
     pub Name: String,
     pub GPIO: i8,
-    pub Time: i64,
+    pub Time: ZoneTime,
     pub Enabled: bool,
     pub Autooff: bool,
     pub SystemOrder: i8,
-    pub id: i8,
+    pub id: ZoneId,
+    #[serde(default)]
+    pub Priority: i8,
+    #[serde(default)]
+    pub total_runs: i64,
+    #[serde(default)]
+    pub total_runtime_secs: i64,
+    /// A bitmask of the months this zone waters in, bit 0 being January.
+    #[serde(default = "all_months")]
+    pub ActiveMonths: i16,
+    /// Whether this zone may be turned on by hand. The schedule runs it either way.
+    #[serde(default = "manual_allowed")]
+    pub AllowManual: bool,
+    /// How many milliseconds this zone's valve takes to start passing water after it is turned on.
+    #[serde(default)]
+    pub OpenLagMs: i32,
+    /// How many milliseconds this zone's valve keeps passing water after it is turned off.
+    #[serde(default)]
+    pub CloseLagMs: i32,
+    /// A stable id for this zone, made from its name when it is added and never changed, so the zone
+    /// can be renamed without breaking integrations that refer to it.
+    #[serde(default)]
+    pub Slug: String,
+}
+
+impl Default for Zone {
+    fn default() -> Self {
+        Zone {
+            Name: String::new(),
+            GPIO: 0,
+            Time: ZoneTime::default(),
+            Enabled: false,
+            Autooff: false,
+            SystemOrder: 0,
+            id: 0,
+            Priority: 0,
+            total_runs: 0,
+            total_runtime_secs: 0,
+            ActiveMonths: ALL_MONTHS,
+            AllowManual: true,
+            OpenLagMs: 0,
+            CloseLagMs: 0,
+            Slug: String::new(),
+        }
+    }
+}
+
+fn all_months() -> i16 {
+    ALL_MONTHS
+}
+
+fn manual_allowed() -> bool {
+    true
+}
+
+/// The outcome of testing a single zone.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ZoneTestResult {
+    pub zone_id: ZoneId,
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+/// Gets whether a GPIO pin is one of the `reserved` pins, which zones may never use.
+pub(crate) fn is_reserved_gpio(gpio: i8, reserved: &[u8]) -> bool {
+    gpio >= 0 && reserved.contains(&(gpio as u8))
+}
+
+/// Fails if a GPIO pin is listed in `reserved_gpios`, for refusing to give it to a zone.
+/// # Params
+/// * `gpio` The pin a zone would use.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::zone;
+/// zone::check_gpio_not_reserved(12)?;
+/// ```
+pub fn check_gpio_not_reserved(gpio: i8) -> Result<(), String> {
+    if is_reserved_gpio(gpio, &get_settings().reserved_gpios) {
+        warn!("GPIO {} is listed in reserved_gpios, refusing to give it to a zone", gpio);
+        return Err(format!("GPIO {} is reserved and can't be used by a zone", gpio));
+    }
+    Ok(())
+}
+
+/// Works out how long a valve must be held on to deliver `run_time` of water, see `Zone::valve_time`.
+fn lag_compensated(run_time: time::Duration, open_lag_ms: i32, close_lag_ms: i32) -> time::Duration {
+    let open_lag = time::Duration::from_millis(open_lag_ms.max(0) as u64);
+    let close_lag = time::Duration::from_millis(close_lag_ms.max(0) as u64);
+    run_time.saturating_add(open_lag).saturating_sub(close_lag)
+}
+
+/// Works out how long a zone's valve is held on in real time, see `Zone::effective_runtime`.
+fn effective_runtime(
+    run_time: time::Duration,
+    open_lag_ms: i32,
+    close_lag_ms: i32,
+    settings: &MyConfig,
+) -> time::Duration {
+    let capped = capped_run_time(run_time, settings.commissioning_mode, settings.commissioning_run_secs);
+    if capped != run_time {
+        debug!("Commissioning mode cut the run from {:?} to {:?}", run_time, capped);
+    }
+    let valve_time = lag_compensated(capped, open_lag_ms, close_lag_ms);
+    if valve_time != capped {
+        debug!("Valve lag calibration changed the run from {:?} to {:?}", capped, valve_time);
+    }
+    let scaled = scale_down(valve_time, settings.time_scale);
+    if scaled != valve_time {
+        debug!("time_scale shortened the run from {:?} to {:?}", valve_time, scaled);
+    }
+    let on_for = scaled.saturating_add(time::Duration::from_millis(settings.zone_startup_delay_ms));
+    if on_for != scaled {
+        debug!("The startup delay lengthened the run from {:?} to {:?}", scaled, on_for);
+    }
+    on_for
+}
+
+/// Picks the duration of a quick run, see `Zone::quick_run_time`.
+fn quick_run_time(
+    explicit: Option<time::Duration>,
+    default_run_minutes: u64,
+    zone_time: time::Duration,
+) -> time::Duration {
+    match explicit {
+        Some(run_time) => run_time,
+        None if default_run_minutes > 0 => time::Duration::from_secs(default_run_minutes * 60),
+        None => zone_time,
+    }
+}
+
+/// Cuts a run down to `commissioning_run_secs` while `commissioning_mode` is on, so every zone can be
+/// checked quickly while the valves still open.
+/// # Params
+/// * `run_time` How long the run would last.
+/// # Return
+/// The shorter of `run_time` and `commissioning_run_secs` in commissioning mode, otherwise `run_time`.
+pub fn commissioning_cap(run_time: time::Duration) -> time::Duration {
+    let settings = get_settings();
+    capped_run_time(run_time, settings.commissioning_mode, settings.commissioning_run_secs)
+}
+
+/// Cuts a run down to `cap_secs` if `capped` is set, see `commissioning_cap`.
+fn capped_run_time(run_time: time::Duration, capped: bool, cap_secs: u64) -> time::Duration {
+    if capped {
+        run_time.min(time::Duration::from_secs(cap_secs))
+    } else {
+        run_time
+    }
+}
+
+/// Checks that the GPIO interface is available. If it isn't, and `require_real_gpio` is off, zones
+/// are switched in memory from then on so everything but the valves still works.
+/// # Return
+/// An error if the GPIO interface is unavailable and `require_real_gpio` is on.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::zone;
+/// zone::init_gpio()?;
+/// ```
+pub fn init_gpio() -> Result<(), rppal::gpio::Error> {
+    match Gpio::new() {
+        Ok(_) => Ok(()),
+        Err(e) if get_settings().require_real_gpio => Err(e),
+        Err(e) => {
+            warn!("GPIO is unavailable ({}), using virtual GPIO. NO VALVES WILL BE SWITCHED!", e);
+            VIRTUAL_GPIO.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+}
+
+/// Switches zones in memory from now on, so tests never touch real GPIO pins.
+#[cfg(test)]
+pub(crate) fn use_virtual_gpio() {
+    VIRTUAL_GPIO.store(true, Ordering::Relaxed);
+}
+
+/// Gets whether zones are being switched in memory because the GPIO interface is unavailable.
+pub fn is_virtual_gpio() -> bool {
+    VIRTUAL_GPIO.load(Ordering::Relaxed)
+}
+
+/// Sets whether a virtual GPIO pin is on.
+fn set_virtual_pin(pin: i8, on: bool) {
+    VIRTUAL_PINS.lock().unwrap().insert(pin, on);
+}
+
+/// Gets whether a virtual GPIO pin is on. Pins start off.
+fn virtual_pin_is_on(pin: i8) -> bool {
+    VIRTUAL_PINS.lock().unwrap().get(&pin).copied().unwrap_or(false)
 }
 
 impl Zone {
     /// Gets the gpio interface for this zone.
     /// # Return
-    ///     `gpio` An OutputPin that we can use to turn the zone on or off
+    /// * `gpio` An OutputPin that we can use to turn the zone on or off
     /// # Example
-    /// ```
+    /// ```ignore
     /// use sqlsprinkler::zone::Zone;
     /// let zone = Zone::default();
     /// let gpio = zone.get_gpio();
@@ -42,15 +352,33 @@ impl Zone {
         };
     }
 
-    /// Turns on this zone.
+    /// Checks that this zone's GPIO pin can be acquired, without changing its mode or level.
+    /// # Return
+    /// An error if the GPIO interface or the pin is unavailable.
     /// # Example
+    /// ```ignore
+    /// use sqlsprinkler::zone::Zone;
+    /// let zone = Zone::default();
+    /// let usable = zone.probe_gpio().is_ok();
     /// ```
+    pub fn probe_gpio(&self) -> Result<(), rppal::gpio::Error> {
+        Gpio::new()?.get(self.GPIO as u8)?;
+        Ok(())
+    }
+
+    /// Turns on this zone.
+    /// # Example
+    /// ```ignore
     /// use sqlsprinkler::zone::Zone;
     /// let zone = Zone::default();
     /// zone.turn_on();
     /// ```
     pub fn turn_on(&self) {
         info!("Turned on {}", self);
+        if is_virtual_gpio() {
+            set_virtual_pin(self.GPIO, true);
+            return;
+        }
         match self.get_gpio() {
             Ok(mut gpio) => {
                 gpio.set_low();
@@ -63,12 +391,17 @@ impl Zone {
 
     /// Turns off this zone.
     /// # Example
-    /// ```
+    /// ```ignore
     /// use sqlsprinkler::zone::Zone;
     /// let zone = Zone::default();
     /// zone.turn_off();
     /// ```
     pub fn turn_off(&self) {
+        if is_virtual_gpio() {
+            info!("Turned off {}", self);
+            set_virtual_pin(self.GPIO, false);
+            return;
+        }
         match self.get_gpio() {
             Ok(mut gpio) => {
                 info!("Turned off {}", self);
@@ -82,7 +415,7 @@ impl Zone {
 
     /// Gets the name of this zone
     /// # Example
-    /// ```
+    /// ```ignore
     /// use sqlsprinkler::zone::Zone;
     /// let zone = Zone::default();
     /// let name = zone.get_name();
@@ -93,14 +426,17 @@ impl Zone {
 
     /// Gets whether or not this zone is on
     /// # Example
-    /// ```
+    /// ```ignore
     /// use sqlsprinkler::zone::Zone;
     /// let zone = Zone::default();
     /// let is_on = zone.is_on();
     /// ```
     /// # Return
-    ///     `on` A bool representing whether or not this zone is on.
-    pub(self) fn is_on(&self) -> bool {
+    /// * `on` A bool representing whether or not this zone is on.
+    pub fn is_on(&self) -> bool {
+        if is_virtual_gpio() {
+            return virtual_pin_is_on(self.GPIO);
+        }
         match self.get_gpio() {
             Ok(gpio) => gpio.is_set_low(),
             Err(_e) => {
@@ -110,84 +446,385 @@ impl Zone {
         }
     }
 
-    /// Turns the zone on for 12 seconds and then turn off.
+    /// Turns the zone on for `test_duration_secs` and then off like `test_for`. With 0 the zone is
+    /// switched on and straight back off, for a quick electrical check.
+    /// # Return
+    /// * `true` if the zone read back as on after turning on, and off after turning off.
+    /// # Example
+    /// ```ignore
+    /// use sqlsprinkler::zone::Zone;
+    /// let zone = Zone::default();
+    /// let passed = zone.test();
+    /// ```
+    pub fn test(&self) -> bool {
+        self.test_for(time::Duration::from_secs(get_settings().test_duration_secs))
+    }
+
+    /// Turns the zone on for the given duration and then off, checking that the pin really switched.
+    /// # Params
+    /// * `run_time` How long to keep the zone on for.
+    /// # Return
+    /// * `true` if the zone read back as on after turning on, and off after turning off.
     /// # Example
+    /// ```ignore
+    /// use sqlsprinkler::zone::Zone;
+    /// let zone = Zone::default();
+    /// let passed = zone.test_for(std::time::Duration::from_secs(5));
     /// ```
+    pub fn test_for(&self, run_time: time::Duration) -> bool {
+        self.test_report(run_time).passed
+    }
+
+    /// Turns the zone on for the given duration and then off like `test_for`, describing what
+    /// went wrong if the pin didn't switch.
+    /// # Params
+    /// * `run_time` How long to keep the zone on for.
+    /// # Example
+    /// ```ignore
     /// use sqlsprinkler::zone::Zone;
     /// let zone = Zone::default();
-    /// zone.test();
+    /// let result = zone.test_report(std::time::Duration::from_secs(5));
     /// ```
-    pub fn test(&self) {
-        info!("Testing {}", self.Name);
+    pub fn test_report(&self, run_time: time::Duration) -> ZoneTestResult {
+        info!("Testing {} for {} seconds", self.Name, run_time.as_secs());
         self.turn_on();
-        info!("Sleeping for 12 seconds...");
-        let run_time = time::Duration::from_secs(12);
-        thread::sleep(run_time);
+        let turned_on = self.is_on();
+        if !turned_on {
+            warn!("Zone {} did not read back as on", self.id);
+        }
+        sleep(run_time);
         self.turn_off();
+        let turned_off = !self.is_on();
+        if !turned_off {
+            warn!("Zone {} did not read back as off", self.id);
+        }
+        let detail = match (turned_on, turned_off) {
+            (true, true) => "turned on and off",
+            (false, true) => "did not read back as on",
+            (true, false) => "did not read back as off",
+            (false, false) => "did not read back as on, then not as off",
+        };
+        ZoneTestResult {
+            zone_id: self.id,
+            name: self.Name.clone(),
+            passed: turned_on && turned_off,
+            detail: detail.to_string(),
+        }
+    }
+
+    /// Gets whether this zone may preempt the given running zone. Higher priority zones win, and
+    /// zones with equal priority are broken by system order, with the zone that runs first winning.
+    /// # Params
+    /// * `other` The zone that is already running.
+    /// # Return
+    /// * `true` if this zone should be turned on in place of `other`.
+    /// # Example
+    /// ```ignore
+    /// use sqlsprinkler::zone::Zone;
+    /// let zone = Zone::default();
+    /// let preempts = zone.outranks(&Zone::default());
+    /// ```
+    pub fn outranks(&self, other: &Zone) -> bool {
+        if self.Priority != other.Priority {
+            return self.Priority > other.Priority;
+        }
+        self.SystemOrder < other.SystemOrder
+    }
+
+    /// Gets whether this zone waters in the given month.
+    /// # Params
+    /// * `month` The month, from 1 for January to 12 for December.
+    /// # Example
+    /// ```ignore
+    /// use sqlsprinkler::zone::Zone;
+    /// let zone = Zone::default();
+    /// let waters_in_july = zone.is_active_in(7);
+    /// ```
+    pub fn is_active_in(&self, month: u32) -> bool {
+        (1..=12).contains(&month) && self.ActiveMonths & (1 << (month - 1)) != 0
     }
 
-    /// Runs this zone, and automatically turn it off if launched from another thread and if
-    /// `auto_off` is set to true for this zone. Will run for `time` minutes
+    /// Gets how long this zone should run for, interpreting `Time` in the configured `time_unit`.
+    /// # Return
+    /// * `Duration` The length of a run of this zone, cut down in commissioning mode.
+    pub fn run_time(&self) -> time::Duration {
+        commissioning_cap(self.Time.duration(get_settings().time_unit))
+    }
+
+    /// Gets how long to hold this zone's valve on so that it passes water for `run_time`. The valve
+    /// only starts passing water `OpenLagMs` after it is turned on, and keeps passing water for
+    /// `CloseLagMs` after it is turned off, so it is held on for `run_time + OpenLagMs - CloseLagMs`,
+    /// and never less than zero.
+    /// # Params
+    /// * `run_time` How long the zone should water for.
     /// # Example
+    /// ```ignore
+    /// use sqlsprinkler::zone::Zone;
+    /// let zone = Zone::default();
+    /// let on_for = zone.valve_time(zone.run_time());
     /// ```
+    pub fn valve_time(&self, run_time: time::Duration) -> time::Duration {
+        lag_compensated(run_time, self.OpenLagMs, self.CloseLagMs)
+    }
+
+    /// Gets how long, in real time, to hold this zone on for a run of `run_time`. Every way of running
+    /// a zone goes through this, so they all agree. The adjustments are made in order: the
+    /// commissioning cap, the valve lag calibration of `valve_time`, `time_scale`, then the startup
+    /// delay. Each one that changes the time is logged at debug level.
+    /// # Params
+    /// * `run_time` How long the zone should water for.
+    /// # Example
+    /// ```ignore
     /// use sqlsprinkler::zone::Zone;
     /// let zone = Zone::default();
-    /// zone.run_async();
+    /// let off_in = zone.effective_runtime(zone.run_time());
     /// ```
-    pub fn run_async(&self) {
-        self.turn_on();
-        if self.Autooff {
-            // Need to clone because we are moving into a new thread.
-            let _zone = self.clone();
-            thread::spawn(move || {
-                let run_time = time::Duration::from_secs((_zone.Time * 60) as u64);
-                thread::sleep(run_time);
-                _zone.turn_off();
-            });
+    pub fn effective_runtime(&self, run_time: time::Duration) -> time::Duration {
+        effective_runtime(run_time, self.OpenLagMs, self.CloseLagMs, &get_settings())
+    }
+
+    /// Gets how long a quick run of this zone should last. An explicit duration wins, then
+    /// `default_run_minutes` if it is set, then the zone's own time.
+    /// # Params
+    /// * `explicit` The duration given for this run, if any.
+    /// # Example
+    /// ```ignore
+    /// use sqlsprinkler::zone::Zone;
+    /// let zone = Zone::default();
+    /// let run_time = zone.quick_run_time(None);
+    /// ```
+    pub fn quick_run_time(&self, explicit: Option<time::Duration>) -> time::Duration {
+        commissioning_cap(quick_run_time(explicit, get_settings().default_run_minutes, self.run_time()))
+    }
+
+    /// Gets how long a zone turned on by hand should stay on. A zone with `auto_off` never stays on
+    /// by hand indefinitely, however it is turned on: it runs for the time asked for, or else its own
+    /// time. A zone without it stays on until it is turned off, unless a time is asked for.
+    /// # Params
+    /// * `requested` Whether a time was asked for, such as with `--for`, and if so what time. A
+    ///   request without a time lasts as long as a quick run, see `quick_run_time`.
+    /// # Return
+    /// How long the zone should stay on, or `None` if it stays on until turned off.
+    /// # Example
+    /// ```ignore
+    /// use sqlsprinkler::zone::Zone;
+    /// let zone = Zone::default();
+    /// let run_time = zone.manual_run_time(None);
+    /// ```
+    pub fn manual_run_time(&self, requested: Option<Option<time::Duration>>) -> Option<time::Duration> {
+        match requested {
+            Some(explicit) => Some(self.quick_run_time(explicit)),
+            None if self.Autooff => Some(self.run_time()),
+            None => None,
         }
     }
 
-    /// Runs this zone in a blocking fashion.
+    /// Turns on this zone, and if `auto_off` is set or a time is asked for, spawns a tokio task that
+    /// turns it off after that time. The `started` event is sent, and the `finished` event once a
+    /// timed run turns the zone off. Must be called from within a tokio runtime.
+    /// # Params
+    /// * `requested` How long to run the zone for, or `None` for its own time if it has `auto_off`.
+    /// # Return
+    /// How long until the zone is turned off, or `None` if it stays on until turned off.
     /// # Example
+    /// ```ignore
+    /// use sqlsprinkler::zone::Zone;
+    /// let zone = Zone::default();
+    /// zone.run_async(None);
     /// ```
+    pub fn run_async(&self, requested: Option<time::Duration>) -> Option<time::Duration> {
+        let run_time = match self.manual_run_time(requested.map(Some)) {
+            Some(run_time) => run_time,
+            None => {
+                self.turn_on();
+                notify::send_in_background(EventType::Started, Some(self), "Turned on until turned off".to_string());
+                return None;
+            }
+        };
+        let off_in = self.effective_runtime(run_time);
+        self.run_async_for(off_in);
+        notify::send_in_background(EventType::Started, Some(self), format!("Running for {}", format_duration(off_in)));
+        Some(off_in)
+    }
+
+    /// Turns on this zone and spawns a tokio task that turns it off after `off_in`, replacing any
+    /// pending auto off. Once it does, the run is recorded in the zone's history and the `finished`
+    /// event is sent. A run cut short is recorded by whoever stops it, see `cancel_run`. `off_in` is
+    /// real time, it isn't divided by `time_scale`. Must be called from within a tokio runtime.
+    /// # Params
+    /// * `off_in` How long until the zone is turned off.
+    /// # Example
+    /// ```ignore
     /// use sqlsprinkler::zone::Zone;
     /// let zone = Zone::default();
-    /// zone.run();
+    /// zone.run_async_for(std::time::Duration::from_secs(60));
     /// ```
-    pub fn run(&self) {
+    pub fn run_async_for(&self, off_in: time::Duration) {
         self.turn_on();
+        let run_id = NEXT_RUN_ID.fetch_add(1, Ordering::Relaxed);
+        // Need to clone because we are moving into a new task.
         let _zone = self.clone();
+        let started_at = unix_now();
+        let mut runs = RUNS.lock().unwrap();
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(off_in).await;
+            let finished = {
+                let mut runs = RUNS.lock().unwrap();
+                // A newer run may have replaced this one while we slept.
+                let current = matches!(runs.get(&_zone.id), Some(run) if run.id == run_id);
+                if current {
+                    runs.remove(&_zone.id);
+                    _zone.turn_off();
+                }
+                current
+            };
+            if finished {
+                manual_run::clear(_zone.id).await;
+                history::record(&_zone, started_at, off_in).await;
+                notify::send(EventType::Finished, Some(&_zone), &format!("Ran for {}", format_duration(off_in))).await;
+            }
+        });
+        if let Some(replaced) = runs.insert(self.id, Run { id: run_id, started_at, task }) {
+            replaced.task.abort();
+        }
+    }
 
-        let run_time = time::Duration::from_secs((_zone.Time * 60) as u64);
-        thread::sleep(run_time);
+    /// Cancels the pending auto off of this zone, if it was started with `run_async`. The run is cut
+    /// short, so it isn't recorded in the zone's history, which is left to the caller.
+    /// # Return
+    /// When the cancelled run started, in seconds since the unix epoch, or `None` if there was no
+    /// pending auto off to cancel.
+    pub fn cancel_run(&self) -> Option<i64> {
+        match RUNS.lock().unwrap().remove(&self.id) {
+            Some(run) => {
+                run.task.abort();
+                Some(run.started_at)
+            }
+            None => None,
+        }
+    }
+
+    /// Gets whether this zone is running, either because its pin is on or because it has a pending
+    /// auto off.
+    pub fn is_running(&self) -> bool {
+        self.is_on() || RUNS.lock().unwrap().contains_key(&self.id)
+    }
+
+    /// Stops this zone, turning it off and cancelling any pending auto off.
+    /// # Return
+    /// When the run whose auto off was cancelled started, see `cancel_run`.
+    /// # Example
+    /// ```ignore
+    /// use sqlsprinkler::zone::Zone;
+    /// let zone = Zone::default();
+    /// zone.stop();
+    /// ```
+    pub fn stop(&self) -> Option<i64> {
+        let started_at = self.cancel_run();
+        if started_at.is_some() {
+            info!("Cancelled pending auto off of zone {}", self.id);
+        }
         self.turn_off();
+        started_at
     }
 
-    /// Updates this zone to the given `zone` parameter.
-    /// # Params
-    ///     `zone` The zone to update to.
+    /// Re-acquires this zone's GPIO pin and drives it to the off level, cancelling any pending auto
+    /// off. Used to recover when the pin and the software disagree about whether the zone is on.
     /// # Return
-    ///     `true` if the zone was updated, `false` otherwise.
+    /// An error if the GPIO pin could not be acquired.
     /// # Example
+    /// ```ignore
+    /// use sqlsprinkler::zone::Zone;
+    /// let zone = Zone::default();
+    /// zone.reset_gpio();
     /// ```
+    pub fn reset_gpio(&self) -> Result<(), rppal::gpio::Error> {
+        if is_virtual_gpio() {
+            self.cancel_run();
+            set_virtual_pin(self.GPIO, false);
+            info!("Reset virtual GPIO {} of zone {}", self.GPIO, self.id);
+            return Ok(());
+        }
+        let pin = Gpio::new()?.get(self.GPIO as u8)?;
+        let before = pin.read();
+        if self.cancel_run().is_some() {
+            info!("Cancelled pending auto off of zone {}", self.id);
+        }
+        let mut pin = pin.into_output();
+        pin.set_reset_on_drop(false);
+        pin.set_high();
+        let after = if pin.is_set_high() { Level::High } else { Level::Low };
+        info!(
+            "Reset GPIO {} of zone {}: {} -> {}",
+            self.GPIO,
+            self.id,
+            before,
+            after
+        );
+        Ok(())
+    }
+
+    /// Runs this zone in a blocking fashion.
+    /// # Params
+    /// * `run_time` How long the zone should water for, such as its `run_time`.
+    /// # Example
+    /// ```ignore
     /// use sqlsprinkler::zone::Zone;
     /// let zone = Zone::default();
-    /// let mut new_zone = zone.clone();
-    /// new_zone.name = "New Name".to_string();
-    /// zone.update(new_zone);
+    /// zone.run(zone.run_time());
     /// ```
-    pub async fn update(&self, zone: Zone) -> Result<bool, sqlx::Error> {
-        // let query = get_pool().prepare("UPDATE Zones SET Name=?, Gpio=?, Time=?, AutoOff=?, Enabled=? ,SystemOrder=? WHERE ID=?").into_iter();
+    pub fn run(&self, run_time: time::Duration) {
+        self.turn_on();
+        self.finish_run(run_time);
+    }
+
+    /// Waits out a run of this zone, which has already been turned on, in a blocking fashion, then
+    /// turns it off.
+    /// # Params
+    /// * `run_time` How long the zone should water for, such as its `run_time`.
+    /// # Example
+    /// ```ignore
+    /// use sqlsprinkler::zone::Zone;
+    /// let zone = Zone::default();
+    /// zone.turn_on();
+    /// zone.finish_run(zone.run_time());
+    /// ```
+    pub fn finish_run(&self, run_time: time::Duration) {
+        info!("Running {} for {} seconds", self.Name, run_time.as_secs());
+        sleep_unscaled(self.effective_runtime(run_time));
+        self.turn_off();
+    }
+
+    /// Updates this zone with the given `update`. Fields left out of the update keep their current
+    /// values, so a client that doesn't know a field can't reset it.
+    /// # Params
+    /// * `update` The new values of the zone.
+    /// # Return
+    /// * `true` if the zone was updated, `false` otherwise.
+    /// # Example
+    /// ```ignore
+    /// use sqlsprinkler::zone::{Zone, ZoneUpdate};
+    /// let zone = Zone::default();
+    /// let mut update = ZoneUpdate::from(&zone);
+    /// update.Name = "New Name".to_string();
+    /// zone.update(update);
+    /// ```
+    pub async fn update(&self, update: ZoneUpdate) -> Result<bool, sqlx::Error> {
         sqlx::query!(
-            "UPDATE Zones SET Name=?, GPIO=?, Time=?, Autooff=?, Enabled=? ,SystemOrder=? WHERE ID=?",
-            zone.Name,
-            zone.GPIO,
-            zone.Time,
-            zone.Autooff,
-            zone.Enabled,
-            zone.SystemOrder,
+            "UPDATE Zones SET Name=?, GPIO=?, Time=?, Autooff=?, Enabled=? ,SystemOrder=?, Priority=COALESCE(?, Priority), ActiveMonths=COALESCE(?, ActiveMonths), AllowManual=COALESCE(?, AllowManual), OpenLagMs=COALESCE(?, OpenLagMs), CloseLagMs=COALESCE(?, CloseLagMs) WHERE ID=?",
+            update.Name,
+            update.GPIO,
+            update.Time.units(),
+            update.Autooff,
+            update.Enabled,
+            update.SystemOrder,
+            update.Priority,
+            update.ActiveMonths,
+            update.AllowManual,
+            update.OpenLagMs,
+            update.CloseLagMs,
             self.id
-        ).execute(&get_pool()).await?;
+        ).execute(&get_pool()?).await?;
         info!("Updated zone with id {}.", self.id);
         Ok(true)
     }
@@ -196,7 +833,7 @@ impl Zone {
     /// # Return
     /// `zone_with_state` A ZoneWithState struct representing this zone and its current state.
     /// # Example
-    /// ```
+    /// ```ignore
     /// use sqlsprinkler::zone::Zone;
     /// let zone = Zone::default();
     /// let zone_with_state = zone.get_with_state();
@@ -211,24 +848,14 @@ impl Zone {
             system_order: self.SystemOrder,
             state: self.is_on(),
             id: self.id,
-        }
-    }
-
-    /// Updates the order of this zone
-    /// # Params
-    ///     `order` An i8 representing the new ordering of this zone.
-    /// # Example
-    /// ```
-    /// use sqlsprinkler::zone::Zone;
-    /// let zone = Zone::default();
-    /// zone.set_order(1);
-    /// ```
-    pub async fn set_order(&self, order: i8) {
-        let mut updated_zone = self.clone();
-        updated_zone.SystemOrder = order;
-        match self.update(updated_zone).await {
-            Ok(_) => info!("Updated order of zone with id {}", self.id),
-            Err(e) => error!("Failed to update order of zone with id {}: {}", self.id, e),
+            priority: self.Priority,
+            active_months: self.ActiveMonths,
+            allow_manual: self.AllowManual,
+            open_lag_ms: self.OpenLagMs,
+            close_lag_ms: self.CloseLagMs,
+            slug: self.Slug.clone(),
+            total_runs: self.total_runs,
+            total_runtime_secs: self.total_runtime_secs,
         }
     }
 }
@@ -244,46 +871,133 @@ impl Clone for Zone {
             Autooff: self.Autooff,
             SystemOrder: self.SystemOrder,
             id: self.id,
+            Priority: self.Priority,
+            total_runs: self.total_runs,
+            total_runtime_secs: self.total_runtime_secs,
+            ActiveMonths: self.ActiveMonths,
+            AllowManual: self.AllowManual,
+            OpenLagMs: self.OpenLagMs,
+            CloseLagMs: self.CloseLagMs,
+            Slug: self.Slug.clone(),
         }
     }
 }
 
+/// Reads a zone from a row of the `Zones` table. A NULL or mistyped column falls back to its default
+/// and is logged, so one bad row doesn't stop every zone from loading. Only the `id` is required.
+impl<'r> sqlx::FromRow<'r, MySqlRow> for Zone {
+    fn from_row(row: &'r MySqlRow) -> Result<Self, sqlx::Error> {
+        let id: ZoneId = row.try_get("id")?;
+        Ok(Zone {
+            Name: column_or_default(row.try_get("Name"), "Name", id),
+            GPIO: column_or_default(row.try_get("GPIO"), "GPIO", id),
+            Time: ZoneTime::try_from(column_or_default::<f64>(row.try_get("Time"), "Time", id)).unwrap_or_else(|e| {
+                warn!("Zone {} has a bad Time column, using 0: {}", id, e);
+                ZoneTime::default()
+            }),
+            Enabled: column_or_default(row.try_get("Enabled"), "Enabled", id),
+            Autooff: column_or_default(row.try_get("Autooff"), "Autooff", id),
+            SystemOrder: column_or_default(row.try_get("SystemOrder"), "SystemOrder", id),
+            id,
+            Priority: column_or_default(row.try_get("Priority"), "Priority", id),
+            total_runs: column_or_default(row.try_get("total_runs"), "total_runs", id),
+            total_runtime_secs: column_or_default(
+                row.try_get("total_runtime_secs"),
+                "total_runtime_secs",
+                id,
+            ),
+            ActiveMonths: column_or(row.try_get("ActiveMonths"), ALL_MONTHS, "ActiveMonths", id),
+            AllowManual: column_or(row.try_get("AllowManual"), true, "AllowManual", id),
+            OpenLagMs: column_or_default(row.try_get("OpenLagMs"), "OpenLagMs", id),
+            CloseLagMs: column_or_default(row.try_get("CloseLagMs"), "CloseLagMs", id),
+            Slug: column_or_default(row.try_get("Slug"), "Slug", id),
+        })
+    }
+}
+
+/// Gets the value read from a zone's column, or the default for its type if it couldn't be read.
+/// # Params
+/// * `value` The result of reading the column.
+/// * `column` The name of the column, for logging.
+/// * `id` The id of the zone the column belongs to, for logging.
+fn column_or_default<T: Default + fmt::Debug>(value: Result<T, sqlx::Error>, column: &str, id: ZoneId) -> T {
+    column_or(value, T::default(), column, id)
+}
+
+/// Gets the value read from a zone's column, or `fallback` if it couldn't be read.
+fn column_or<T: fmt::Debug>(value: Result<T, sqlx::Error>, fallback: T, column: &str, id: ZoneId) -> T {
+    value.unwrap_or_else(|e| {
+        warn!("Zone {} has a bad {} column, using {:?}: {}", id, column, fallback, e);
+        fallback
+    })
+}
+
 /// Formats the zone to be displayed as
-/// `name | gpio | time | auto_off | enabled | system_order | id`
+/// `name | gpio | time | auto_off | enabled | system_order | priority | id`
 impl fmt::Display for Zone {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "Name: {} | Gpio: {} | Time: {} | Enabled: {} | AutoOff: {} | Order: {} | Id: {}",
-            self.Name, self.GPIO, self.Time, self.Enabled, self.Autooff, self.SystemOrder, self.id
+            "Name: {} | Slug: {} | Gpio: {} | Time: {} | Enabled: {} | AutoOff: {} | Order: {} | Priority: {} | Id: {}",
+            self.Name, self.Slug, self.GPIO, self.Time, self.Enabled, self.Autooff, self.SystemOrder, self.Priority, self.id
         )
     }
 }
 
 /// Object representing toggling the zone.
 /// # Params
-///     * `id` The ID of the zone as it pertains in the database
-///     * `state` The state to set the GPIO pin (true for on, false for off)
+/// * `id` The ID of the zone as it pertains in the database
+/// * `state` The state to set the GPIO pin (true for on, false for off)
+/// * `time` How long to run the zone for when turning it on, in the configured `time_unit`,
+///   turning it off after even without `auto_off`
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ZoneToggle {
-    pub id: i8,
+    #[serde(deserialize_with = "deserialize_zone_id")]
+    pub id: ZoneId,
     pub state: bool,
+    #[serde(default)]
+    pub time: Option<ZoneTime>,
 }
 
 /// Object representing the ordering of a zone.
 /// # Params
-///     * `order` A JSON list representing the new system ordering.
+/// * `order` A JSON list representing the new system ordering.
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ZoneOrder {
     pub order: Vec<i8>,
 }
 
+/// Which way to move a zone in the system order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Earlier in the system order.
+    Up,
+    /// Later in the system order.
+    Down,
+}
+
+impl FromStr for Direction {
+    type Err = String;
+    fn from_str(input: &str) -> Result<Direction, Self::Err> {
+        match input {
+            "up" => Ok(Direction::Up),
+            "down" => Ok(Direction::Down),
+            _ => Err(format!("Unrecognized direction {}, expected up or down", input)),
+        }
+    }
+}
+
 /// Used when are deleting a new zone via api
 /// # Params
-///     *   `id` The ID in the database that we are going to delete
+/// *   `id` The ID in the database that we are going to delete
+/// *   `force` Whether to stop the zone and delete it if it is running
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ZoneDelete {
-    pub id: i8,
+    #[serde(deserialize_with = "deserialize_zone_id")]
+    pub id: ZoneId,
+    #[serde(default)]
+    pub force: bool,
 }
 
 /// Used when we are creating a new zone from an api response.
@@ -291,11 +1005,119 @@ pub struct ZoneDelete {
 pub struct ZoneAdd {
     pub name: String,
     pub gpio: i8,
-    pub time: u64,
+    /// How long the zone runs, in the configured `time_unit`, such as `10` or `2.5`.
+    pub time: ZoneTime,
     #[structopt(parse(try_from_str))]
     pub enabled: bool,
     #[structopt(parse(try_from_str))]
     pub auto_off: bool,
+    #[structopt(long, default_value = "0")]
+    #[serde(default)]
+    pub priority: i8,
+    /// The months the zone waters in, such as `4-10` or `1,2,12`. Defaults to every month.
+    #[structopt(long, default_value = "1-12", parse(try_from_str = parse_months))]
+    #[serde(default = "all_months")]
+    pub active_months: i16,
+    /// Whether the zone may be turned on by hand. Defaults to true.
+    #[structopt(long, default_value = "true", parse(try_from_str))]
+    #[serde(default = "manual_allowed")]
+    pub allow_manual: bool,
+    /// How many milliseconds the zone's valve takes to open. Defaults to 0.
+    #[structopt(long, default_value = "0")]
+    #[serde(default)]
+    pub open_lag_ms: i32,
+    /// How many milliseconds the zone's valve takes to close. Defaults to 0.
+    #[structopt(long, default_value = "0")]
+    #[serde(default)]
+    pub close_lag_ms: i32,
+}
+
+/// Parses a list of months into an `ActiveMonths` bitmask. Months are numbered 1 to 12 and
+/// separated by commas. A range such as `4-10` includes both ends, and a range such as `11-2` wraps
+/// around the new year.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::zone::parse_months;
+/// let summer = parse_months("6-8")?;
+/// ```
+pub fn parse_months(months: &str) -> Result<i16, String> {
+    let month = |m: &str| match m.trim().parse::<u32>() {
+        Ok(m) if (1..=12).contains(&m) => Ok(m),
+        _ => Err(format!("'{}' is not a month from 1 to 12", m.trim())),
+    };
+    let mut mask = 0;
+    for part in months.split(',') {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (month(first)?, month(last)?),
+            None => (month(part)?, month(part)?),
+        };
+        let mut m = first;
+        loop {
+            mask |= 1 << (m - 1);
+            if m == last {
+                break;
+            }
+            m = m % 12 + 1;
+        }
+    }
+    Ok(mask)
+}
+
+/// Used when we are swapping the system order of two zones.
+/// # Params
+/// * `id1` The ID of the first zone
+/// * `id2` The ID of the second zone
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ZoneSwap {
+    #[serde(deserialize_with = "deserialize_zone_id")]
+    pub id1: ZoneId,
+    #[serde(deserialize_with = "deserialize_zone_id")]
+    pub id2: ZoneId,
+}
+
+/// Used when we are updating a zone from an api response. The fields added to zones over time are
+/// optional, and a zone keeps its current value for any that are left out.
+/// # Params
+/// * `id` The ID of the zone to update
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ZoneUpdate {
+    #[serde(deserialize_with = "deserialize_zone_id")]
+    pub id: ZoneId,
+    pub Name: String,
+    pub GPIO: i8,
+    pub Time: ZoneTime,
+    pub Enabled: bool,
+    pub Autooff: bool,
+    pub SystemOrder: i8,
+    #[serde(default)]
+    pub Priority: Option<i8>,
+    #[serde(default)]
+    pub ActiveMonths: Option<i16>,
+    #[serde(default)]
+    pub AllowManual: Option<bool>,
+    #[serde(default)]
+    pub OpenLagMs: Option<i32>,
+    #[serde(default)]
+    pub CloseLagMs: Option<i32>,
+}
+
+impl From<&Zone> for ZoneUpdate {
+    fn from(zone: &Zone) -> Self {
+        ZoneUpdate {
+            id: zone.id,
+            Name: zone.Name.clone(),
+            GPIO: zone.GPIO,
+            Time: zone.Time,
+            Enabled: zone.Enabled,
+            Autooff: zone.Autooff,
+            SystemOrder: zone.SystemOrder,
+            Priority: Some(zone.Priority),
+            ActiveMonths: Some(zone.ActiveMonths),
+            AllowManual: Some(zone.AllowManual),
+            OpenLagMs: Some(zone.OpenLagMs),
+            CloseLagMs: Some(zone.CloseLagMs),
+        }
+    }
 }
 
 /// Used when we want to get a zone with whether or not it is turned on.
@@ -303,12 +1125,20 @@ pub struct ZoneAdd {
 pub struct ZoneWithState {
     pub name: String,
     pub gpio: i8,
-    pub time: i64,
+    pub time: ZoneTime,
     pub enabled: bool,
     pub auto_off: bool,
     pub system_order: i8,
     pub state: bool,
-    pub id: i8,
+    pub id: ZoneId,
+    pub priority: i8,
+    pub active_months: i16,
+    pub allow_manual: bool,
+    pub open_lag_ms: i32,
+    pub close_lag_ms: i32,
+    pub slug: String,
+    pub total_runs: i64,
+    pub total_runtime_secs: i64,
 }
 
 /// Used for reordering zones.
@@ -317,21 +1147,108 @@ pub struct ZoneList {
     pub zones: Vec<Zone>,
 }
 
+impl ZoneList {
+    /// Finds the one zone that the given reference refers to. Names are matched ignoring case, and a
+    /// name also matches a zone's slug.
+    /// # Params
+    /// * `zone_ref` The id, name or slug of the zone.
+    /// # Return
+    /// The matching zone, or an error if no zone or more than one zone matches.
+    /// # Example
+    /// ```ignore
+    /// use sqlsprinkler::zone::ZoneRef;
+    /// let zone = zone_list.find(&"Front Lawn".parse().unwrap());
+    /// ```
+    pub fn find(&self, zone_ref: &ZoneRef) -> Result<Zone, ZoneLookupError> {
+        let matches: Vec<&Zone> = self
+            .zones
+            .iter()
+            .filter(|z| match zone_ref {
+                ZoneRef::Id(id) => z.id == *id,
+                ZoneRef::Name(name) => z.Name.eq_ignore_ascii_case(name) || z.Slug == *name,
+            })
+            .collect();
+        match matches.as_slice() {
+            [] => Err(ZoneLookupError::NotFound(zone_ref.clone())),
+            [zone] => Ok((*zone).clone()),
+            _ => Err(ZoneLookupError::Ambiguous(zone_ref.clone(), matches.len())),
+        }
+    }
+}
+
+/// Refers to a zone from the command line, either by its id or by its name or slug. Anything that
+/// parses as a number is treated as an id, and must be a valid `ZoneId` of at least 1.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ZoneRef {
+    Id(ZoneId),
+    Name(String),
+}
+
+impl FromStr for ZoneRef {
+    type Err = String;
+    fn from_str(input: &str) -> Result<ZoneRef, Self::Err> {
+        let number = match input.parse::<i64>() {
+            Ok(number) => number,
+            Err(_) => return Ok(ZoneRef::Name(input.to_string())),
+        };
+        match ZoneId::try_from(number) {
+            Ok(id) if is_valid_zone_id(id) => Ok(ZoneRef::Id(id)),
+            _ => Err(format!("Zone ids must be between 1 and {}, got {}", ZoneId::MAX, number)),
+        }
+    }
+}
+
+impl fmt::Display for ZoneRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ZoneRef::Id(id) => write!(f, "id {}", id),
+            ZoneRef::Name(name) => write!(f, "name \"{}\"", name),
+        }
+    }
+}
+
+/// The ways looking up a zone from a `ZoneRef` can fail.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ZoneLookupError {
+    /// No zone matched.
+    NotFound(ZoneRef),
+    /// More than one zone matched, which can happen when zones share a name.
+    Ambiguous(ZoneRef, usize),
+}
+
+impl fmt::Display for ZoneLookupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ZoneLookupError::NotFound(zone_ref) => write!(f, "Unable to find zone with {}", zone_ref),
+            ZoneLookupError::Ambiguous(zone_ref, count) => write!(
+                f,
+                "{} zones have the {}, use the zone's id instead",
+                count, zone_ref
+            ),
+        }
+    }
+}
+
+impl Error for ZoneLookupError {}
+
 /// Gets a zone from the given id
 /// # Params
-///     `zone_id` The id of the zone we want to get
+/// * `zone_id` The id of the zone we want to get
 /// # Return
-///     `Zone` The zone that corresponds to the given id.
+/// * `Zone` The zone that corresponds to the given id.
 /// # Example
-/// ```
+/// ```ignore
 /// use sqlsprinkler::zone::Zone;
 /// let zone = Zone::get_zone(1);
 /// ```
-pub async fn get_zone_from_id(zone_id: i8) -> Result<Zone, sqlx::Error> {
-    let zones = sqlx::query_as::<_, Zone>("SELECT * FROM Zones WHERE id = ?")
-        .bind(zone_id)
-        .fetch_all(&get_pool())
-        .await?;
+pub async fn get_zone_from_id(zone_id: ZoneId) -> Result<Zone, sqlx::Error> {
+    let zones = read(|pool| async move {
+        sqlx::query_as::<_, Zone>("SELECT * FROM Zones WHERE id = ?")
+            .bind(zone_id)
+            .fetch_all(&pool)
+            .await
+    })
+    .await?;
     info!("Getting row from id: {}", zone_id);
     if zones.is_empty() {
         warn!("Default zone on get_zone_from_id: {}", zone_id);
@@ -343,18 +1260,18 @@ pub async fn get_zone_from_id(zone_id: i8) -> Result<Zone, sqlx::Error> {
 
 /// Gets a zone from the given id - DEPRECATED
 /// # Params
-///     `zone_id` The id of the zone we want to get
+/// * `zone_id` The id of the zone we want to get
 /// # Return
-///     `Zone` The zone that corresponds to the given id.
+/// * `Zone` The zone that corresponds to the given id.
 /// # Example
-/// ```
+/// ```ignore
 /// use sqlsprinkler::zone::Zone;
 /// let zone = Zone::get_zone_from_order(1);
 /// ```
 pub async fn get_zone_from_order(zone_order: i8) -> Result<Zone, sqlx::Error> {
     let query = sqlx::query_as::<_, Zone>("SELECT * FROM Zones WHERE SystemOrder = ?")
         .bind(zone_order)
-        .fetch_all(&get_pool())
+        .fetch_all(&get_pool()?)
         .await?;
     let mut _zone = Zone::default();
     if query.len() == 0 {
@@ -366,18 +1283,18 @@ pub async fn get_zone_from_order(zone_order: i8) -> Result<Zone, sqlx::Error> {
 
 /// Deletes the given zone
 /// # Params
-///     `_zone` The zone we are deleting
+/// * `_zone` The zone we are deleting
 /// # Return
-///     a bool representing if the deletion was successful (true) or not (false)
+/// a bool representing if the deletion was successful (true) or not (false)
 /// # Example
-/// ```
+/// ```ignore
 /// use sqlsprinkler::zone::Zone;
 /// let zone = Zone::get_zone(1);
 /// zone.delete();
 /// ```
 pub async fn delete(_zone: ZoneDelete) -> Result<bool, sqlx::Error> {
     let query = sqlx::query!("DELETE FROM `Zones` WHERE `ID` = ?", _zone.id)
-        .execute(&get_pool())
+        .execute(&get_pool()?)
         .await;
     let res = match query {
         Ok(_) => {
@@ -392,11 +1309,33 @@ pub async fn delete(_zone: ZoneDelete) -> Result<bool, sqlx::Error> {
     Ok(res)
 }
 
-/// Adds a new zone
+/// Resets the lifetime run count and runtime of a zone, such as after replacing its valve.
 /// # Params
-///     `ZoneAdd` The zone we are adding
+/// * `zone_id` The id of the zone to reset
+/// # Return
+/// a bool representing if the zone was found (true) or not (false)
 /// # Example
+/// ```ignore
+/// use sqlsprinkler::zone;
+/// zone::reset_stats(1);
 /// ```
+pub async fn reset_stats(zone_id: ZoneId) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE Zones SET total_runs = 0, total_runtime_secs = 0 WHERE id = ?",
+        zone_id
+    )
+    .execute(&get_pool()?)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Adds a new zone
+/// # Params
+/// * `ZoneAdd` The zone we are adding
+/// # Return
+/// * `true` if the zone was added, `false` if there are already `max_zones` zones.
+/// # Example
+/// ```ignore
 /// use sqlsprinkler::zone::Zone;
 /// let zone = Zone::ZoneAdd {
 ///     name: "Test Zone".to_string(),
@@ -408,27 +1347,451 @@ pub async fn delete(_zone: ZoneDelete) -> Result<bool, sqlx::Error> {
 /// Zone::add(zone);
 /// ```
 pub async fn add(_zone: ZoneAdd) -> Result<bool, sqlx::Error> {
-    let pool = &get_pool();
-    let query = sqlx::query!(
-        "INSERT INTO `Zones` (Name,GPIO,Time,Enabled,AutoOff,SystemOrder) VALUES (?,?,?,?,?,?)",
+    let pool = &get_pool()?;
+    let taken: Vec<String> = sqlx::query_scalar("SELECT Slug FROM Zones").fetch_all(pool).await?;
+    let max_zones = get_settings().max_zones;
+    if taken.len() >= max_zones {
+        warn!("Not adding zone, there are already max_zones ({}) zones", max_zones);
+        return Ok(false);
+    }
+    let slug = unique_slug(&_zone.name, &taken);
+    sqlx::query!(
+        "INSERT INTO `Zones` (Name,GPIO,Time,Enabled,AutoOff,SystemOrder,Priority,ActiveMonths,AllowManual,OpenLagMs,CloseLagMs,Slug) VALUES (?,?,?,?,?,?,?,?,?,?,?,?)",
         _zone.name,
         _zone.gpio,
-        _zone.time,
+        _zone.time.units(),
         _zone.enabled,
         _zone.auto_off,
-        1
+        1,
+        _zone.priority,
+        _zone.active_months,
+        _zone.allow_manual,
+        _zone.open_lag_ms,
+        _zone.close_lag_ms,
+        slug
     )
     .execute(pool)
-    .await;
-    let res = match query {
-        Ok(_) => {
-            info!("Zone added!");
-            true
+    .await?;
+    info!("Zone added!");
+    Ok(true)
+}
+
+/// Makes a slug from a zone name. Letters and digits are lowercased and every run of anything else
+/// becomes a single `-`, the same as the migration that gave existing zones their slugs.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::zone::slugify;
+/// assert_eq!(slugify("Front Lawn #2"), "front-lawn-2");
+/// ```
+pub fn slugify(name: &str) -> String {
+    let mut slug = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
         }
-        Err(e) => {
-            error!("Error adding zone: {}", e);
-            false
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// Makes a slug for a new zone that no other zone has, by numbering it if it is taken.
+/// # Params
+/// * `name` The name of the new zone.
+/// * `taken` The slugs of the existing zones.
+fn unique_slug(name: &str, taken: &[String]) -> String {
+    let slug = match slugify(name) {
+        slug if slug.is_empty() => "zone".to_string(),
+        slug => slug,
+    };
+    if !taken.contains(&slug) {
+        return slug;
+    }
+    (2..)
+        .map(|n| format!("{}-{}", slug, n))
+        .find(|numbered| !taken.contains(numbered))
+        .unwrap()
+}
+
+/// Swaps the system order of two zones in a single transaction.
+/// # Params
+/// * `_swap` The ids of the two zones we are swapping
+/// # Return
+/// a bool representing if both zones were found and swapped (true) or not (false)
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::zone::{swap, ZoneSwap};
+/// swap(ZoneSwap { id1: 1, id2: 2 });
+/// ```
+pub async fn swap(_swap: ZoneSwap) -> Result<bool, sqlx::Error> {
+    let mut tx = get_pool()?.begin().await?;
+    let mut zones = Vec::new();
+    for id in [_swap.id1, _swap.id2] {
+        let zone = sqlx::query_as::<_, Zone>("SELECT * FROM Zones WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&mut tx)
+            .await?;
+        match zone {
+            Some(zone) => zones.push(zone),
+            None => {
+                warn!("Unable to find zone with id {} to swap", id);
+                return Ok(false);
+            }
+        }
+    }
+    sqlx::query!(
+        "UPDATE Zones SET SystemOrder=? WHERE ID=?",
+        zones[1].SystemOrder,
+        zones[0].id
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE Zones SET SystemOrder=? WHERE ID=?",
+        zones[0].SystemOrder,
+        zones[1].id
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    info!("Swapped order of zones {} and {}", zones[0].id, zones[1].id);
+    Ok(true)
+}
+
+/// Sets the system order of every zone in a single transaction, so the order is never left half
+/// updated.
+/// # Params
+/// * `_order` The new order of each zone, given in the zones' current system order.
+/// # Return
+/// * `false` if the number of orders given doesn't match the number of zones, and nothing was
+///   changed.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::zone::{reorder, ZoneOrder};
+/// reorder(&ZoneOrder { order: vec![1, 0] });
+/// ```
+pub async fn reorder(_order: &ZoneOrder) -> Result<bool, sqlx::Error> {
+    let mut tx = get_pool()?.begin().await?;
+    let zones = sqlx::query_as::<_, Zone>("SELECT * FROM Zones ORDER BY SystemOrder")
+        .fetch_all(&mut tx)
+        .await?;
+    if zones.len() != _order.order.len() {
+        warn!(
+            "Got {} orders for {} zones, not reordering",
+            _order.order.len(),
+            zones.len()
+        );
+        return Ok(false);
+    }
+    for (zone, new_order) in zones.iter().zip(&_order.order) {
+        sqlx::query!("UPDATE Zones SET SystemOrder=? WHERE ID=?", new_order, zone.id)
+            .execute(&mut tx)
+            .await?;
+    }
+    tx.commit().await?;
+    info!("Reordered {} zones", zones.len());
+    Ok(true)
+}
+
+/// Moves a zone one step up or down the system order by swapping it with its neighbour, in a single
+/// transaction. Every zone is renumbered from 0 in the new order, so zones that shared an order are
+/// separated. Moving the first zone up or the last zone down leaves the order as it is.
+/// # Params
+/// * `id` The id of the zone to move.
+/// * `direction` Which way to move it.
+/// # Return
+/// The ids of every zone in the new order, or `None` if there is no zone with that id.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::zone::{move_zone, Direction};
+/// let order = move_zone(1, Direction::Down).await?;
+/// ```
+pub async fn move_zone(id: ZoneId, direction: Direction) -> Result<Option<Vec<ZoneId>>, sqlx::Error> {
+    let mut tx = get_pool()?.begin().await?;
+    let zones = sqlx::query_as::<_, Zone>("SELECT * FROM Zones ORDER BY SystemOrder, id")
+        .fetch_all(&mut tx)
+        .await?;
+    let ids: Vec<ZoneId> = zones.iter().map(|zone| zone.id).collect();
+    let moved = match moved_order(&ids, id, direction) {
+        Some(moved) => moved,
+        None => {
+            warn!("Unable to find zone with id {} to move", id);
+            return Ok(None);
         }
     };
-    Ok(res)
+    for (order, zone_id) in moved.iter().enumerate() {
+        sqlx::query!("UPDATE Zones SET SystemOrder=? WHERE ID=?", order as i8, zone_id)
+            .execute(&mut tx)
+            .await?;
+    }
+    tx.commit().await?;
+    info!("Moved zone {} {:?}", id, direction);
+    Ok(Some(moved))
+}
+
+/// Gets the order of zones after moving one of them a step up or down.
+/// # Params
+/// * `ids` The ids of the zones, in system order.
+/// * `id` The id of the zone to move.
+/// * `direction` Which way to move it.
+/// # Return
+/// The ids in the new order, or `None` if `id` isn't one of `ids`.
+fn moved_order(ids: &[ZoneId], id: ZoneId, direction: Direction) -> Option<Vec<ZoneId>> {
+    let index = ids.iter().position(|zone_id| *zone_id == id)?;
+    let mut moved = ids.to_vec();
+    match direction {
+        Direction::Up if index > 0 => moved.swap(index, index - 1),
+        Direction::Down if index + 1 < moved.len() => moved.swap(index, index + 1),
+        _ => (),
+    }
+    Some(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        capped_run_time, column_or_default, effective_runtime, is_reserved_gpio, lag_compensated, moved_order, parse_months, quick_run_time, set_virtual_pin, slugify, unique_slug, use_virtual_gpio, virtual_pin_is_on, unix_now, Zone, RUNS,
+        Direction, ZoneList, ZoneLookupError, ZoneRef, ZoneTime, ZoneToggle, ZoneUpdate,
+    };
+    use crate::config::{MyConfig, TimeUnit};
+    use std::time::Duration;
+
+    fn zone_list() -> ZoneList {
+        ZoneList {
+            zones: vec![
+                Zone { id: 1, Name: "Front Lawn".to_string(), ..Zone::default() },
+                Zone { id: 2, Name: "Garden".to_string(), ..Zone::default() },
+                Zone { id: 3, Name: "garden".to_string(), ..Zone::default() },
+                Zone { id: 4, Name: "Roses".to_string(), Slug: "side-bed".to_string(), ..Zone::default() },
+            ],
+        }
+    }
+
+    #[test]
+    fn slugs_are_made_from_names_and_numbered_when_taken() {
+        assert_eq!(slugify("  Front Lawn #2 "), "front-lawn-2");
+        assert_eq!(slugify("Back--Yard"), "back-yard");
+        let taken = vec!["garden".to_string(), "garden-2".to_string()];
+        assert_eq!(unique_slug("Garden", &taken), "garden-3");
+        assert_eq!(unique_slug("Front", &taken), "front");
+        assert_eq!(unique_slug("***", &taken), "zone");
+    }
+
+    #[test]
+    fn zones_move_one_step_and_stop_at_the_ends() {
+        assert_eq!(moved_order(&[1, 2, 3], 2, Direction::Up), Some(vec![2, 1, 3]));
+        assert_eq!(moved_order(&[1, 2, 3], 2, Direction::Down), Some(vec![1, 3, 2]));
+        assert_eq!(moved_order(&[1, 2, 3], 1, Direction::Up), Some(vec![1, 2, 3]));
+        assert_eq!(moved_order(&[1, 2, 3], 3, Direction::Down), Some(vec![1, 2, 3]));
+        assert_eq!(moved_order(&[1, 2, 3], 4, Direction::Up), None);
+    }
+
+    #[test]
+    fn zones_are_found_by_id_or_name() {
+        let list = zone_list();
+        assert_eq!(list.find(&"2".parse().unwrap()).unwrap().id, 2);
+        assert_eq!(list.find(&"front lawn".parse().unwrap()).unwrap().id, 1);
+        assert_eq!(list.find(&"side-bed".parse().unwrap()).unwrap().id, 4);
+    }
+
+    #[test]
+    fn unknown_and_ambiguous_names_are_errors() {
+        let list = zone_list();
+        let unknown: ZoneRef = "Back Lawn".parse().unwrap();
+        assert_eq!(list.find(&unknown), Err(ZoneLookupError::NotFound(unknown.clone())));
+        let shared: ZoneRef = "Garden".parse().unwrap();
+        assert_eq!(list.find(&shared), Err(ZoneLookupError::Ambiguous(shared.clone(), 2)));
+    }
+
+    #[test]
+    fn zone_ids_past_127_are_kept_and_out_of_range_ids_are_rejected() {
+        assert_eq!("127".parse(), Ok(ZoneRef::Id(127)));
+        assert_eq!("128".parse(), Ok(ZoneRef::Id(128)));
+        assert_eq!("200".parse(), Ok(ZoneRef::Id(200)));
+        assert_eq!(i16::MAX.to_string().parse(), Ok(ZoneRef::Id(i16::MAX)));
+        assert!("32768".parse::<ZoneRef>().is_err());
+        assert!("0".parse::<ZoneRef>().is_err());
+        assert!("-1".parse::<ZoneRef>().is_err());
+        let toggle: ZoneToggle = serde_json::from_str(r#"{"id": 200, "state": true}"#).unwrap();
+        assert_eq!(toggle.id, 200);
+        assert!(serde_json::from_str::<ZoneToggle>(r#"{"id": 40000, "state": true}"#).is_err());
+        assert!(serde_json::from_str::<ZoneToggle>(r#"{"id": 0, "state": true}"#).is_err());
+        assert!(serde_json::from_str::<ZoneToggle>(r#"{"id": -3, "state": true}"#).is_err());
+    }
+
+    #[test]
+    fn higher_priority_outranks_regardless_of_order() {
+        let running = Zone { Priority: 1, SystemOrder: 0, ..Zone::default() };
+        let requested = Zone { Priority: 2, SystemOrder: 5, ..Zone::default() };
+        assert!(requested.outranks(&running));
+        assert!(!running.outranks(&requested));
+    }
+
+    #[test]
+    fn equal_priority_is_broken_by_system_order() {
+        let first = Zone { SystemOrder: 1, ..Zone::default() };
+        let second = Zone { SystemOrder: 2, ..Zone::default() };
+        assert!(first.outranks(&second));
+        assert!(!second.outranks(&first));
+    }
+
+    #[test]
+    fn quick_runs_prefer_explicit_then_default_then_zone_time() {
+        let zone_time = Duration::from_secs(600);
+        let explicit = Some(Duration::from_secs(60));
+        assert_eq!(quick_run_time(explicit, 5, zone_time), Duration::from_secs(60));
+        assert_eq!(quick_run_time(None, 5, zone_time), Duration::from_secs(300));
+        assert_eq!(quick_run_time(None, 0, zone_time), zone_time);
+    }
+
+    #[test]
+    fn only_listed_pins_are_reserved() {
+        assert!(is_reserved_gpio(2, &[2, 3]));
+        assert!(!is_reserved_gpio(4, &[2, 3]));
+        assert!(!is_reserved_gpio(-1, &[255]));
+    }
+
+    #[test]
+    fn fractional_minutes_are_run_to_the_second() {
+        let drip: ZoneTime = "2.5".parse().unwrap();
+        assert_eq!(drip.duration(TimeUnit::Minutes), Duration::from_secs(150));
+        let pulse: ZoneTime = "0.5".parse().unwrap();
+        assert_eq!(pulse.duration(TimeUnit::Minutes), Duration::from_secs(30));
+        assert_eq!(pulse.duration(TimeUnit::Seconds), Duration::from_millis(500));
+        assert!("-1".parse::<ZoneTime>().is_err());
+        assert!("soon".parse::<ZoneTime>().is_err());
+    }
+
+    #[test]
+    fn zone_times_are_written_as_plain_numbers() {
+        assert_eq!(serde_json::to_string(&ZoneTime::from(10)).unwrap(), "10");
+        assert_eq!(serde_json::to_string(&"2.5".parse::<ZoneTime>().unwrap()).unwrap(), "2.5");
+        assert_eq!(serde_json::from_str::<ZoneTime>("10").unwrap(), ZoneTime::from(10));
+        assert_eq!(serde_json::from_str::<ZoneTime>("0.5").unwrap(), "0.5".parse().unwrap());
+        assert!(serde_json::from_str::<ZoneTime>("-2").is_err());
+    }
+
+    #[test]
+    fn auto_off_zones_always_get_a_shutoff_when_turned_on_by_hand() {
+        let minute = Duration::from_secs(60);
+        let auto_off = Zone { Time: 10.into(), Autooff: true, ..Zone::default() };
+        let stays_on = Zone { Time: 10.into(), Autooff: false, ..Zone::default() };
+        // The API, and the CLI without --for.
+        assert_eq!(auto_off.manual_run_time(None), Some(minute * 10));
+        assert_eq!(stays_on.manual_run_time(None), None);
+        // The CLI with --for, with and without minutes.
+        assert_eq!(auto_off.manual_run_time(Some(Some(minute))), Some(minute));
+        assert_eq!(stays_on.manual_run_time(Some(Some(minute))), Some(minute));
+        assert_eq!(auto_off.manual_run_time(Some(None)), Some(minute * 10));
+    }
+
+    #[test]
+    fn commissioning_mode_caps_runs() {
+        let ten_minutes = Duration::from_secs(600);
+        assert_eq!(capped_run_time(ten_minutes, true, 20), Duration::from_secs(20));
+        assert_eq!(capped_run_time(Duration::from_secs(5), true, 20), Duration::from_secs(5));
+        assert_eq!(capped_run_time(ten_minutes, false, 20), ten_minutes);
+    }
+
+    #[test]
+    fn valve_lag_is_compensated_for() {
+        let run_time = Duration::from_secs(10);
+        assert_eq!(lag_compensated(run_time, 0, 0), run_time);
+        assert_eq!(lag_compensated(run_time, 2000, 500), Duration::from_millis(11_500));
+        assert_eq!(lag_compensated(run_time, 0, 1500), Duration::from_millis(8_500));
+        assert_eq!(lag_compensated(Duration::from_secs(1), 0, 2000), Duration::ZERO);
+        assert_eq!(lag_compensated(run_time, -100, -100), run_time);
+    }
+
+    #[test]
+    fn every_adjustment_is_made_in_order() {
+        let settings = MyConfig {
+            commissioning_mode: true,
+            commissioning_run_secs: 20,
+            time_scale: 2,
+            zone_startup_delay_ms: 500,
+            ..MyConfig::default()
+        };
+        // Capped to 20s, held 2s longer for the valve, halved, then the startup delay is added.
+        let run_time = Duration::from_secs(600);
+        assert_eq!(effective_runtime(run_time, 2000, 0, &settings), Duration::from_millis(11_500));
+        let huge = MyConfig { zone_startup_delay_ms: u64::MAX, ..MyConfig::default() };
+        assert_eq!(effective_runtime(Duration::MAX, i32::MAX, 0, &huge), Duration::MAX);
+        let fastest = MyConfig { time_scale: u64::MAX, ..MyConfig::default() };
+        let longest_scale = Duration::from_secs(u32::MAX as u64);
+        assert_eq!(effective_runtime(longest_scale, 0, 0, &fastest), Duration::from_secs(1));
+        let zero = MyConfig { time_scale: 0, ..MyConfig::default() };
+        assert_eq!(effective_runtime(Duration::ZERO, 0, i32::MAX, &zero), Duration::ZERO);
+        assert_eq!(effective_runtime(run_time, 0, 0, &zero), run_time);
+    }
+
+    #[test]
+    fn months_are_parsed_into_a_bitmask() {
+        assert_eq!(parse_months("1-12"), Ok(0x0FFF));
+        assert_eq!(parse_months("1,3"), Ok(0b101));
+        assert_eq!(parse_months("11-2"), Ok(0b1100_0000_0011));
+        assert!(parse_months("13").is_err());
+        let zone = Zone { ActiveMonths: parse_months("4-10").unwrap(), ..Zone::default() };
+        assert!(zone.is_active_in(4) && zone.is_active_in(10));
+        assert!(!zone.is_active_in(3) && !zone.is_active_in(11));
+    }
+
+    #[test]
+    fn an_update_keeps_the_fields_it_leaves_out() {
+        let json = r#"{"Name":"Lawn","GPIO":12,"Time":10,"Enabled":true,"Autooff":true,"SystemOrder":0,"id":1}"#;
+        let update: ZoneUpdate = serde_json::from_str(json).unwrap();
+        assert_eq!(update.AllowManual, None);
+        assert_eq!(update.ActiveMonths, None);
+        let zone = Zone { AllowManual: false, ..Zone::default() };
+        assert_eq!(ZoneUpdate::from(&zone).AllowManual, Some(false));
+    }
+
+    #[test]
+    fn zones_without_allow_manual_allow_manual_runs() {
+        let json = r#"{"Name":"Lawn","GPIO":12,"Time":10,"Enabled":true,"Autooff":true,"SystemOrder":0,"id":1}"#;
+        let zone: Zone = serde_json::from_str(json).unwrap();
+        assert!(zone.AllowManual);
+        assert!(Zone::default().AllowManual);
+    }
+
+    #[test]
+    fn virtual_pins_start_off_and_remember_their_level() {
+        assert!(!virtual_pin_is_on(-1));
+        set_virtual_pin(-1, true);
+        assert!(virtual_pin_is_on(-1));
+        set_virtual_pin(-1, false);
+        assert!(!virtual_pin_is_on(-1));
+    }
+
+    #[test]
+    fn null_columns_fall_back_to_defaults() {
+        let null_name: Result<String, sqlx::Error> = Err(sqlx::Error::ColumnDecode {
+            index: "Name".to_string(),
+            source: Box::new(sqlx::error::UnexpectedNullError),
+        });
+        assert_eq!(column_or_default(null_name, "Name", 4), "");
+        assert!(column_or_default(Ok(true), "Enabled", 4));
+    }
+
+    #[tokio::test]
+    async fn zones_turned_on_by_hand_turn_off_after_the_time_asked_for() {
+        use_virtual_gpio();
+        let zone = Zone { id: 101, GPIO: 41, ..Zone::default() };
+        assert_eq!(zone.run_async(Some(Duration::from_millis(100))), Some(Duration::from_millis(100)));
+        assert!(zone.is_on() && zone.is_running());
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!zone.is_on() && !zone.is_running());
+    }
+
+    #[tokio::test]
+    async fn stopping_aborts_pending_auto_off() {
+        let zone = Zone { id: 100, Time: 60.into(), Autooff: true, ..Zone::default() };
+        zone.run_async(None);
+        let task = RUNS.lock().unwrap().get(&zone.id).unwrap().task.abort_handle();
+        let started_at = zone.cancel_run().unwrap();
+        assert!((unix_now() - started_at).abs() <= 1);
+        assert_eq!(zone.cancel_run(), None);
+        tokio::task::yield_now().await;
+        assert!(task.is_finished());
+    }
 }
@@ -0,0 +1,223 @@
+use crate::sqlsprinkler::changelog::{self, Source};
+use crate::sqlsprinkler::system::get_zones;
+use crate::sqlsprinkler::zone::{self, check_gpio_not_reserved, Zone, ZoneAdd, ZoneDelete, ZoneList};
+use log::info;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+
+/// The contents of a zones file, each zone being a `[[zones]]` table.
+#[derive(Debug, Deserialize)]
+struct ZonesFile {
+    #[serde(default)]
+    zones: Vec<ZoneAdd>,
+}
+
+/// A change needed to make the database match the zones file. Zones are matched by name.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ZoneChange {
+    /// A zone in the file that isn't in the database.
+    Add(ZoneAdd),
+    /// A zone in both whose settings differ.
+    Update { before: Zone, after: Zone },
+    /// A zone in the database that isn't in the file.
+    Remove(Zone),
+}
+
+impl fmt::Display for ZoneChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ZoneChange::Add(zone) => write!(f, "+ {} (GPIO {}, {} minutes)", zone.name, zone.gpio, zone.time),
+            ZoneChange::Update { before, after } => write!(
+                f,
+                "~ {} (GPIO {} → {}, {} → {} minutes)",
+                after.Name, before.GPIO, after.GPIO, before.Time, after.Time
+            ),
+            ZoneChange::Remove(zone) => write!(f, "- {} (id {})", zone.Name, zone.id),
+        }
+    }
+}
+
+/// Reads the zones from a zones file.
+/// # Params
+/// * `path` The path of the TOML file.
+/// # Return
+/// The zones, or an error if the file could not be read or parsed, or names a zone twice.
+pub fn read(path: &str) -> Result<Vec<ZoneAdd>, Box<dyn Error>> {
+    let text = fs::read_to_string(path)?;
+    let file: ZonesFile = toml::from_str(&text)?;
+    let mut names = HashSet::new();
+    for zone in &file.zones {
+        if !names.insert(zone.name.as_str()) {
+            return Err(format!("Zone '{}' is listed more than once in {}", zone.name, path).into());
+        }
+    }
+    Ok(file.zones)
+}
+
+/// Works out what needs to change for the database to match the zones file. The system order and
+/// run history of existing zones are kept.
+/// # Params
+/// * `zone_list` The zones in the database.
+/// * `wanted` The zones in the file.
+/// * `remove_extras` Whether zones that aren't in the file are removed.
+/// # Return
+/// The changes, removals first, then updates and additions in the file's order.
+pub fn diff(zone_list: &ZoneList, wanted: Vec<ZoneAdd>, remove_extras: bool) -> Vec<ZoneChange> {
+    let mut changes = Vec::new();
+    if remove_extras {
+        let names: HashSet<_> = wanted.iter().map(|zone| zone.name.as_str()).collect();
+        changes.extend(
+            zone_list
+                .zones
+                .iter()
+                .filter(|zone| !names.contains(zone.Name.as_str()))
+                .map(|zone| ZoneChange::Remove(zone.clone())),
+        );
+    }
+    for zone in wanted {
+        match zone_list.zones.iter().find(|current| current.Name == zone.name) {
+            Some(current) => {
+                let after = Zone {
+                    GPIO: zone.gpio,
+                    Time: zone.time,
+                    Enabled: zone.enabled,
+                    Autooff: zone.auto_off,
+                    Priority: zone.priority,
+                    ActiveMonths: zone.active_months,
+                    AllowManual: zone.allow_manual,
+                    OpenLagMs: zone.open_lag_ms,
+                    CloseLagMs: zone.close_lag_ms,
+                    ..current.clone()
+                };
+                if after != *current {
+                    changes.push(ZoneChange::Update { before: current.clone(), after });
+                }
+            }
+            None => changes.push(ZoneChange::Add(zone)),
+        }
+    }
+    changes
+}
+
+/// Makes the zones in the database match the zones file, recording each change in the changelog.
+/// # Params
+/// * `path` The path of the TOML file.
+/// * `remove_extras` Whether zones that aren't in the file are removed.
+/// # Return
+/// The changes that were made. Nothing is changed if the file can't be read or puts a zone on a
+/// reserved GPIO.
+/// # Example
+/// ```ignore
+/// use sqlsprinkler::zones_file;
+/// let changes = zones_file::reconcile("/etc/sqlsprinkler/zones.toml", false);
+/// ```
+pub async fn reconcile(path: &str, remove_extras: bool) -> Result<Vec<ZoneChange>, Box<dyn Error>> {
+    let wanted = read(path)?;
+    for zone in &wanted {
+        check_gpio_not_reserved(zone.gpio)?;
+    }
+    let changes = diff(&get_zones().await?, wanted, remove_extras);
+    for change in &changes {
+        match change {
+            ZoneChange::Add(zone) => {
+                if !zone::add(clone_zone_add(zone)).await? {
+                    return Err(format!("Unable to add zone {}", zone.name).into());
+                }
+                changelog::record(Source::File, "zone.add", None, changelog::snapshot(zone)).await;
+            }
+            ZoneChange::Update { before, after } => {
+                before.update(after.into()).await?;
+                changelog::record(
+                    Source::File,
+                    "zone.update",
+                    changelog::snapshot(before),
+                    changelog::snapshot(after),
+                )
+                .await;
+            }
+            ZoneChange::Remove(zone) => {
+                if !zone::delete(ZoneDelete { id: zone.id, force: false }).await? {
+                    return Err(format!("Unable to remove zone {}", zone.Name).into());
+                }
+                changelog::record(Source::File, "zone.delete", changelog::snapshot(zone), None).await;
+            }
+        }
+        info!("{}", change);
+    }
+    Ok(changes)
+}
+
+/// Copies a `ZoneAdd`, which can't derive `Clone` alongside `StructOpt`.
+fn clone_zone_add(zone: &ZoneAdd) -> ZoneAdd {
+    ZoneAdd {
+        name: zone.name.clone(),
+        gpio: zone.gpio,
+        time: zone.time,
+        enabled: zone.enabled,
+        auto_off: zone.auto_off,
+        priority: zone.priority,
+        active_months: zone.active_months,
+        allow_manual: zone.allow_manual,
+        open_lag_ms: zone.open_lag_ms,
+        close_lag_ms: zone.close_lag_ms,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, ZoneChange, ZonesFile};
+    use crate::sqlsprinkler::zone::{Zone, ZoneList};
+
+    #[test]
+    fn zones_file_is_diffed_by_name() {
+        let file: ZonesFile = toml::from_str(
+            r#"
+            [[zones]]
+            name = "Front"
+            gpio = 12
+            time = 10
+            enabled = true
+            auto_off = true
+
+            [[zones]]
+            name = "Back"
+            gpio = 16
+            time = 20
+            enabled = true
+            auto_off = true
+            "#,
+        )
+        .unwrap();
+        let zone = |id, name: &str, gpio, time: u32| Zone {
+            id,
+            Name: name.to_string(),
+            GPIO: gpio,
+            Time: time.into(),
+            Enabled: true,
+            Autooff: true,
+            SystemOrder: id as i8,
+            ..Zone::default()
+        };
+        let zone_list = ZoneList {
+            zones: vec![zone(1, "Front", 12, 10), zone(2, "Side", 20, 5)],
+        };
+        let changes = diff(&zone_list, file.zones, false);
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(&changes[0], ZoneChange::Add(zone) if zone.name == "Back"));
+
+        let file: ZonesFile = toml::from_str(
+            "[[zones]]\nname = \"Front\"\ngpio = 13\ntime = 10\nenabled = true\nauto_off = true\n",
+        )
+        .unwrap();
+        let changes = diff(&zone_list, file.zones, true);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0], ZoneChange::Remove(zone(2, "Side", 20, 5)));
+        assert_eq!(
+            changes[1],
+            ZoneChange::Update { before: zone(1, "Front", 12, 10), after: zone(1, "Front", 13, 10) }
+        );
+    }
+}